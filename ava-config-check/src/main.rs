@@ -0,0 +1,46 @@
+use std::env;
+use std::path::Path;
+use std::process::exit;
+
+use ava_toolkit::config_check::{check_module, Severity};
+
+/// Read the value after the given flag on the command line
+fn read_arg(flag: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|index| args.get(index + 1).cloned())
+}
+
+fn has_flag(flag: &str) -> bool {
+    env::args().any(|a| a == flag)
+}
+
+/// Check a module file and its factory message folder, and report every problem
+/// * --module : the module file (devices, loops, ...)
+/// * --factory-dir : the folder of the `<message_type>.json` templates
+/// * --strict [optional] : the warnings fail the check too
+///
+/// The templates are only read as JSON, not as the message type of the service
+/// Exit 0 if the module is fine, 1 if there are problems, 2 on wrong arguments
+fn main() {
+    let (Some(module), Some(factory_dir)) = (read_arg("--module"), read_arg("--factory-dir")) else {
+        eprintln!("Usage: ava-config-check --module <module.json> --factory-dir <folder> [--strict]");
+        eprintln!("The templates and schemas are only read as JSON, the service checks the template against its message type at startup");
+        exit(2);
+    };
+
+    println!("🔎 Checking module [{}] with factory folder [{}]", &module, &factory_dir);
+    let problems = check_module(Path::new(&module), Path::new(&factory_dir));
+    for problem in &problems {
+        println!("{}", problem);
+    }
+
+    let errors = problems.iter().filter(|p| p.severity == Severity::Error).count();
+    let warnings = problems.len() - errors;
+    println!("🏁 {} error(s), {} warning(s)", errors, warnings);
+
+    if errors > 0 || (has_flag("--strict") && warnings > 0) {
+        exit(1);
+    }
+}
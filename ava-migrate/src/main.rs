@@ -0,0 +1,106 @@
+use std::env;
+use std::process::exit;
+
+use common_config::conf_reader::{read_config, read_env};
+use common_config::properties::{get_prop_pg_connect_string, set_prop_values};
+use commons_error::*;
+use commons_pg::migration::{applied_versions, latest_version, migrate, pending_migrations};
+use commons_pg::sql_transaction2::SQLConnection2;
+use log::*;
+
+/// Read the value after the given flag on the command line
+fn read_arg(flag: &str) -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter()
+        .position(|a| a == flag)
+        .and_then(|index| args.get(index + 1).cloned())
+}
+
+fn has_flag(flag: &str) -> bool {
+    env::args().any(|a| a == flag)
+}
+
+/// Connect string from --db-url, or from the db.* properties of the ava-migrate service
+fn read_connect_string() -> String {
+    if let Some(db_url) = read_arg("--db-url") {
+        return db_url;
+    }
+
+    const PROJECT_CODE: &str = "ava-migrate";
+    const VAR_NAME: &str = "AVA_ENV";
+
+    let o_config_file = read_env(VAR_NAME);
+    println!(
+        "😎 Config file using PROJECT_CODE={} VAR_NAME={}",
+        PROJECT_CODE, VAR_NAME
+    );
+    let props = read_config(
+        PROJECT_CODE,
+        &o_config_file,
+        &Some("AVA_CLUSTER_PROFILE".to_string()),
+    );
+    set_prop_values(props);
+
+    match get_prop_pg_connect_string()
+        .map_err(err_fwd!("Cannot read the database connection information"))
+    {
+        Ok((connect_string, _)) => connect_string,
+        Err(e) => {
+            log_error!("{:?}", e);
+            exit(-64);
+        }
+    }
+}
+
+/// Apply the pending migrations of the AVA database
+/// * --db-url [optional] : the postgres url, otherwise the db.* properties are used
+/// * --status [optional] : only list the applied and pending migrations
+/// * --env, --cluster-profile : as for the other services
+#[tokio::main]
+async fn main() {
+    env::set_var(
+        "RUST_LOG",
+        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
+    );
+    env_logger::init();
+
+    info!("Starting AVA ava-migrate 0.1.0");
+
+    let connect_string = read_connect_string();
+    let mut cnx = match SQLConnection2::new(&connect_string).await {
+        Ok(c) => c,
+        Err(e) => {
+            log_error!("Cannot connect to the database, e=[{:?}]", e);
+            exit(-64);
+        }
+    };
+
+    if has_flag("--status") {
+        let applied = applied_versions(&mut cnx).await.unwrap_or_else(|e| {
+            log_error!("Cannot read the schema version, e=[{:?}]", e);
+            exit(-65);
+        });
+        let pending = pending_migrations(&mut cnx).await.unwrap_or_else(|e| {
+            log_error!("Cannot read the schema version, e=[{:?}]", e);
+            exit(-65);
+        });
+        info!("Applied versions: {:?}, binary version: [{}]", applied, latest_version());
+        for m in &pending {
+            info!("Pending migration: [{}] {}", m.version, m.name);
+        }
+        return;
+    }
+
+    match migrate(&mut cnx).await {
+        Ok(applied) if applied.is_empty() => {
+            info!("🏁 The schema is up to date, version [{}]", latest_version());
+        }
+        Ok(applied) => {
+            info!("🏁 Applied migrations {:?}, the schema is at version [{}]", applied, latest_version());
+        }
+        Err(e) => {
+            log_error!("Migration failed, e=[{:?}]", e);
+            exit(-65);
+        }
+    }
+}
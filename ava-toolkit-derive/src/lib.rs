@@ -0,0 +1,254 @@
+//! `#[derive(Locality)]` for the MessageEnum of the services.
+//!
+//! Each variant holds one message struct (Serialize + Deserialize).
+//! The derive writes `query_for_state`, `find_set_topic`, `raw_message` and `json_to_local`,
+//! and calls the inherent `to_local`, `process` and `compute` of the enum for the rest.
+//!
+//! ```ignore
+//! #[derive(Debug, Clone, Serialize, Deserialize, Locality)]
+//! enum MessageEnum {
+//!     #[locality(set_suffix = "set", state_query = r#"{"color":{"x":"","y":""}}"#)]
+//!     LampRgb(LampRgbMsg),
+//!     SimpleSwitch(SimpleSwitchMsg),
+//! }
+//! ```
+//!
+//! Variant attributes :
+//! * `set_suffix` : the set topic is `<topic>/<suffix>`, the device topic otherwise
+//! * `state_query` : the payload sent on `<topic>/get`, `{"state":""}` by default
+//! * `dynamic` : the variant holds a `DynamicMessage`, its payloads are checked by the schema of the factory folder
+//!
+//! Enum attributes :
+//! * `#[locality(with_data)]` : `to_local_with_data` calls the inherent one, `to_local` otherwise
+//! * `#[locality(journal)]` : `journal` calls the inherent one, nothing is recorded otherwise
+
+use proc_macro::TokenStream;
+use proc_macro2::TokenStream as TokenStream2;
+use quote::quote;
+use syn::{parse_macro_input, Attribute, Data, DeriveInput, Fields, Ident, LitStr};
+
+const DEFAULT_STATE_QUERY: &str = r#"{"state":""}"#;
+
+#[proc_macro_derive(Locality, attributes(locality))]
+pub fn derive_locality(input: TokenStream) -> TokenStream {
+    let input = parse_macro_input!(input as DeriveInput);
+    match expand(&input) {
+        Ok(tokens) => tokens.into(),
+        Err(e) => e.to_compile_error().into(),
+    }
+}
+
+struct VariantSettings {
+    ident: Ident,
+    set_suffix: Option<LitStr>,
+    state_query: LitStr,
+    dynamic: bool,
+}
+
+#[derive(Default)]
+struct EnumSettings {
+    with_data: bool,
+    journal: bool,
+}
+
+fn read_enum_settings(attrs: &[Attribute]) -> syn::Result<EnumSettings> {
+    let mut settings = EnumSettings::default();
+    for attr in attrs.iter().filter(|a| a.path().is_ident("locality")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("with_data") {
+                settings.with_data = true;
+                Ok(())
+            } else if meta.path.is_ident("journal") {
+                settings.journal = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `with_data` or `journal`"))
+            }
+        })?;
+    }
+    Ok(settings)
+}
+
+fn read_variant_settings(ident: &Ident, attrs: &[Attribute]) -> syn::Result<VariantSettings> {
+    let mut settings = VariantSettings {
+        ident: ident.clone(),
+        set_suffix: None,
+        state_query: LitStr::new(DEFAULT_STATE_QUERY, ident.span()),
+        dynamic: false,
+    };
+    for attr in attrs.iter().filter(|a| a.path().is_ident("locality")) {
+        attr.parse_nested_meta(|meta| {
+            if meta.path.is_ident("set_suffix") {
+                settings.set_suffix = Some(meta.value()?.parse()?);
+                Ok(())
+            } else if meta.path.is_ident("state_query") {
+                settings.state_query = meta.value()?.parse()?;
+                Ok(())
+            } else if meta.path.is_ident("dynamic") {
+                settings.dynamic = true;
+                Ok(())
+            } else {
+                Err(meta.error("expected `set_suffix`, `state_query` or `dynamic`"))
+            }
+        })?;
+    }
+    Ok(settings)
+}
+
+fn expand(input: &DeriveInput) -> syn::Result<TokenStream2> {
+    let name = &input.ident;
+    let Data::Enum(data) = &input.data else {
+        return Err(syn::Error::new_spanned(name, "#[derive(Locality)] is only for enums"));
+    };
+
+    let mut variants = vec![];
+    for variant in &data.variants {
+        match &variant.fields {
+            Fields::Unnamed(fields) if fields.unnamed.len() == 1 => {}
+            _ => {
+                return Err(syn::Error::new_spanned(
+                    variant,
+                    "#[derive(Locality)] needs variants holding one message, ex : LampRgb(LampRgbMsg)",
+                ))
+            }
+        }
+        variants.push(read_variant_settings(&variant.ident, &variant.attrs)?);
+    }
+    let enum_settings = read_enum_settings(&input.attrs)?;
+
+    let query_arms = variants.iter().map(|v| {
+        let ident = &v.ident;
+        let query = &v.state_query;
+        quote! { Self::#ident(_) => #query.to_string(), }
+    });
+
+    let set_topic_arms = variants.iter().map(|v| {
+        let ident = &v.ident;
+        match &v.set_suffix {
+            Some(suffix) => quote! { Self::#ident(_) => format!("{}/{}", topic, #suffix), },
+            None => quote! { Self::#ident(_) => topic.to_string(), },
+        }
+    });
+
+    let raw_arms = variants.iter().map(|v| {
+        let ident = &v.ident;
+        let variant_name = ident.to_string();
+        if v.dynamic {
+            return quote! { Self::#ident(msg) => ::ava_toolkit::generic_device::Locality::raw_message(msg), };
+        }
+        quote! {
+            Self::#ident(msg) => ::ava_toolkit::__private::serde_json::to_string(msg).unwrap_or_else(|e| {
+                ::ava_toolkit::__private::log::error!("💣 Cannot serialize the [{}] message, e=[{}]", #variant_name, e);
+                String::new()
+            }),
+        }
+    });
+
+    let json_arms = variants.iter().map(|v| {
+        let ident = &v.ident;
+        if v.dynamic {
+            return quote! {
+                Self::#ident(msg) => ::ava_toolkit::generic_device::Locality::json_to_local(msg, json_msg).map(Self::#ident),
+            };
+        }
+        quote! {
+            Self::#ident(_) => Ok(Self::#ident(
+                ::ava_toolkit::__private::serde_json::from_str(json_msg).map_err(|e| e.to_string())?,
+            )),
+        }
+    });
+
+    let with_data = if enum_settings.with_data {
+        quote! { #name::to_local_with_data(self, original_message, last_message, ext_data, topic, source_topic) }
+    } else {
+        quote! {
+            let _ = (ext_data, topic, source_topic);
+            #name::to_local(self, original_message, last_message)
+        }
+    };
+
+    let journal = if enum_settings.journal {
+        quote! {
+            fn journal(&self, entry: &::ava_toolkit::journal::JournalEntry) -> impl ::std::future::Future<Output = ()> + Send {
+                #name::journal(self, entry)
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    let with_schema = if variants.iter().any(|v| v.dynamic) {
+        let schema_arms = variants.iter().map(|v| {
+            let ident = &v.ident;
+            if v.dynamic {
+                quote! { Self::#ident(msg) => Self::#ident(::ava_toolkit::generic_device::Locality::with_schema(msg, schema)), }
+            } else {
+                quote! { other @ Self::#ident(_) => other, }
+            }
+        });
+        quote! {
+            fn with_schema(self, schema: ::std::sync::Arc<::ava_toolkit::json_schema::JsonSchema>) -> Self {
+                match self {
+                    #(#schema_arms)*
+                }
+            }
+        }
+    } else {
+        quote! {}
+    };
+
+    Ok(quote! {
+        impl ::ava_toolkit::generic_device::Locality for #name {
+            fn query_for_state(&self) -> String {
+                match self {
+                    #(#query_arms)*
+                }
+            }
+
+            fn find_set_topic(&self, topic: &str) -> String {
+                match self {
+                    #(#set_topic_arms)*
+                }
+            }
+
+            fn raw_message(&self) -> String {
+                match self {
+                    #(#raw_arms)*
+                }
+            }
+
+            fn to_local(&self, original_message: &Self, last_message: &Self) -> Self {
+                #name::to_local(self, original_message, last_message)
+            }
+
+            fn to_local_with_data(
+                &self,
+                original_message: &Self,
+                last_message: &Self,
+                ext_data: Option<&::std::collections::HashMap<String, f64>>,
+                topic: Option<&str>,
+                source_topic: &str,
+            ) -> Self {
+                #with_data
+            }
+
+            fn json_to_local(&self, json_msg: &str) -> Result<Self, String> {
+                match self {
+                    #(#json_arms)*
+                }
+            }
+
+            fn process(&self, topic: &str, args: &[String]) -> impl ::std::future::Future<Output = ()> + Send {
+                #name::process(self, topic, args)
+            }
+
+            fn compute(&self) -> impl ::std::future::Future<Output = Option<::std::collections::HashMap<String, f64>>> + Send {
+                #name::compute(self)
+            }
+
+            #journal
+
+            #with_schema
+        }
+    })
+}
@@ -0,0 +1,351 @@
+//! Checks of a module file and its factory message folder, run by `ava-config-check` before a deployment.
+
+use std::collections::HashSet;
+use std::fmt;
+use std::path::Path;
+
+use serde_json::Value;
+
+use crate::domotic_factory::{read_json_file, ConfigRoot};
+use crate::rules::compile_rules;
+use crate::topic::is_wildcard;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    Error,      // the service fails or misbehaves
+    Warning,    // probably a mistake
+}
+
+#[derive(Debug, Clone)]
+pub struct ConfigProblem {
+    pub severity: Severity,
+    pub message: String,
+}
+
+impl fmt::Display for ConfigProblem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.severity {
+            Severity::Error => write!(f, "❌ {}", self.message),
+            Severity::Warning => write!(f, "⚠️ {}", self.message),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Report {
+    problems: Vec<ConfigProblem>,
+}
+
+impl Report {
+    fn error(&mut self, message: String) {
+        self.problems.push(ConfigProblem { severity: Severity::Error, message });
+    }
+
+    fn warning(&mut self, message: String) {
+        self.problems.push(ConfigProblem { severity: Severity::Warning, message });
+    }
+
+    /// Report the names found twice in the list
+    fn duplicates<'a>(&mut self, names: impl Iterator<Item = &'a String>, what: &str) {
+        let mut seen = HashSet::new();
+        for name in names {
+            if !seen.insert(name) {
+                self.error(format!("Duplicate {} [{}]", what, name));
+            }
+        }
+    }
+}
+
+/// The template and its schema must be JSON, their message type is only known by the service
+fn check_template(report: &mut Report, factory_message_dir: &Path, device_name: &str, message_type: &str) {
+    let template = factory_message_dir.join(format!("{}.json", message_type));
+    if let Err(e) = read_json_file::<Value>(&template) {
+        report.error(format!("Device [{}] : cannot read the template [{}], e=[{}]", device_name, template.display(), e));
+    }
+    let schema = factory_message_dir.join(format!("{}.schema.json", message_type));
+    if schema.exists() {
+        if let Err(e) = read_json_file::<Value>(&schema) {
+            report.error(format!("Device [{}] : cannot read the schema [{}], e=[{}]", device_name, schema.display(), e));
+        }
+    }
+}
+
+/// Every problem of the module, an empty list if the module is fine
+pub fn check_module(config_path: &Path, factory_message_dir: &Path) -> Vec<ConfigProblem> {
+    match read_json_file::<ConfigRoot>(config_path) {
+        Ok(config) => check_config(&config, factory_message_dir),
+        Err(e) => vec![ConfigProblem {
+            severity: Severity::Error,
+            message: format!("Cannot read the module file [{}], e=[{}]", config_path.display(), e),
+        }],
+    }
+}
+
+/// Every problem of a module already read
+pub(crate) fn check_config(config: &ConfigRoot, factory_message_dir: &Path) -> Vec<ConfigProblem> {
+    let mut report = Report::default();
+
+    if !factory_message_dir.is_dir() {
+        report.error(format!("The factory folder [{}] does not exist", factory_message_dir.display()));
+    }
+
+    report.duplicates(config.devices.iter().map(|d| &d.name), "device");
+    report.duplicates(config.loops.iter().map(|l| &l.loop_name), "loop");
+    report.duplicates(config.schedules.iter().map(|s| &s.name), "schedule");
+
+    let known: HashSet<&String> = config.devices.iter().map(|d| &d.name).collect();
+    let listened: HashSet<&String> = config.devices_to_listen.iter().collect();
+    let initialized: HashSet<&String> = config.devices_to_init.iter().collect();
+    let scheduled: HashSet<&String> = config.schedules.iter().map(|s| &s.device).collect();
+    let in_loops: HashSet<&String> = config.loops.iter().flat_map(|l| l.devices.iter()).collect();
+    // A filter has no concrete topic to send to, nor a /get topic
+    let wildcards: HashSet<&String> = config.devices.iter().map(|d| &d.name).filter(|n| is_wildcard(n)).collect();
+
+    for def in &config.devices {
+        check_template(&mut report, factory_message_dir, &def.name, &def.message_type);
+        if let Err(e) = compile_rules(&def.rules) {
+            report.error(format!("Device [{}] : {}", def.name, e));
+        }
+        if !listened.contains(&def.name) && !initialized.contains(&def.name) && !scheduled.contains(&def.name) {
+            report.warning(format!("Device [{}] is neither listened to nor initialized", def.name));
+        }
+    }
+
+    for (list, names) in [("devices_to_listen", &config.devices_to_listen), ("devices_to_init", &config.devices_to_init)] {
+        for name in names.iter().filter(|n| !known.contains(n)) {
+            report.error(format!("Unknown device [{}] in {}", name, list));
+        }
+        report.duplicates(names.iter(), &format!("entry in {}", list));
+    }
+    for name in config.devices_to_init.iter().filter(|n| wildcards.contains(n)) {
+        report.error(format!("Wildcard device [{}] in devices_to_init, it cannot be initialized", name));
+    }
+
+    for def in &config.loops {
+        for name in def.devices.iter().filter(|n| !known.contains(n)) {
+            report.error(format!("Unknown device [{}] in loop [{}]", name, def.loop_name));
+        }
+        report.duplicates(def.devices.iter(), &format!("device in loop [{}]", def.loop_name));
+        // A wildcard device can only be the source of its loop
+        for name in def.devices.iter().filter(|n| wildcards.contains(n)) {
+            for sender in def.devices.iter().filter(|n| *n != name && listened.contains(n)) {
+                report.error(format!("Wildcard device [{}] cannot receive the messages of [{}] in loop [{}]", name, sender, def.loop_name));
+            }
+        }
+        match def.guard.check() {
+            Ok(names) => {
+                for name in &names {
+                    if !known.contains(name) {
+                        report.error(format!("Unknown device [{}] in the conditions of loop [{}]", name, def.loop_name));
+                    } else if wildcards.contains(name) {
+                        report.error(format!("Wildcard device [{}] in the conditions of loop [{}], it has no state of its own", name, def.loop_name));
+                    } else if listened.contains(name) || scheduled.contains(name) {
+                        // The state follows the messages of the device
+                    } else if initialized.contains(name) {
+                        report.warning(format!("Device [{}] in the conditions of loop [{}] is not listened to, its state stays the one of the init", name, def.loop_name));
+                    } else {
+                        report.error(format!("Device [{}] in the conditions of loop [{}] is not listened to, the loop is never active", name, def.loop_name));
+                    }
+                }
+            }
+            Err(e) => report.error(format!("Loop [{}] : {}", def.loop_name, e)),
+        }
+        if def.devices.len() < 2 {
+            report.warning(format!("Loop [{}] has {} device(s), it never sends anything", def.loop_name, def.devices.len()));
+        }
+    }
+
+    for def in &config.schedules {
+        if let Err(e) = def.trigger() {
+            report.error(format!("Schedule [{}] : {}", def.name, e));
+        }
+        match config.devices.iter().find(|d| d.name == def.device) {
+            None => report.error(format!("Unknown device [{}] in schedule [{}]", def.device, def.name)),
+            Some(_) if wildcards.contains(&def.device) => {
+                report.error(format!("Wildcard device [{}] in schedule [{}], it has no concrete topic", def.device, def.name));
+            }
+            Some(device) => {
+                if !in_loops.contains(&def.device) {
+                    report.warning(format!("Device [{}] of schedule [{}] is in no loop, it triggers nothing", def.device, def.name));
+                }
+                if !device.process_same_message {
+                    report.warning(format!("Device [{}] of schedule [{}] sends the same message each time, it needs process_same_message", def.device, def.name));
+                }
+            }
+        }
+    }
+
+    report.problems
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use serde_json::json;
+
+    use super::*;
+
+    /// The problems of the module, with a factory folder holding the `Sensor` template
+    fn check(test: &str, module: Value) -> Vec<String> {
+        let dir = std::env::temp_dir().join(format!("ava-config-check-{}-{}", test, std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Sensor.json"), "{}").unwrap();
+        let module_file = dir.join("module.json");
+        fs::write(&module_file, module.to_string()).unwrap();
+        let problems = check_module(&module_file, &dir).into_iter().map(|p| p.to_string()).collect();
+        fs::remove_dir_all(&dir).unwrap();
+        problems
+    }
+
+    fn device(name: &str) -> Value {
+        json!({"family": "zigbee2mqtt", "name": name, "message_type": "Sensor", "process_same_message": false})
+    }
+
+    #[test]
+    fn wildcard_device_as_a_source_only() {
+        let module = json!({
+            "devices": [device("ts_+"), device("hall_lamp")],
+            "loops": [{"loop_name": "temperatures", "devices": ["ts_+", "hall_lamp"]}],
+            "devices_to_listen": ["ts_+"],
+            "devices_to_init": ["hall_lamp"],
+        });
+        assert!(check("source", module).is_empty());
+    }
+
+    #[test]
+    fn wildcard_device_cannot_be_a_target() {
+        let module = json!({
+            "devices": [device("ts_+"), device("hall_lamp")],
+            "loops": [{"loop_name": "temperatures", "devices": ["ts_+", "hall_lamp"]}],
+            "devices_to_listen": ["ts_+", "hall_lamp"],
+        });
+        assert_eq!(
+            vec!["❌ Wildcard device [ts_+] cannot receive the messages of [hall_lamp] in loop [temperatures]"],
+            check("target", module)
+        );
+    }
+
+    #[test]
+    fn wildcard_device_cannot_be_initialized_nor_scheduled() {
+        let module = json!({
+            "devices": [device("ts_#"), device("hall_lamp")],
+            "loops": [{"loop_name": "temperatures", "devices": ["ts_#", "hall_lamp"]}],
+            "devices_to_listen": ["ts_#"],
+            "devices_to_init": ["ts_#", "hall_lamp"],
+            "schedules": [{"name": "every_minute", "device": "ts_#", "every_s": 60}],
+        });
+        assert_eq!(
+            vec![
+                "❌ Wildcard device [ts_#] in devices_to_init, it cannot be initialized",
+                "❌ Wildcard device [ts_#] in schedule [every_minute], it has no concrete topic",
+            ],
+            check("init", module)
+        );
+    }
+
+    #[test]
+    fn loop_conditions_need_a_followed_device() {
+        let guarded = |name: &str| json!({"loop_name": name, "devices": ["hall_switch", "hall_lamp"], "conditions": [format!("{}.illuminance < 50", name)]});
+        let module = json!({
+            "devices": [device("hall_switch"), device("hall_lamp"), device("lux_listened"), device("lux_init"), device("lux_none"), device("lux_+")],
+            "loops": [guarded("lux_listened"), guarded("lux_init"), guarded("lux_none"), guarded("lux_+")],
+            "devices_to_listen": ["hall_switch", "lux_listened", "lux_+"],
+            "devices_to_init": ["hall_lamp", "lux_init"],
+            "schedules": [],
+        });
+        assert_eq!(
+            vec![
+                "⚠️ Device [lux_none] is neither listened to nor initialized",
+                "⚠️ Device [lux_init] in the conditions of loop [lux_init] is not listened to, its state stays the one of the init",
+                "❌ Device [lux_none] in the conditions of loop [lux_none] is not listened to, the loop is never active",
+                "❌ Wildcard device [lux_+] in the conditions of loop [lux_+], it has no state of its own",
+            ],
+            check("conditions", module)
+        );
+    }
+
+    #[test]
+    fn unknown_devices() {
+        let module = json!({
+            "devices": [device("hall_switch"), device("hall_lamp")],
+            "loops": [{"loop_name": "hall", "devices": ["hall_switch", "ghost_lamp"]}],
+            "devices_to_listen": ["hall_switch", "ghost_switch"],
+            "devices_to_init": ["hall_lamp"],
+            "schedules": [{"name": "evening", "device": "ghost_trigger", "every_s": 60}],
+        });
+        assert_eq!(
+            vec![
+                "❌ Unknown device [ghost_switch] in devices_to_listen",
+                "❌ Unknown device [ghost_lamp] in loop [hall]",
+                "❌ Unknown device [ghost_trigger] in schedule [evening]",
+            ],
+            check("unknown", module)
+        );
+    }
+
+    #[test]
+    fn unreadable_template() {
+        let mut lamp = device("hall_lamp");
+        lamp["message_type"] = json!("Lamp");
+        let module = json!({
+            "devices": [device("hall_switch"), lamp],
+            "loops": [{"loop_name": "hall", "devices": ["hall_switch", "hall_lamp"]}],
+            "devices_to_listen": ["hall_switch"],
+            "devices_to_init": ["hall_lamp"],
+        });
+        let problems = check("template", module);
+        assert_eq!(1, problems.len(), "{:?}", problems);
+        assert!(problems[0].starts_with("❌ Device [hall_lamp] : cannot read the template ["), "{}", problems[0]);
+        assert!(problems[0].contains("Lamp.json"), "{}", problems[0]);
+    }
+
+    #[test]
+    fn device_neither_listened_nor_initialized() {
+        let module = json!({
+            "devices": [device("hall_switch"), device("hall_lamp"), device("spare_lamp")],
+            "loops": [{"loop_name": "hall", "devices": ["hall_switch", "hall_lamp"]}],
+            "devices_to_listen": ["hall_switch"],
+            "devices_to_init": ["hall_lamp"],
+        });
+        assert_eq!(
+            vec!["⚠️ Device [spare_lamp] is neither listened to nor initialized"],
+            check("followed", module)
+        );
+    }
+
+    #[test]
+    fn loop_with_a_single_device() {
+        let module = json!({
+            "devices": [device("hall_switch")],
+            "loops": [{"loop_name": "hall", "devices": ["hall_switch"]}],
+            "devices_to_listen": ["hall_switch"],
+        });
+        assert_eq!(
+            vec!["⚠️ Loop [hall] has 1 device(s), it never sends anything"],
+            check("single", module)
+        );
+    }
+
+    #[test]
+    fn duplicates() {
+        let module = json!({
+            "devices": [device("hall_switch"), device("hall_lamp"), device("hall_lamp")],
+            "loops": [
+                {"loop_name": "hall", "devices": ["hall_switch", "hall_lamp", "hall_switch"]},
+                {"loop_name": "hall", "devices": ["hall_switch", "hall_lamp"]},
+            ],
+            "devices_to_listen": ["hall_switch", "hall_switch"],
+            "devices_to_init": ["hall_lamp"],
+        });
+        assert_eq!(
+            vec![
+                "❌ Duplicate device [hall_lamp]",
+                "❌ Duplicate loop [hall]",
+                "❌ Duplicate entry in devices_to_listen [hall_switch]",
+                "❌ Duplicate device in loop [hall] [hall_switch]",
+            ],
+            check("duplicates", module)
+        );
+    }
+}
@@ -0,0 +1,133 @@
+//! Correlation ID of a message, from its receipt on the broker to the commands it causes.
+//! It travels in the `correlation_id` user property of MQTT v5, and in the `X-Correlation-Id` header of the HTTP APIs.
+//! The processing of a message runs inside `with_correlation_id`, so `Locality::compute`, `to_local_with_data`,
+//! `process` and `publish_message` read it with `current_correlation_id`, and every log line carries it.
+
+use std::future::Future;
+
+use log::warn;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use tracing::Instrument;
+use uuid::Uuid;
+
+pub const CORRELATION_PROPERTY: &str = "correlation_id";
+pub const CORRELATION_HEADER: &str = "x-correlation-id";
+/// The IDs are stored in `varchar(64)` columns, see V006 and V007
+pub const MAX_CORRELATION_ID_LEN: usize = 64;
+
+tokio::task_local! {
+    static CORRELATION_ID: String;
+}
+
+pub fn new_correlation_id() -> String {
+    Uuid::new_v4().to_string()
+}
+
+/// The ID given by a sender, None if it is empty or does not fit in the database
+fn accepted_id(id: &str) -> Option<String> {
+    let id = id.trim();
+    if id.is_empty() {
+        return None;
+    }
+    if id.chars().count() > MAX_CORRELATION_ID_LEN {
+        warn!("Correlation ID longer than [{}] characters, replaced, id=[{}]", MAX_CORRELATION_ID_LEN, id);
+        return None;
+    }
+    Some(id.to_string())
+}
+
+/// The ID given by the sender of the message, or a new one set in the properties, so the journal records it
+pub fn ensure_correlation_id(properties: &mut Option<PublishProperties>) -> String {
+    let properties = properties.get_or_insert_with(PublishProperties::default);
+    let given = properties.user_properties.iter_mut().find(|(key, _)| key == CORRELATION_PROPERTY);
+    if let Some(id) = given.as_ref().and_then(|(_, id)| accepted_id(id)) {
+        return id;
+    }
+    let id = new_correlation_id();
+    match given {
+        Some((_, value)) => *value = id.clone(),
+        None => properties.user_properties.push((CORRELATION_PROPERTY.to_string(), id.clone())),
+    }
+    id
+}
+
+/// The ID of an HTTP request, from its `X-Correlation-Id` header or a new one
+pub fn correlation_from_header(value: Option<&str>) -> String {
+    value.and_then(accepted_id).unwrap_or_else(new_correlation_id)
+}
+
+/// The ID of the message processed by the current task
+pub fn current_correlation_id() -> Option<String> {
+    CORRELATION_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Run the future with the ID, in a span that adds it to the log lines
+pub async fn with_correlation_id<F: Future>(correlation_id: String, future: F) -> F::Output {
+    let span = tracing::info_span!("message", correlation_id = %correlation_id);
+    CORRELATION_ID.scope(correlation_id, future.instrument(span)).await
+}
+
+/// The properties of a publish, with the ID of the current task if any
+pub fn publish_properties() -> PublishProperties {
+    PublishProperties {
+        user_properties: current_correlation_id()
+            .map(|id| vec![(CORRELATION_PROPERTY.to_string(), id)])
+            .unwrap_or_default(),
+        ..Default::default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_id(id: &str) -> Option<PublishProperties> {
+        Some(PublishProperties {
+            user_properties: vec![("origin".to_string(), "bridge".to_string()), (CORRELATION_PROPERTY.to_string(), id.to_string())],
+            ..Default::default()
+        })
+    }
+
+    fn property(properties: &Option<PublishProperties>) -> Vec<&str> {
+        properties.iter()
+            .flat_map(|p| p.user_properties.iter())
+            .filter(|(key, _)| key == CORRELATION_PROPERTY)
+            .map(|(_, id)| id.as_str())
+            .collect()
+    }
+
+    #[test]
+    fn the_given_id_is_kept() {
+        let mut properties = with_id("req-42");
+        assert_eq!("req-42", ensure_correlation_id(&mut properties));
+        assert_eq!(vec!["req-42"], property(&properties));
+        assert_eq!("req-42", correlation_from_header(Some(" req-42 ")));
+    }
+
+    #[test]
+    fn a_missing_id_is_created() {
+        let mut properties = None;
+        let id = ensure_correlation_id(&mut properties);
+        assert_eq!(36, id.len());
+        assert_eq!(vec![id.as_str()], property(&properties));
+        assert_eq!(36, correlation_from_header(None).len());
+    }
+
+    #[test]
+    fn empty_or_too_long_ids_are_replaced() {
+        let limit = "a".repeat(MAX_CORRELATION_ID_LEN);
+        assert_eq!(limit, correlation_from_header(Some(&limit)));
+        assert_eq!(limit, ensure_correlation_id(&mut with_id(&limit)));
+
+        for wrong in ["", "  ", &"a".repeat(MAX_CORRELATION_ID_LEN + 1)] {
+            let id = correlation_from_header(Some(wrong));
+            assert!(Uuid::parse_str(&id).is_ok(), "{}", id);
+
+            let mut properties = with_id(wrong);
+            let id = ensure_correlation_id(&mut properties);
+            assert!(Uuid::parse_str(&id).is_ok(), "{}", id);
+            // Replaced in place, the journal records the new ID once
+            assert_eq!(vec![id.as_str()], property(&properties));
+        }
+    }
+}
@@ -0,0 +1,71 @@
+//! Dead letters: the device messages the service cannot read (wrong JSON, rejected by the schema, ...).
+//! They are republished on the topic of the `mqtt.dead_letter_topic` property (ex: `ava/dlq/luminator`),
+//! so a change in the payloads of a firmware is noticed. event-storage can store them, see README.
+
+use std::sync::OnceLock;
+
+use chrono::{DateTime, Utc};
+use log::{error, info};
+use serde_derive::{Deserialize, Serialize};
+
+use crate::correlation::{current_correlation_id, publish_properties};
+use crate::transport::TransportClient;
+
+static DEAD_LETTER: OnceLock<DeadLetterTopic> = OnceLock::new();
+
+#[derive(Debug)]
+struct DeadLetterTopic {
+    service: String,
+    topic: String,
+}
+
+/// The message published on the dead-letter topic
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct DeadLetter {
+    pub service: String,
+    pub topic: String,      // the topic of the unreadable message
+    pub payload: String,
+    pub error: String,
+    #[serde(default)]
+    pub correlation_id: Option<String>,
+    pub ts_received: DateTime<Utc>,
+}
+
+/// Register the dead-letter topic of the service, the unreadable messages are only logged if it's never called
+pub fn init_dead_letter(service: &str, topic: &str) -> Result<(), String> {
+    DEAD_LETTER
+        .set(DeadLetterTopic { service: service.to_string(), topic: topic.to_string() })
+        .map_err(|_| "The dead-letter topic is already set".to_string())?;
+    info!("📮 Unreadable messages go to [{}]", topic);
+    Ok(())
+}
+
+/// Publish the unreadable message on the dead-letter topic, if any.
+/// A message of the dead-letter topic itself is never sent back to it.
+pub(crate) async fn send_dead_letter(client: &dyn TransportClient, topic: &str, payload: &str, error: &str) {
+    let Some(dead_letter) = DEAD_LETTER.get() else {
+        return;
+    };
+    if topic == dead_letter.topic {
+        return;
+    }
+    let letter = DeadLetter {
+        service: dead_letter.service.clone(),
+        topic: topic.to_string(),
+        payload: payload.to_string(),
+        error: error.to_string(),
+        correlation_id: current_correlation_id(),
+        ts_received: Utc::now(),
+    };
+    let data = match serde_json::to_vec(&letter) {
+        Ok(data) => data,
+        Err(e) => {
+            error!("💣 Cannot serialize the dead letter of [{}], e=[{}]", topic, e);
+            return;
+        }
+    };
+    match client.publish(&dead_letter.topic, data, publish_properties()).await {
+        Ok(()) => info!("📮 Message of [{}] sent to [{}]", topic, &dead_letter.topic),
+        Err(e) => error!("💣 Cannot publish the dead letter of [{}], e=[{}]", topic, e),
+    }
+}
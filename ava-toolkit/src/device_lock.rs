@@ -1,26 +1,149 @@
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
 use log::*;
+use serde_json::Value;
+
+/// Echoes waited for by default, when the device definition has no `echo_timeout_ms`
+pub const DEFAULT_ECHO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A message published to the device, its echo on the device topic must not be processed again
+#[derive(Debug, Clone)]
+pub struct Expectation {
+    pub expected: Value,    // only the fields changed by the published message
+    pub deadline: Instant,
+}
+
+impl Expectation {
+    fn is_expired(&self) -> bool {
+        self.deadline <= Instant::now()
+    }
+}
 
 #[derive(Debug, Clone)]
 pub struct DeviceLock<T> {
-    pub count_locks : u32,
+    pending: VecDeque<Expectation>,
+    last_processed_at: Option<Instant>,
+    last_processed: Option<Value>,  // only kept for the devices with thresholds
     pub last_object_message : T,
 }
 
+/// The fields of `after` that differ from `before`, the nested objects are compared field by field.
+/// An `after` that is not an object is expected as a whole.
+/// A nested object that only lost fields is expected as a whole, an empty one would match anything.
+pub fn changed_fields(before: &Value, after: &Value) -> Value {
+    match (before.as_object(), after.as_object()) {
+        (Some(before_fields), Some(after_fields)) => {
+            let mut changes = serde_json::Map::new();
+            for (key, value) in after_fields {
+                match before_fields.get(key) {
+                    Some(old) if old == value => {}
+                    Some(old) if old.is_object() && value.is_object() => {
+                        let nested = changed_fields(old, value);
+                        changes.insert(key.clone(), if is_empty(&nested) { value.clone() } else { nested });
+                    }
+                    _ => {
+                        changes.insert(key.clone(), value.clone());
+                    }
+                }
+            }
+            Value::Object(changes)
+        }
+        _ => after.clone(),
+    }
+}
+
+/// What the echo of the `published` message must carry : the fields changed since `before`, all of them
+/// when `before` is unknown or when nothing changed (fields dropped, keys reordered).
+/// None when the message is empty or not JSON, nothing can tell its echo from the next message.
+pub fn expected_echo(before: Option<&Value>, published: &Value) -> Option<Value> {
+    let changes = match before {
+        Some(before) => changed_fields(before, published),
+        None => published.clone(),
+    };
+    let expected = if is_empty(&changes) { published.clone() } else { changes };
+    (!is_empty(&expected)).then_some(expected)
+}
+
+fn is_empty(value: &Value) -> bool {
+    match value {
+        Value::Null => true,
+        Value::Object(fields) => fields.is_empty(),
+        _ => false,
+    }
+}
+
+/// True if every field of `expected` has the same value in `message`
+fn matches(expected: &Value, message: &Value) -> bool {
+    match (expected.as_object(), message.as_object()) {
+        (Some(expected_fields), Some(fields)) => expected_fields
+            .iter()
+            .all(|(key, value)| fields.get(key).is_some_and(|v| matches(value, v))),
+        _ => expected == message,
+    }
+}
+
 impl <T> DeviceLock<T> {
     pub fn new(last_message: T) -> Self {
         Self {
-            count_locks: 0,
+            pending: VecDeque::new(),
+            last_processed_at: None,
+            last_processed: None,
             last_object_message: last_message,
         }
     }
 
-    pub fn inc(&mut self) {
-        self.count_locks += 1;
-        info!("🔼 After up Locks:[{}]", self.count_locks);
+    /// Wait for the echo of a published message until the timeout
+    pub fn expect(&mut self, expected: Value, timeout: Duration) {
+        self.pending.retain(|e| !e.is_expired());
+        self.pending.push_back(Expectation {
+            expected,
+            deadline: Instant::now() + timeout,
+        });
+        info!("🔼 After up Locks:[{}]", self.pending.len());
+    }
+
+    /// Remove the expectations past their deadline, they are returned to be logged
+    pub fn expire(&mut self) -> Vec<Expectation> {
+        let now = Instant::now();
+        let (expired, pending): (Vec<_>, Vec<_>) = self.pending.drain(..).partition(|e| e.deadline <= now);
+        self.pending = pending.into();
+        expired
+    }
+
+    /// Consume the oldest expectation matched by the raw message. False if the message is not an echo.
+    /// An expectation past its deadline never matches, even if `expire` was not called yet.
+    pub fn consume_echo(&mut self, raw_message: &str) -> bool {
+        let Ok(message) = serde_json::from_str::<Value>(raw_message) else {
+            return false;
+        };
+        match self.pending.iter().position(|e| !e.is_expired() && matches(&e.expected, &message)) {
+            Some(index) => {
+                self.pending.remove(index);
+                info!("⏬ After down Locks:[{}]", self.pending.len());
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// The expectations still in time
+    pub fn pending_count(&self) -> usize {
+        self.pending.iter().filter(|e| !e.is_expired()).count()
+    }
+
+    /// Remember the last message of the device that went through the loops
+    pub fn mark_processed(&mut self, message: Option<Value>) {
+        self.last_processed_at = Some(Instant::now());
+        self.last_processed = message;
+    }
+
+    pub fn since_processed(&self) -> Option<Duration> {
+        self.last_processed_at.map(|at| at.elapsed())
     }
-    pub fn dec(&mut self) {
-        self.count_locks -= 1;
-        info!("⏬ After down Locks:[{}]", self.count_locks);
+
+    pub fn last_processed(&self) -> Option<&Value> {
+        self.last_processed.as_ref()
     }
 
     pub fn replace(&mut self, o : T) {
@@ -28,3 +151,102 @@ impl <T> DeviceLock<T> {
     }
 
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn changed_fields_keeps_only_the_new_values() {
+        let before = json!({"state": "OFF", "brightness": 120, "color": {"x": 0.3, "y": 0.4}});
+        let after = json!({"state": "ON", "brightness": 120, "color": {"x": 0.5, "y": 0.4}});
+        assert_eq!(json!({"state": "ON", "color": {"x": 0.5}}), changed_fields(&before, &after));
+    }
+
+    #[test]
+    fn changed_fields_keeps_a_nested_object_that_only_lost_fields() {
+        let before = json!({"color": {"x": 0.3, "y": 0.4}});
+        let after = json!({"color": {"x": 0.3}});
+        assert_eq!(json!({"color": {"x": 0.3}}), changed_fields(&before, &after));
+    }
+
+    #[test]
+    fn changed_fields_of_a_scalar_is_the_scalar() {
+        assert_eq!(json!("ON"), changed_fields(&json!({"state": "OFF"}), &json!("ON")));
+    }
+
+    #[test]
+    fn expected_echo_is_the_whole_message_when_nothing_changed() {
+        let before = json!({"state": "ON", "brightness": 120});
+        // Only a field dropped
+        let published = json!({"state": "ON"});
+        assert_eq!(Some(json!({"state": "ON"})), expected_echo(Some(&before), &published));
+        // Only the key order
+        let published = json!({"brightness": 120, "state": "ON"});
+        assert_eq!(Some(published.clone()), expected_echo(Some(&before), &published));
+    }
+
+    #[test]
+    fn expected_echo_of_an_empty_message_is_none() {
+        assert_eq!(None, expected_echo(Some(&json!({"state": "ON"})), &json!({})));
+        assert_eq!(None, expected_echo(None, &Value::Null));
+    }
+
+    #[test]
+    fn consume_echo_takes_the_matching_expectation_once() {
+        let mut lock = DeviceLock::new(());
+        lock.expect(json!({"state": "ON"}), TIMEOUT);
+
+        assert!(!lock.consume_echo(r#"{"state": "OFF", "brightness": 120}"#));
+        assert!(lock.consume_echo(r#"{"state": "ON", "brightness": 120}"#));
+        assert!(!lock.consume_echo(r#"{"state": "ON", "brightness": 120}"#));
+        assert_eq!(0, lock.pending_count());
+    }
+
+    #[test]
+    fn consume_echo_does_not_swallow_the_next_message_after_an_empty_diff() {
+        let mut lock = DeviceLock::new(());
+        let before = json!({"state": "ON", "brightness": 120});
+        let published = json!({"brightness": 120, "state": "ON"});
+        if let Some(expected) = expected_echo(Some(&before), &published) {
+            lock.expect(expected, TIMEOUT);
+        }
+
+        // The user switches the lamp off, it's not the echo
+        assert!(!lock.consume_echo(r#"{"state": "OFF", "brightness": 120}"#));
+        assert!(lock.consume_echo(r#"{"state": "ON", "brightness": 120}"#));
+    }
+
+    #[test]
+    fn consume_echo_ignores_a_message_that_is_not_json() {
+        let mut lock = DeviceLock::new(());
+        lock.expect(json!("ON"), TIMEOUT);
+        assert!(!lock.consume_echo("ON"));
+        assert!(lock.consume_echo(r#""ON""#));
+    }
+
+    #[test]
+    fn expire_returns_the_expectations_past_their_deadline() {
+        let mut lock = DeviceLock::new(());
+        lock.expect(json!({"state": "ON"}), TIMEOUT);
+        lock.expect(json!({"state": "OFF"}), Duration::ZERO);
+
+        let expired = lock.expire();
+        assert_eq!(1, expired.len());
+        assert_eq!(json!({"state": "OFF"}), expired[0].expected);
+        assert_eq!(1, lock.pending_count());
+        assert!(lock.expire().is_empty());
+    }
+
+    #[test]
+    fn an_expired_expectation_never_matches() {
+        let mut lock = DeviceLock::new(());
+        lock.expect(json!({"state": "ON"}), Duration::ZERO);
+        assert_eq!(0, lock.pending_count());
+        assert!(!lock.consume_echo(r#"{"state": "ON"}"#));
+    }
+}
@@ -1,47 +1,70 @@
-use std::cell::RefCell;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::fmt;
+use std::time::{Duration, SystemTime};
 
-use log::{info, error};
-use rumqttc::v5::mqttbytes::QoS;
+use log::{info, warn, error};
 use serde::de::DeserializeOwned;
 use serde::Deserialize;
+use serde_json::Value;
 use uuid::Uuid;
-use crate::generic_device::{GenericDevice, Locality};
+use crate::generic_device::{GenericDevice, Locality, SharedDevice};
+use crate::config_check::{check_config, Severity};
 use crate::hard_loop::HardLoop;
+use crate::init_loop::InitSettings;
+use crate::json_schema::JsonSchema;
+use crate::loop_guard::{GuardDefinition, LoopGuard};
+use crate::rules::compile_rules;
+use crate::schedule::{Schedule, ScheduleDefinition};
+use crate::throttle::ThrottleSettings;
+use crate::topic::subscription_filter;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize)]
 pub struct DeviceDefinition {
-    family: String,
-    name: String,
-    message_type: String,       // ex: "LampRgb" → loads "LampRgb.json"
-    process_same_message: bool,
+    pub(crate) family: String,
+    pub(crate) name: String,
+    pub(crate) message_type: String,       // ex: "LampRgb" → loads "LampRgb.json"
+    pub(crate) process_same_message: bool,
+    #[serde(default)]
+    pub(crate) journal: bool,          // record every raw message through Locality::journal
+    #[serde(default)]
+    pub(crate) init: InitSettings,     // timeout, retries and policy of the init stage
+    #[serde(default)]
+    pub(crate) rules: Vec<String>,     // ex: "on action=single, toggle target.state", see rules.rs
+    #[serde(default)]
+    pub(crate) echo_timeout_ms: Option<u64>,   // how long the echo of a /set is waited for, 5 s by default
+    #[serde(flatten)]
+    pub(crate) throttle: ThrottleSettings,  // debounce_ms, min_interval_ms, thresholds
 }
 
 #[derive(Debug, Deserialize)]
 pub struct LoopDefinition {
-    loop_name: String,
-    devices: Vec<String>,
+    pub(crate) loop_name: String,
+    pub(crate) devices: Vec<String>,
+    #[serde(flatten)]
+    pub(crate) guard: GuardDefinition,  // time_windows, days, conditions
 }
 
 /// Root configuration describing a module's setup.
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Default, Deserialize)]
 pub struct ConfigRoot {
-    devices: Vec<DeviceDefinition>,
-    loops: Vec<LoopDefinition>,
+    pub(crate) devices: Vec<DeviceDefinition>,
+    pub(crate) loops: Vec<LoopDefinition>,
+    #[serde(default)]
+    pub(crate) devices_to_init: Vec<String>,
     #[serde(default)]
-    devices_to_init: Vec<String>,
+    pub(crate) devices_to_listen: Vec<String>,
     #[serde(default)]
-    devices_to_listen: Vec<String>,
+    pub(crate) schedules: Vec<ScheduleDefinition>,
 }
 
 
 /// -------------------------------------------------------------------------
 /// JSON UTILITIES
 /// -------------------------------------------------------------------------
-fn read_json_file<T: for<'de> Deserialize<'de>>(path: &Path) -> std::io::Result<T> {
+pub(crate) fn read_json_file<T: for<'de> Deserialize<'de>>(path: &Path) -> std::io::Result<T> {
     let text = fs::read_to_string(path)?;
     Ok(serde_json::from_str(&text).map_err(|e| {
         std::io::Error::new(std::io::ErrorKind::InvalidData, format!("JSON error: {}", e))
@@ -51,11 +74,68 @@ fn read_json_file<T: for<'de> Deserialize<'de>>(path: &Path) -> std::io::Result<
 /// Loads a message object of type `T` (implements Locality) from a
 /// JSON file named `<message_type>.json`.
 pub fn  factory<T>(message_type: &str, factory_message_dir: &PathBuf) -> T where T : Locality + DeserializeOwned {
+    try_factory(message_type, factory_message_dir).unwrap_or_else(|e| panic!("{}, run ava-config-check", e))
+}
+
+/// Same as `factory`, with an error instead of a panic
+pub fn try_factory<T>(message_type: &str, factory_message_dir: &Path) -> Result<T, String> where T : Locality + DeserializeOwned {
     info!("Factory builds [{}]", message_type);
-    let path_to_json = format!("{}/{}.json", factory_message_dir.to_str().unwrap(), message_type);
-    let  object_json= fs::read_to_string(path_to_json).unwrap();
-    let message : T =  serde_json::from_str(object_json.as_str()).unwrap(); // TODO
-    message
+    let path_to_json = factory_message_dir.join(format!("{}.json", message_type));
+    let  object_json= fs::read_to_string(&path_to_json)
+        .map_err(|e| format!("Cannot read the template [{}], e=[{}]", path_to_json.display(), e))?;
+    let message : T =  serde_json::from_str(object_json.as_str())
+        .map_err(|e| format!("Wrong template [{}], e=[{}]", path_to_json.display(), e))?;
+    match read_schema(message_type, factory_message_dir)? {
+        Some(schema) => Ok(message.with_schema(Arc::new(schema))),
+        None => Ok(message),
+    }
+}
+
+/// The optional `<message_type>.schema.json` next to the template
+fn read_schema(message_type: &str, factory_message_dir: &Path) -> Result<Option<JsonSchema>, String> {
+    let path = factory_message_dir.join(format!("{}.schema.json", message_type));
+    if !path.exists() {
+        return Ok(None);
+    }
+    let schema: Value = read_json_file(&path)
+        .map_err(|e| format!("Cannot read the schema [{}], e=[{}]", path.display(), e))?;
+    info!("📐 Factory loads the schema of [{}]", message_type);
+    Ok(Some(JsonSchema::new(schema)))
+}
+
+/// What a device was built from, a reload keeps the device when none of it changed
+#[derive(Debug, Clone, PartialEq)]
+struct DeviceSource {
+    definition: DeviceDefinition,
+    template: String,
+    schema: Option<String>,
+}
+
+impl DeviceSource {
+    fn read(definition: &DeviceDefinition, factory_message_dir: &Path) -> Self {
+        let read = |suffix: &str| fs::read_to_string(factory_message_dir.join(format!("{}{}", definition.message_type, suffix))).ok();
+        Self {
+            definition: definition.clone(),
+            template: read(".json").unwrap_or_default(),
+            schema: read(".schema.json"),
+        }
+    }
+}
+
+type BuiltDevices<T> = (HashMap<String, SharedDevice<T>>, HashMap<String, DeviceSource>);
+
+/// The devices changed by a reload of the module
+#[derive(Debug, Default)]
+pub struct ReloadSummary {
+    pub kept: Vec<String>,
+    pub rebuilt: Vec<String>,   // new devices, or devices with a new definition or template
+    pub removed: Vec<String>,
+}
+
+impl fmt::Display for ReloadSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "kept [{}], rebuilt {:?}, removed {:?}", self.kept.len(), &self.rebuilt, &self.removed)
+    }
 }
 
 
@@ -63,7 +143,7 @@ pub fn  factory<T>(message_type: &str, factory_message_dir: &PathBuf) -> T where
 pub struct Channels {
     pub server_addr : String,
     pub client_id : String,
-    pub channel_filters: Vec<(String, QoS)>,
+    pub channel_filters: Vec<String>, // subscribed with the QoS of the transport
     pub keep_alive :  u16,
 }
 
@@ -79,7 +159,9 @@ fn generate_client_id() -> String {
 pub struct DomoticFactory<T: Locality> {
     config_path: PathBuf,
     factory_message_dir: PathBuf, // folder holding all the message type json
-    devices: HashMap<String, Arc<RefCell<GenericDevice<T>>>>,
+    config: ConfigRoot,   // the module read by the last build or reload, the loops, lists and schedules are built from it
+    devices: HashMap<String, SharedDevice<T>>,
+    sources: HashMap<String, DeviceSource>,
 }
 
 impl<T: Locality + Clone + DeserializeOwned> DomoticFactory<T> {
@@ -88,20 +170,24 @@ impl<T: Locality + Clone + DeserializeOwned> DomoticFactory<T> {
         Self {
             config_path: config_path.as_ref().to_path_buf(),
             factory_message_dir: factory_message_dir.as_ref().to_path_buf(),
+            config: ConfigRoot::default(),
             devices: HashMap::new(),
+            sources: HashMap::new(),
         }
     }
 
     /// Static
     /// Extract channel name from devices
-    pub fn extract_channel_from_devices(devices : &Vec<Arc<RefCell<GenericDevice<T>>>>, mqtt_host: &str) -> Channels {
+    pub fn extract_channel_from_devices(devices : &Vec<SharedDevice<T>>, mqtt_host: &str) -> Channels {
         let client_id = generate_client_id(); // CLIENT_ID.to_string();
 
-        let mut channel_filters: Vec<(String, QoS)> = vec![];
+        let mut channel_filters: Vec<String> = vec![];
         for dev in devices {
-            let dd = dev.as_ref().borrow();
-            let topic = dd.get_topic();
-            channel_filters.push((topic, QoS::AtMostOnce));
+            // The wildcards inside a level are filtered by the devices, the broker gets whole levels
+            let topic = subscription_filter(&dev.get_topic());
+            if !channel_filters.contains(&topic) {
+                channel_filters.push(topic);
+            }
         }
 
         Channels {
@@ -116,53 +202,131 @@ impl<T: Locality + Clone + DeserializeOwned> DomoticFactory<T> {
     pub fn build_devices(&mut self) {
         let config: ConfigRoot =
             read_json_file(&self.config_path).expect("Cannot parse configuration");
+        let (devices, sources) = self.try_build_devices(&config).unwrap_or_else(|e| panic!("{}", e));
+        self.config = config;
+        self.devices = devices;
+        self.sources = sources;
+        info!("✅ Built {} device(s)", self.devices.len());
+    }
+
+    fn build_device(&self, def: &DeviceDefinition) -> Result<GenericDevice<T>, String> {
+        let msg: T = try_factory(&def.message_type, &self.factory_message_dir)?;
+        let mut dev = GenericDevice::new(&def.family, &def.name, msg, def.process_same_message);
+        dev.journal = def.journal;
+        dev.init_settings = def.init;
+        if let Some(echo_timeout_ms) = def.echo_timeout_ms {
+            dev.echo_timeout = Duration::from_millis(echo_timeout_ms);
+        }
+        dev.throttle = def.throttle.clone();
+        dev.rules = compile_rules(&def.rules)
+            .map_err(|e| format!("Cannot compile the rules of device [{}], {}", &def.name, e))?;
+        if !dev.rules.is_empty() {
+            info!("📜 Device [{}] has {} rule(s)", &def.name, dev.rules.len());
+        }
+        Ok(dev)
+    }
 
+    /// The devices of the configuration, the current ones are kept when their source did not change
+    fn try_build_devices(&self, config: &ConfigRoot) -> Result<BuiltDevices<T>, String> {
+        let mut devices = HashMap::new();
+        let mut sources = HashMap::new();
         for def in &config.devices {
-            let msg: T = factory(&def.message_type, &self.factory_message_dir);
-            let dev = GenericDevice::new(&def.family, &def.name, msg, def.process_same_message);
-            self.devices.insert(def.name.clone(), Arc::new(RefCell::new(dev)));
+            let source = DeviceSource::read(def, &self.factory_message_dir);
+            let device = match (self.devices.get(&def.name), self.sources.get(&def.name)) {
+                (Some(current), Some(current_source)) if *current_source == source => current.clone(),
+                _ => self.build_device(def)?.shared(),
+            };
+            devices.insert(def.name.clone(), device);
+            sources.insert(def.name.clone(), source);
         }
-        info!("✅ Built {} device(s)", self.devices.len());
+        Ok((devices, sources))
+    }
+
+    /// Read the module again after a change of its files.
+    /// The unchanged devices are kept with their state (last message, echoes waited for).
+    /// The current devices stay in place if the new module has errors.
+    pub fn reload(&mut self) -> Result<ReloadSummary, String> {
+        // Read once, the file may change again while it is checked
+        let config: ConfigRoot = read_json_file(&self.config_path)
+            .map_err(|e| format!("Cannot parse configuration, e=[{}]", e))?;
+        let errors: Vec<String> = check_config(&config, &self.factory_message_dir)
+            .into_iter()
+            .filter(|p| p.severity == Severity::Error)
+            .map(|p| p.message)
+            .collect();
+        if !errors.is_empty() {
+            return Err(errors.join(", "));
+        }
+        let (devices, sources) = self.try_build_devices(&config)?;
+
+        let mut summary = ReloadSummary::default();
+        for (name, device) in &devices {
+            match self.devices.get(name) {
+                Some(current) if Arc::ptr_eq(current, device) => summary.kept.push(name.clone()),
+                _ => summary.rebuilt.push(name.clone()),
+            }
+        }
+        summary.removed = self.devices.keys().filter(|n| !devices.contains_key(*n)).cloned().collect();
+        self.config = config;
+        self.devices = devices;
+        self.sources = sources;
+        Ok(summary)
+    }
+
+    /// The modification times of the module file and of the factory folder files
+    pub fn files_stamp(&self) -> Vec<(PathBuf, Option<SystemTime>)> {
+        let modified = |path: &Path| fs::metadata(path).and_then(|m| m.modified()).ok();
+        let mut stamp = vec![(self.config_path.clone(), modified(&self.config_path))];
+        if let Ok(entries) = fs::read_dir(&self.factory_message_dir) {
+            let mut files: Vec<PathBuf> = entries.filter_map(|e| e.ok().map(|e| e.path())).collect();
+            files.sort();
+            stamp.extend(files.into_iter().map(|f| {
+                let time = modified(&f);
+                (f, time)
+            }));
+        }
+        stamp
     }
 
     /// Return a reference to the device repository
-    pub fn repo(&self) -> &HashMap<String, Arc<RefCell<GenericDevice<T>>>> {
+    pub fn repo(&self) -> &HashMap<String, SharedDevice<T>> {
         &self.devices
     }
 
     /// Return all devices that need initialization
-    pub fn devices_to_init(&self) -> Vec<Arc<RefCell<GenericDevice<T>>>> {
-        let config: ConfigRoot =
-            read_json_file(&self.config_path).expect("Cannot parse configuration");
-        config
+    pub fn devices_to_init(&self) -> Vec<SharedDevice<T>> {
+        self.config
             .devices_to_init
-            .into_iter()
-            .filter_map(|name| self.devices.get(&name).cloned())
+            .iter()
+            .filter_map(|name| self.devices.get(name).cloned())
             .collect()
     }
 
-    pub fn devices_to_listen(&self) -> Vec<Arc<RefCell<GenericDevice<T>>>> {
-        let config: ConfigRoot =
-            read_json_file(&self.config_path).expect("Cannot parse configuration");
-        config
+    pub fn devices_to_listen(&self) -> Vec<SharedDevice<T>> {
+        self.config
             .devices_to_listen
-            .into_iter()
-            .filter_map(|name| self.devices.get(&name).cloned())
+            .iter()
+            .filter_map(|name| self.devices.get(name).cloned())
             .collect()
     }
 
     /// Build loops defined in the configuration file
     pub fn build_loops(&self) -> Vec<HardLoop<T>> {
-        let config: ConfigRoot =
-            read_json_file(&self.config_path).expect("Cannot parse configuration");
+        let config = &self.config;
         let mut loops = Vec::new();
+        let followed: HashSet<&String> = config.devices_to_listen.iter().chain(config.schedules.iter().map(|s| &s.device)).collect();
+
+        for def in &config.loops {
+            // See config_check, the state of a device that is not listened to never changes
+            for name in def.guard.check().unwrap_or_default().iter().filter(|n| !followed.contains(n)) {
+                warn!("⚠️ Device [{}] in the conditions of loop [{}] is not listened to, its state never changes", name, def.loop_name);
+            }
 
-        for def in config.loops {
             let devices: Vec<_> = def
                 .devices
-                .into_iter()
+                .iter()
                 .filter_map(|n| {
-                    if let Some(d) = self.devices.get(&n) {
+                    if let Some(d) = self.devices.get(n) {
                         Some(d.clone())
                     } else {
                         error!("Unknown device '{n}' in loop '{}'", def.loop_name);
@@ -171,9 +335,100 @@ impl<T: Locality + Clone + DeserializeOwned> DomoticFactory<T> {
                 })
                 .collect();
 
-            loops.push(HardLoop::new(def.loop_name.clone(), devices));
+            match LoopGuard::compile(&def.guard, &self.devices) {
+                Ok(guard) => loops.push(HardLoop::new(def.loop_name.clone(), devices).with_guard(guard)),
+                Err(e) => error!("💣 Loop '{}' is disabled, wrong guard, {}", def.loop_name, e),
+            }
         }
         info!("🔁 Built {} loop(s)", loops.len());
         loops
     }
+
+    /// Build the scheduled triggers defined in the configuration file
+    pub fn build_schedules(&self) -> Vec<Schedule> {
+        let mut schedules = Vec::new();
+
+        for def in &self.config.schedules {
+            let Some(device) = self.devices.get(&def.device) else {
+                error!("💣 Schedule '{}' is disabled, unknown device '{}'", def.name, def.device);
+                continue;
+            };
+            let trigger = match def.trigger() {
+                Ok(trigger) => trigger,
+                Err(e) => {
+                    error!("💣 Schedule '{}' is disabled, {}", def.name, e);
+                    continue;
+                }
+            };
+            let message = match &def.message {
+                None => None,
+                Some(value) => match device.message_type.json_to_local(&value.to_string()) {
+                    Ok(_) => Some(value.to_string()),
+                    Err(e) => {
+                        error!("💣 Schedule '{}' is disabled, wrong message, {}", def.name, e);
+                        continue;
+                    }
+                },
+            };
+            schedules.push(Schedule {
+                name: def.name.clone(),
+                topic: device.get_topic(),
+                trigger,
+                message,
+            });
+        }
+        info!("⏰ Built {} schedule(s)", schedules.len());
+        schedules
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::dynamic_message::DynamicMessage;
+
+    use super::*;
+
+    fn module(listened: &[&str]) -> String {
+        let device = |name: &str| json!({"family": "zigbee2mqtt", "name": name, "message_type": "Sensor", "process_same_message": false});
+        json!({
+            "devices": [device("hall_switch"), device("hall_lamp")],
+            "loops": [{"loop_name": "hall", "devices": ["hall_switch", "hall_lamp"]}],
+            "devices_to_listen": listened,
+            "devices_to_init": ["hall_lamp"],
+        }).to_string()
+    }
+
+    fn topics(devices: Vec<SharedDevice<DynamicMessage>>) -> Vec<String> {
+        devices.iter().map(|d| d.get_topic()).collect()
+    }
+
+    #[test]
+    fn builds_from_the_module_read_by_the_last_reload() {
+        let dir = std::env::temp_dir().join(format!("ava-domotic-factory-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("Sensor.json"), "{}").unwrap();
+        let module_file = dir.join("module.json");
+        fs::write(&module_file, module(&["hall_switch"])).unwrap();
+
+        let mut factory: DomoticFactory<DynamicMessage> = DomoticFactory::new(&module_file, &dir);
+        factory.build_devices();
+        assert_eq!(vec!["zigbee2mqtt/hall_switch"], topics(factory.devices_to_listen()));
+
+        // A file being written is refused, the lists and loops come from the current module
+        fs::write(&module_file, &module(&["hall_switch", "hall_lamp"])[..40]).unwrap();
+        assert!(factory.reload().unwrap_err().starts_with("Cannot parse configuration"));
+        assert_eq!(vec!["zigbee2mqtt/hall_switch"], topics(factory.devices_to_listen()));
+        assert_eq!(vec!["zigbee2mqtt/hall_lamp"], topics(factory.devices_to_init()));
+        assert_eq!(1, factory.build_loops().len());
+        assert!(factory.build_schedules().is_empty());
+
+        fs::write(&module_file, module(&["hall_switch", "hall_lamp"])).unwrap();
+        factory.reload().unwrap();
+        // Nothing is read after the reload
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(vec!["zigbee2mqtt/hall_switch", "zigbee2mqtt/hall_lamp"], topics(factory.devices_to_listen()));
+        assert_eq!(1, factory.build_loops().len());
+    }
 }
@@ -0,0 +1,86 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use log::info;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::generic_device::Locality;
+use crate::json_schema::JsonSchema;
+
+/// A message read as plain JSON, for the devices without a struct in `device_message.rs`.
+/// The factory template is the default message, and the optional `<message_type>.schema.json`
+/// of the factory folder checks every incoming payload.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct DynamicMessage {
+    pub value: Value,
+    #[serde(skip)]
+    schema: Option<Arc<JsonSchema>>,
+}
+
+impl DynamicMessage {
+    pub fn new(value: Value) -> Self {
+        Self { value, schema: None }
+    }
+
+    /// A message with the value, checked by the schema of this one
+    pub fn with_value(&self, value: Value) -> Result<Self, String> {
+        if let Some(schema) = &self.schema {
+            schema.validate(&value)?;
+        }
+        Ok(Self {
+            value,
+            schema: self.schema.clone(),
+        })
+    }
+
+    pub fn get(&self, field: &str) -> Option<&Value> {
+        self.value.get(field)
+    }
+}
+
+impl Locality for DynamicMessage {
+    fn query_for_state(&self) -> String {
+        r#"{"state":""}"#.to_string()
+    }
+
+    fn find_set_topic(&self, topic: &str) -> String {
+        format!("{}/set", topic)
+    }
+
+    fn raw_message(&self) -> String {
+        self.value.to_string()
+    }
+
+    fn to_local(&self, original_message: &Self, _last_message: &Self) -> Self {
+        Self {
+            value: original_message.value.clone(),
+            schema: self.schema.clone(),
+        }
+    }
+
+    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>, _source_topic: &str) -> Self {
+        self.to_local(original_message, last_message)
+    }
+
+    fn json_to_local(&self, json_msg: &str) -> Result<Self, String> {
+        let value: Value = serde_json::from_str(json_msg).map_err(|e| e.to_string())?;
+        self.with_value(value)
+    }
+
+    async fn process(&self, topic: &str, _args: &[String]) {
+        info!("Dynamic message for [{}], message=[{}]", topic, &self.value);
+    }
+
+    async fn compute(&self) -> Option<HashMap<String, f64>> {
+        None
+    }
+
+    fn with_schema(self, schema: Arc<JsonSchema>) -> Self {
+        Self {
+            value: self.value,
+            schema: Some(schema),
+        }
+    }
+}
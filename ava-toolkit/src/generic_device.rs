@@ -1,43 +1,78 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::fmt::Debug;
 use std::future::Future;
-use std::ops::Deref;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
-use log::{error, info};
-use rumqttc::v5::AsyncClient;
-use rumqttc::v5::mqttbytes::QoS;
+use log::{error, info, warn};
 use serde::de::DeserializeOwned;
-use crate::device_lock::DeviceLock;
+use tokio::sync::{Mutex, MutexGuard};
+use crate::correlation::publish_properties;
+use crate::device_lock::{expected_echo, DeviceLock, DEFAULT_ECHO_TIMEOUT};
+use crate::init_loop::InitSettings;
+use crate::metrics;
+use crate::replay::{record, Direction, RecordedMessage};
+use crate::rules::{apply_rules, Rule};
+use crate::throttle::ThrottleSettings;
+use crate::topic::{is_wildcard, topic_matches};
+use crate::transport::TransportClient;
+
+pub use ava_toolkit_derive::Locality;
+use crate::journal::JournalEntry;
+use crate::json_schema::JsonSchema;
 
 pub const ZIGBEE_FAMILY : &str = "zigbee2mqtt";
 pub const EXTERNAL_FAMILY: &str = "external";
 pub const SYSTEM_FAMILY: &str = "regulator";
 /// A Locality is a set of features
-/// shared by a group of messages often called a MessageEnum
-
-pub trait Locality : Clone + Debug  {
+/// shared by a group of messages often called a MessageEnum.
+/// The messages are processed on several tasks, so they must be Send + Sync.
+pub trait Locality : Clone + Debug + Send + Sync + 'static {
     // Self is MessageEnum indeed
     fn query_for_state(&self) -> String;
     fn find_set_topic(&self, topic: &str) -> String;
     fn raw_message(&self) -> String;
     fn to_local(&self, original_message: &Self, last_message: &Self) -> Self;
-    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, ext_data: Option<&HashMap<String, f64>>, topic: Option<&str>) -> Self;
+    /// `topic` is the topic of the target device, `source_topic` the concrete topic the original message came from,
+    /// even for a wildcard device
+    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, ext_data: Option<&HashMap<String, f64>>, topic: Option<&str>, source_topic: &str) -> Self;
     fn json_to_local(&self, json_msg: &str) -> Result<Self, String>;
+    /// `topic` is the concrete topic the message came from, even for a wildcard device
     fn process(&self, topic: &str, _args: &[String]) -> impl Future<Output = ()> + Send;
     fn compute(&self) -> impl Future<Output = Option<HashMap<String, f64>>> + Send;
+    /// Record the raw incoming message, only called for the devices with the `journal` flag
+    fn journal(&self, _entry: &JournalEntry) -> impl Future<Output = ()> + Send {
+        async {}
+    }
+    /// Attach the `<message_type>.schema.json` of the factory folder, only kept by the dynamic messages
+    fn with_schema(self, _schema: Arc<JsonSchema>) -> Self {
+        self
+    }
 }
 
 
+/// A device shared between the loops, the init stage and the processing tasks
+pub type SharedDevice<T> = Arc<GenericDevice<T>>;
+
+/// The identity of the device is read only, the state (last message, locks) is behind an async mutex.
+/// The mutex is held for the whole processing of a message, so the messages of a device are applied one at a time.
 #[derive(Debug)]
 pub struct GenericDevice<T: Locality> {
     pub family: String, // "zigbee2mqtt", "regulator", "external", ...
     pub name: String,
     pub message_type: T,
-    pub lock: Arc<RefCell<DeviceLock<T>>>,
-    pub setup: bool,
+    pub lock: Mutex<DeviceLock<T>>,
+    setup: AtomicBool,
+    unknown: AtomicBool,    // the last message is the factory one, the device never told its state
     pub process_same_message: bool,
+    pub journal: bool,
+    pub init_settings: InitSettings,
+    pub rules: Vec<Rule>,   // from the module file, they replace Locality::to_local_with_data when present
+    pub echo_timeout: Duration, // how long the echo of a published message is waited for
+    pub throttle: ThrottleSettings,
+    last_raw: std::sync::Mutex<String>,   // copy of the last message, read by the loop guards without waiting for the lock
+    instances: std::sync::Mutex<HashMap<String, SharedDevice<T>>>, // concrete devices of a wildcard device, by topic
 }
 
 impl <T> GenericDevice<T>  where T : Locality + DeserializeOwned {
@@ -49,18 +84,58 @@ impl <T> GenericDevice<T>  where T : Locality + DeserializeOwned {
             family: family.to_string(),
             name: name.to_string(),
             message_type: msg,
-            lock: Arc::new(RefCell::new(dl)),
-            setup: false,
-            process_same_message
+            lock: Mutex::new(dl),
+            setup: AtomicBool::new(false),
+            unknown: AtomicBool::new(false),
+            process_same_message,
+            journal: false,
+            init_settings: InitSettings::default(),
+            rules: vec![],
+            echo_timeout: DEFAULT_ECHO_TIMEOUT,
+            throttle: ThrottleSettings::default(),
+            last_raw: std::sync::Mutex::new(String::new()),
+            instances: std::sync::Mutex::new(HashMap::new()),
         }
     }
 
-    fn get_lock(&self) -> Arc<RefCell<DeviceLock<T>>> {
-        self.lock.clone()
+    /// Wrap the device to share it between the tasks
+    pub fn shared(self) -> SharedDevice<T> {
+        Arc::new(self)
+    }
+
+    /// True if the topic of the device is a filter, ex : `zigbee2mqtt/ts_+`
+    pub fn is_wildcard(&self) -> bool {
+        is_wildcard(&self.get_topic())
+    }
+
+    /// The device that handles a concrete topic.
+    /// A wildcard device has one instance per matching topic, each with its own state,
+    /// so the topic given to `Locality::process` is the concrete one.
+    pub fn for_topic(self: &Arc<Self>, topic: &str) -> Option<SharedDevice<T>> {
+        let own_topic = self.get_topic();
+        if !is_wildcard(&own_topic) {
+            return (own_topic == topic).then(|| self.clone());
+        }
+        if !topic_matches(&own_topic, topic) {
+            return None;
+        }
+        let mut instances = self.instances.lock().unwrap_or_else(|e| e.into_inner());
+        let instance = instances.entry(topic.to_string()).or_insert_with(|| {
+            let prefix = format!("{}/", &self.family);
+            let name = topic.strip_prefix(&prefix).unwrap_or(topic);
+            let mut dev = GenericDevice::new(&self.family, name, self.message_type.clone(), self.process_same_message);
+            dev.journal = self.journal;
+            dev.init_settings = self.init_settings;
+            dev.rules = self.rules.clone();
+            dev.echo_timeout = self.echo_timeout;
+            dev.throttle = self.throttle.clone();
+            dev.shared()
+        });
+        Some(instance.clone())
     }
 
-    fn setup(&mut self, setup: bool) {
-        self.setup = setup;
+    fn setup(&self, setup: bool) {
+        self.setup.store(setup, Ordering::SeqCst);
     }
 
     pub fn make_topic(family: &str, device_name: &str) -> String {
@@ -71,51 +146,97 @@ impl <T> GenericDevice<T>  where T : Locality + DeserializeOwned {
         format!("{}/{}", self.family, self.name)
     }
     pub fn is_init(&self) -> bool {
-        self.setup
-    }
-
-    pub fn init(&mut self, topic : &str, json_msg: &str) {
-        let new_lock = {
-            let lk = self.get_lock();
-            let borr = lk.as_ref().borrow();
-            let mut dev_lock = borr.deref().clone();
-
-            if topic == &self.get_topic() {
-                match dev_lock.last_object_message.json_to_local(json_msg) {
-                    Ok(msg) => {
-                        info!("✨ Init device [{}], with message <{:?}>",  &self.get_topic().to_uppercase(), &msg);
-                        //self.topic = topic.to_string();
-                        self.setup(true);
-                        dev_lock.replace(msg);
-                        info!("Init done");
-                    }
-                    Err(e) => {
-                        error!("✨ Fail to convert init message for device [{}], e=[{}]>",  &self.get_topic().to_uppercase(), e);
-                    }
-                }
+        self.setup.load(Ordering::SeqCst)
+    }
+
+    /// The device waits for a new init message, when the init stage runs again
+    pub fn reset_init(&self) {
+        self.setup(false);
+    }
+
+    /// Replace the last message of the device
+    fn remember(&self, dev_lock: &mut DeviceLock<T>, message: T) {
+        *self.last_raw.lock().unwrap_or_else(|e| e.into_inner()) = message.raw_message();
+        dev_lock.replace(message);
+    }
+
+    /// The last message as JSON, None while the device never told its state
+    pub fn last_state(&self) -> Option<serde_json::Value> {
+        if self.is_unknown() {
+            return None;
+        }
+        // Empty until the first message, so not JSON
+        serde_json::from_str(&self.last_raw.lock().unwrap_or_else(|e| e.into_inner())).ok()
+    }
+
+    pub fn is_unknown(&self) -> bool {
+        self.unknown.load(Ordering::SeqCst)
+    }
+
+    /// End the init with the factory message, when the device did not answer
+    pub async fn init_with_default(&self) {
+        let mut dev_lock = self.lock.lock().await;
+        self.remember(&mut dev_lock, self.message_type.clone());
+        self.setup(true);
+    }
+
+    /// Same as `init_with_default`, but the next message for the device is never considered as the same
+    pub async fn mark_unknown(&self) {
+        self.init_with_default().await;
+        self.unknown.store(true, Ordering::SeqCst);
+    }
+
+    pub async fn init(&self, topic : &str, json_msg: &str) {
+        if topic != self.get_topic() {
+            return;
+        }
+        let mut dev_lock = self.lock.lock().await;
+        match dev_lock.last_object_message.json_to_local(json_msg) {
+            Ok(msg) => {
+                info!("✨ Init device [{}], with message <{:?}>",  &self.get_topic().to_uppercase(), &msg);
+                self.setup(true);
+                self.unknown.store(false, Ordering::SeqCst);
+                self.remember(&mut dev_lock, msg);
+                info!("Init done");
             }
-            dev_lock
-        };
-        self.get_lock().replace(new_lock.clone());
+            Err(e) => {
+                error!("✨ Fail to convert init message for device [{}], e=[{}]>",  &self.get_topic().to_uppercase(), e);
+            }
+        }
     }
 
     /// Send the message on the right end point (/get) to trigger the device properties on the bus
-    pub fn trigger_info(&self) -> Vec<u8> {
-        let lk = self.get_lock();
-        let borr = lk.as_ref().borrow();
-        let dev_lock = borr.deref().clone();
+    pub async fn trigger_info(&self) -> Vec<u8> {
+        let dev_lock = self.lock.lock().await;
         dev_lock.last_object_message.query_for_state().as_bytes().to_vec()
     }
 
-    fn allowed_to_process(&self, object_message: &T) -> (bool, bool) {
-        let lk = self.get_lock();
-        let borr = lk.as_ref().borrow();
-        let dev_lock = borr.deref().clone();
+    /// Take the lock of the device, counting the times another message holds it
+    async fn acquire(&self) -> MutexGuard<'_, DeviceLock<T>> {
+        match self.lock.try_lock() {
+            Ok(dev_lock) => dev_lock,
+            Err(_) => {
+                metrics::lock_wait(&self.name);
+                self.lock.lock().await
+            }
+        }
+    }
+
+    fn is_same(&self, dev_lock: &DeviceLock<T>, object_message: &T) -> bool {
+        !self.is_unknown() && object_message.raw_message() == dev_lock.last_object_message.raw_message()
+    }
+
+    /// Drop the echoes waited for too long, the device may have missed the message
+    fn expire_echoes(&self, dev_lock: &mut DeviceLock<T>) {
+        for stale in dev_lock.expire() {
+            warn!("⌛ Device {} never echoed <{}>, no longer waited for.", & self.get_topic().to_uppercase(), &stale.expected);
+        }
+    }
 
-        let incoming_message = object_message.raw_message();
-        let is_locked = dev_lock.count_locks > 0;
-        let is_same = *incoming_message == dev_lock.last_object_message.raw_message();
-        (is_locked, is_same)
+    /// True if the message is the echo of a message published to the device, the echo is consumed
+    fn is_echo(&self, dev_lock: &mut DeviceLock<T>, incoming_message: &T) -> bool {
+        self.expire_echoes(dev_lock);
+        dev_lock.consume_echo(&incoming_message.raw_message())
     }
 
     ///
@@ -123,7 +244,7 @@ impl <T> GenericDevice<T>  where T : Locality + DeserializeOwned {
     ///
     async fn process(&self,  original_message : &T, args: &[String]) {
         info!("Default empty process for device {}.", & self.get_topic());
-        original_message.process(& self.get_topic(), &args).await;
+        original_message.process(& self.get_topic(), args).await;
     }
 
     ///
@@ -132,105 +253,298 @@ impl <T> GenericDevice<T>  where T : Locality + DeserializeOwned {
     pub async fn process_and_continue(&self, original_message : &T, args: &[String]) -> bool {
 
         info!("process_and_continue");
-        let (new_lock, allowed) = {
-            let lk = self.get_lock();
-            let borr = lk.as_ref().borrow();
-            let mut dev_lock = borr.deref().clone();
-            let allowed: bool;
-            match self.allowed_to_process(&original_message) {
-                (true, _) => {
-                    info!("❌ Device {} is locked.", & self.get_topic().to_uppercase());
-                    dev_lock.dec();
-                    allowed = false;
-                }
-                (false, true) => {
-                    if self.process_same_message {
-                        info!("❌ Device {}, same message, process anyways.", & self.get_topic().to_uppercase());
-                        self.process(&original_message, &args).await; // In this case, we process the message even if it's the same as before
-                        allowed = true;    
-                    } else {
-                        info!("❌ Device {}, same message.", & self.get_topic().to_uppercase());
-                        allowed = false;
-                    }
+        let mut dev_lock = self.acquire().await;
+        let allowed: bool;
+        let is_echo = self.is_echo(&mut dev_lock, original_message);
+        let o_json = if self.throttle.has_thresholds() {
+            serde_json::from_str::<serde_json::Value>(&original_message.raw_message()).ok()
+        } else {
+            None
+        };
+        let is_throttled = self.is_throttled(&dev_lock, o_json.as_ref());
+        match (is_echo || is_throttled, self.is_same(&dev_lock, original_message)) {
+            (true, _) => {
+                if is_echo {
+                    info!("❌ Device {}, echo of a published message.", & self.get_topic().to_uppercase());
+                    metrics::device_message(&self.name, "echo");
+                } else {
+                    metrics::device_message(&self.name, "throttled");
                 }
-                (false, false) => {
-                    info!("👍 Device {}, allowed to process the message.", & self.get_topic().to_uppercase());
-                    self.process(&original_message, &args).await;
+                allowed = false;
+            }
+            (false, true) => {
+                if self.process_same_message {
+                    info!("❌ Device {}, same message, process anyways.", & self.get_topic().to_uppercase());
+                    self.process(original_message, args).await; // In this case, we process the message even if it's the same as before
+                    metrics::device_message(&self.name, "processed");
                     allowed = true;
+                } else {
+                    info!("❌ Device {}, same message.", & self.get_topic().to_uppercase());
+                    metrics::device_message(&self.name, "same");
+                    allowed = false;
                 }
             }
-            //let json_message= original_message.raw_message();
-            dev_lock.replace(original_message.clone());
-            (dev_lock, allowed)
-        };
-        self.get_lock().replace(new_lock);
+            (false, false) => {
+                info!("👍 Device {}, allowed to process the message.", & self.get_topic().to_uppercase());
+                self.process(original_message, args).await;
+                metrics::device_message(&self.name, "processed");
+                allowed = true;
+            }
+        }
+        if allowed {
+            dev_lock.mark_processed(o_json);
+        }
+        self.remember(&mut dev_lock, original_message.clone());
+        self.unknown.store(false, Ordering::SeqCst);
         allowed
     }
 
+    /// True if the message comes too soon after the last processed one, or does not change enough
+    fn is_throttled(&self, dev_lock: &DeviceLock<T>, o_json: Option<&serde_json::Value>) -> bool {
+        if self.throttle.too_soon(dev_lock.since_processed()) {
+            info!("⏱️ Device {}, too soon after the last processed message.", & self.get_topic().to_uppercase());
+            return true;
+        }
+        if let Some(json) = o_json {
+            if !self.throttle.is_significant(dev_lock.last_processed(), json) {
+                info!("〰️ Device {}, change under the thresholds.", & self.get_topic().to_uppercase());
+                return true;
+            }
+        }
+        false
+    }
+
     ///
-    /// Make the device consume the current message
+    /// Make the device consume the current message, received on the concrete topic `source_topic`
     ///
-    pub async fn consume_message(&self, original_message : &T, o_ext_data: Option<&HashMap<String, f64>>, mut client: &mut AsyncClient) {
+    pub async fn consume_message(&self, original_message : &T, o_ext_data: Option<&HashMap<String, f64>>, source_topic: &str, client: &dyn TransportClient) {
         info!("The device is consuming the message");
-        let new_lock = {
-            let lk = self.get_lock();
-            let borr = lk.as_ref().borrow();
-            let mut dev_lock = borr.deref().clone();
-
-            info!("Execute device {}", & self.get_topic().to_uppercase());
-
-            // Last message est du même format que le message du device.
-            // Il permet de récupérer certaines informations.
-            // Ex : Incoming inter dim message + last (LampRGB) ---> hall_lamp message (LampRGB)
-            // In Generic Mode it's much simplier, we have the last message in the correct format.
-            let last_message = &dev_lock.last_object_message;
-
-            // TODO : make the get_topic an option here
-            let topic = self.get_topic();
-            let o_topic = match topic.as_str() {
-                "" => None,
-                t => Some(t),
-            };
-            
-            let object_message = self.message_type.to_local_with_data(&original_message, &last_message, o_ext_data, o_topic );
-            // let object_message = self.message_type.to_local(&original_message, &last_message);
-            // let object_message = self.to_local(&original_message, &last_message);
-
-            match self.allowed_to_process(&object_message) {
-                (true, _) => {
-                    info!("⛔ Device {} is locked.", & self.get_topic().to_uppercase());
-                    info!("object message : {:?}", &object_message);
-                    info!("Last message : {:?}", &dev_lock.last_object_message);
-                    dev_lock.dec();
-                    // self.unlock(&mut locks);
-                }
-                (false, true) => {
-                    info!("⛔ Device {}, same message.", & self.get_topic().to_uppercase());
-                    info!("object message : {:?}", &object_message);
-                    info!("Last message : {:?}", &dev_lock.last_object_message);
-                }
-                (false, false) => {
-                    info!("🍺 Device {}, process the message.", & self.get_topic().to_uppercase());
-                    info!("object message : {:?}", &object_message);
-                    info!("Last message : {:?}", &dev_lock.last_object_message);
-                    dev_lock.inc();
-                    self.publish_message(&mut client, &object_message).await;
-                }
-            }
-            dev_lock.replace(object_message);
+        let mut dev_lock = self.acquire().await;
+
+        info!("Execute device {}", & self.get_topic().to_uppercase());
+
+        // Last message est du même format que le message du device.
+        // Il permet de récupérer certaines informations.
+        // Ex : Incoming inter dim message + last (LampRGB) ---> hall_lamp message (LampRGB)
+        // In Generic Mode it's much simplier, we have the last message in the correct format.
+        let last_message = &dev_lock.last_object_message;
 
-            let message_locked = &dev_lock.last_object_message;
-            info!("Now last : {:?}", &message_locked);
-            dev_lock
+        // TODO : make the get_topic an option here
+        let topic = self.get_topic();
+        let o_topic = match topic.as_str() {
+            "" => None,
+            t => Some(t),
         };
-        self.get_lock().replace(new_lock);
+
+        let object_message = if self.rules.is_empty() {
+            self.message_type.to_local_with_data(original_message, last_message, o_ext_data, o_topic, source_topic)
+        } else {
+            self.apply_rules(original_message, last_message)
+        };
+
+        // A message waiting for its echo does not stop the next ones, only the echo is ignored
+        self.expire_echoes(&mut dev_lock);
+        if self.is_same(&dev_lock, &object_message) {
+            info!("⛔ Device {}, same message.", & self.get_topic().to_uppercase());
+            metrics::command(&self.name, "same");
+            info!("object message : {:?}", &object_message);
+            info!("Last message : {:?}", &dev_lock.last_object_message);
+        } else {
+            info!("🍺 Device {}, process the message.", & self.get_topic().to_uppercase());
+            info!("object message : {:?}", &object_message);
+            info!("Last message : {:?}", &dev_lock.last_object_message);
+            match self.expected_echo(&dev_lock.last_object_message, &object_message) {
+                Some(expected) => dev_lock.expect(expected, self.echo_timeout),
+                None => warn!("Device {}, the echo of <{}> cannot be told apart, not waited for.", & self.get_topic().to_uppercase(), object_message.raw_message()),
+            }
+            metrics::command(&self.name, "published");
+            self.publish_message(client, &object_message).await;
+        }
+        self.remember(&mut dev_lock, object_message);
+        self.unknown.store(false, Ordering::SeqCst);
+
+        info!("Now last : {:?}", &dev_lock.last_object_message);
+    }
+
+    /// The echo must carry the fields changed by the message, all of them when the last state is unknown
+    fn expected_echo(&self, last_message: &T, object_message: &T) -> Option<serde_json::Value> {
+        let read = |m: &T| serde_json::from_str(&m.raw_message()).unwrap_or(serde_json::Value::Null);
+        let last = (!self.is_unknown()).then(|| read(last_message));
+        expected_echo(last.as_ref(), &read(object_message))
+    }
+
+    /// Build the new message of the device from the rules of the module file.
+    /// The last message is kept if a rule fails, so nothing is published.
+    fn apply_rules(&self, original_message: &T, last_message: &T) -> T {
+        let result = serde_json::from_str(&original_message.raw_message())
+            .and_then(|source| Ok((source, serde_json::from_str(&last_message.raw_message())?)))
+            .map_err(|e| format!("Cannot read the messages as JSON, e=[{}]", e))
+            .and_then(|(source, target)| apply_rules(&self.rules, &source, &target))
+            .and_then(|json| last_message.json_to_local(&json.to_string()));
+        match result {
+            Ok(message) => message,
+            Err(e) => {
+                error!("💣 Rules of device [{}] not applied, {}", &self.name, e);
+                last_message.clone()
+            }
+        }
     }
-    
-    pub async fn publish_message(&self, client: &mut AsyncClient, object_message : &T) {
+
+    pub async fn publish_message(&self, client: &dyn TransportClient, object_message : &T) {
         let message = object_message.raw_message();
         let data = message.as_bytes().to_vec();
         let set_topic = self.message_type.find_set_topic(&self.get_topic());
         info!("Publishing the message to channel [{}]", & set_topic);
-        client.publish(&set_topic, QoS::AtLeastOnce, false, data).await.unwrap(); // TODO
+        // The correlation ID of the message being processed goes along with the command
+        match client.publish(&set_topic, data, publish_properties()).await {
+            Ok(()) => {
+                metrics::publish(&set_topic, true);
+                record(RecordedMessage::new(Direction::Out, &set_topic, &message));
+            }
+            Err(e) => {
+                error!("💣 Cannot publish to [{}], e=[{}]", &set_topic, e);
+                metrics::publish(&set_topic, false);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use serde_derive::{Deserialize, Serialize};
+    use serde_json::json;
+
+    use crate::dynamic_message::DynamicMessage;
+
+    use super::*;
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct LampMsg {
+        state: String,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    struct SwitchMsg {
+        action: String,
+    }
+
+    static JOURNAL: Mutex<Vec<String>> = Mutex::new(vec![]);
+
+    /// `#[derive(Locality)]` with every attribute
+    #[derive(Debug, Clone, Locality)]
+    #[locality(with_data, journal)]
+    enum FullEnum {
+        #[locality(set_suffix = "set", state_query = r#"{"state":"","brightness":""}"#)]
+        Lamp(LampMsg),
+        Switch(SwitchMsg),
+        #[locality(dynamic)]
+        Dynamic(DynamicMessage),
+    }
+
+    impl FullEnum {
+        fn to_local(&self, _original_message: &Self, last_message: &Self) -> Self {
+            last_message.clone()
+        }
+
+        fn to_local_with_data(&self, _original_message: &Self, _last_message: &Self, ext_data: Option<&HashMap<String, f64>>, topic: Option<&str>, source_topic: &str) -> Self {
+            let state = format!("{:?} {:?} {}", ext_data.map(|d| d["lux"]), topic, source_topic);
+            FullEnum::Lamp(LampMsg { state })
+        }
+
+        async fn process(&self, _topic: &str, _args: &[String]) {}
+
+        async fn compute(&self) -> Option<HashMap<String, f64>> {
+            None
+        }
+
+        async fn journal(&self, entry: &JournalEntry) {
+            JOURNAL.lock().unwrap().push(entry.topic.clone());
+        }
+    }
+
+    /// `#[derive(Locality)]` without attribute
+    #[derive(Debug, Clone, Locality)]
+    enum PlainEnum {
+        Lamp(LampMsg),
+    }
+
+    impl PlainEnum {
+        fn to_local(&self, original_message: &Self, _last_message: &Self) -> Self {
+            let PlainEnum::Lamp(lamp) = original_message;
+            PlainEnum::Lamp(LampMsg { state: format!("to_local {}", lamp.state) })
+        }
+
+        async fn process(&self, _topic: &str, _args: &[String]) {}
+
+        async fn compute(&self) -> Option<HashMap<String, f64>> {
+            None
+        }
+    }
+
+    fn lamp(state: &str) -> FullEnum {
+        FullEnum::Lamp(LampMsg { state: state.to_string() })
+    }
+
+    #[test]
+    fn variant_attributes() {
+        let switch = FullEnum::Switch(SwitchMsg { action: "single".to_string() });
+        assert_eq!(r#"{"state":"","brightness":""}"#, lamp("ON").query_for_state());
+        assert_eq!(r#"{"state":""}"#, switch.query_for_state());
+        assert_eq!("zigbee2mqtt/hall_lamp/set", lamp("ON").find_set_topic("zigbee2mqtt/hall_lamp"));
+        assert_eq!("zigbee2mqtt/hall_switch", switch.find_set_topic("zigbee2mqtt/hall_switch"));
+    }
+
+    #[test]
+    fn json_keeps_the_variant() {
+        assert_eq!(r#"{"state":"ON"}"#, lamp("ON").raw_message());
+        match lamp("ON").json_to_local(r#"{"state":"OFF"}"#) {
+            Ok(FullEnum::Lamp(msg)) => assert_eq!("OFF", msg.state),
+            other => panic!("Wrong message {:?}", other),
+        }
+        assert!(lamp("ON").json_to_local(r#"{"action":"single"}"#).is_err());
+    }
+
+    #[test]
+    fn dynamic_variant_uses_the_schema() {
+        let schema = Arc::new(JsonSchema::new(json!({"required": ["illuminance"]})));
+        let dynamic = FullEnum::Dynamic(DynamicMessage::new(json!({"illuminance": 0}))).with_schema(schema.clone());
+        assert_eq!(r#"{"illuminance":0}"#, dynamic.raw_message());
+        match dynamic.json_to_local(r#"{"illuminance":30}"#) {
+            Ok(FullEnum::Dynamic(msg)) => assert_eq!(Some(&json!(30)), msg.get("illuminance")),
+            other => panic!("Wrong message {:?}", other),
+        }
+        assert_eq!(Err("[illuminance] is missing".to_string()), dynamic.json_to_local(r#"{"linkquality":80}"#).map(|_| ()));
+
+        // The other variants ignore the schema
+        assert!(lamp("ON").with_schema(schema).json_to_local(r#"{"state":"OFF"}"#).is_ok());
+    }
+
+    #[test]
+    fn with_data_calls_the_inherent_one() {
+        let data = HashMap::from([("lux".to_string(), 12.0)]);
+        let FullEnum::Lamp(msg) = lamp("").to_local_with_data(&lamp("ON"), &lamp("OFF"), Some(&data), Some("zigbee2mqtt/hall_lamp"), "zigbee2mqtt/ts_salon") else {
+            panic!("Wrong variant");
+        };
+        assert_eq!(r#"Some(12.0) Some("zigbee2mqtt/hall_lamp") zigbee2mqtt/ts_salon"#, msg.state);
+    }
+
+    #[test]
+    fn without_with_data_to_local_is_called() {
+        let on = PlainEnum::Lamp(LampMsg { state: "ON".to_string() });
+        let PlainEnum::Lamp(msg) = on.to_local_with_data(&on, &on, None, Some("zigbee2mqtt/hall_lamp"), "zigbee2mqtt/hall_switch");
+        assert_eq!("to_local ON", msg.state);
+    }
+
+    #[tokio::test]
+    async fn journal_calls_the_inherent_one() {
+        let entry = JournalEntry::new("zigbee2mqtt/journal_test", "zigbee2mqtt", "journal_test", r#"{"state":"ON"}"#, None);
+        Locality::journal(&lamp("ON"), &entry).await;
+        assert!(JOURNAL.lock().unwrap().contains(&"zigbee2mqtt/journal_test".to_string()));
+
+        // Nothing recorded without the attribute, the default does nothing
+        let on = PlainEnum::Lamp(LampMsg { state: "ON".to_string() });
+        Locality::journal(&on, &entry).await;
     }
 }
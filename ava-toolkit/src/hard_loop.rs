@@ -1,38 +1,54 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
-use std::ops::Deref;
-use std::sync::Arc;
 use log::info;
-use rumqttc::v5::AsyncClient;
 use serde::de::DeserializeOwned;
-use crate::generic_device::{GenericDevice, Locality};
+use chrono::Local;
+use crate::generic_device::{Locality, SharedDevice};
+use crate::loop_guard::LoopGuard;
+use crate::topic::topic_matches;
+use crate::transport::TransportClient;
 
 #[derive(Clone)]
 pub struct HardLoop<T : Locality> {
     pub name : String,
-    pub devices : Vec<Arc<RefCell<GenericDevice<T>>>>,
+    pub devices : Vec<SharedDevice<T>>,
+    pub guard: LoopGuard<T>,
 }
 
 impl <T> HardLoop<T> where T : Locality + DeserializeOwned {
-    pub fn new(name: String, devices : Vec<Arc<RefCell<GenericDevice<T>>>>) -> Self {
+    pub fn new(name: String, devices : Vec<SharedDevice<T>>) -> Self {
         Self {
             name,
             devices,
+            guard: LoopGuard::default(),
         }
     }
 
+    pub fn with_guard(mut self, guard: LoopGuard<T>) -> Self {
+        self.guard = guard;
+        self
+    }
+
     // static
-    pub fn find_loops(topic: &str, all_loops: &Vec<HardLoop<T>>) -> (Vec<HardLoop<T>>, Option<Arc<RefCell<GenericDevice<T>>>>)  {
+    /// The active loops of the device, the device is returned even if its loops are inactive, for its own processing.
+    /// When an exact device and a wildcard device both match the topic, the exact device is returned.
+    pub fn find_loops(topic: &str, all_loops: &Vec<HardLoop<T>>) -> (Vec<HardLoop<T>>, Option<SharedDevice<T>>)  {
         let mut eligible_loops : Vec<HardLoop<T>> = vec![];
-        let mut output_dev : Option<Arc<RefCell<GenericDevice<T>>>> = None;
+        let mut output_dev : Option<SharedDevice<T>> = None;
+        let now = Local::now();
 
         for lp in all_loops {
             match lp.find_device_by_topic(topic) {
                 None => {}
                 Some(dev) => {
-                    info!("Found topic in [{}] loop, topic=[{}]", & lp.get_name(), topic);
-                    eligible_loops.push(lp.clone());
-                    output_dev = Some(dev.clone());
+                    if output_dev.is_none() || lp.has_exact_device(topic) {
+                        output_dev = Some(dev.clone());
+                    }
+                    if lp.guard.is_active(&now) {
+                        info!("Found topic in [{}] loop, topic=[{}]", & lp.get_name(), topic);
+                        eligible_loops.push(lp.clone());
+                    } else {
+                        info!("💤 Loop [{}] is not active, topic=[{}]", & lp.get_name(), topic);
+                    }
                 }
             }
 
@@ -45,35 +61,76 @@ impl <T> HardLoop<T> where T : Locality + DeserializeOwned {
         self.name.clone()
     }
 
-    fn get_devices(&self) -> Vec<Arc<RefCell<GenericDevice<T>>>> {
+    fn get_devices(&self) -> Vec<SharedDevice<T>> {
         self.devices.clone()
     }
 
-    pub fn find_device_by_topic(&self, topic: &str) -> Option<Arc<RefCell<GenericDevice<T>>>> {
-        for dev in self.get_devices() {
-            let dd = dev.deref().borrow();
-            if dd.get_topic() == topic {
-                return Some(dev.clone());
-            }
-        }
-        None
+    /// The device of the loop for a concrete topic, an instance of the device if its topic is a filter.
+    /// The exact device wins over a wildcard device matching the same topic.
+    pub fn find_device_by_topic(&self, topic: &str) -> Option<SharedDevice<T>> {
+        self.devices.iter().find(|dev| dev.get_topic() == topic).cloned()
+            .or_else(|| self.devices.iter().find_map(|dev| dev.for_topic(topic)))
+    }
+
+    fn has_exact_device(&self, topic: &str) -> bool {
+        self.devices.iter().any(|dev| dev.get_topic() == topic)
     }
 
     /// This routine may manipulate some external data, like in the regulator project.
-    pub async fn loop_devices(&self, topic: &str, original_message: &T, o_ext_data: Option<&HashMap<String, f64>>, mut client: &mut AsyncClient) {
+    /// Each device is locked while it consumes the message, the loops sharing a device wait for each other.
+    /// A wildcard device only receives messages, there is no concrete topic to send them to.
+    pub async fn loop_devices(&self, topic: &str, original_message: &T, o_ext_data: Option<&HashMap<String, f64>>, client: &dyn TransportClient) {
         let devices = self.get_devices();
-        for dev in devices.iter() {
-            let ref_device = dev.as_ref().borrow();
-            let device = ref_device.deref();
+        for device in devices.iter() {
             info!("Loop the devices : [{}], for the current topic [{}]", &device.get_topic(), topic);
-            if &device.get_topic() != topic {
+            if topic_matches(&device.get_topic(), topic) {
+                info!("Device ignored : [{}]", &device.get_topic());
+            } else if device.is_wildcard() {
+                info!("Wildcard device ignored : [{}]", &device.get_topic());
+            } else {
                 info!("🚀 Device Topic of the loop: [{:?}]", &device.get_topic());
-                device.consume_message(original_message, o_ext_data, &mut client).await;
+                device.consume_message(original_message, o_ext_data, topic, client).await;
                 info!("🚩 End Device Topic of the loop: [{:?}]", &device.get_topic());
-            } else {
-                 info!("Device ignored : [{}]", &device.get_topic());
             }
         }
     }
 
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use serde_json::json;
+
+    use super::*;
+    use crate::dynamic_message::DynamicMessage;
+    use crate::generic_device::GenericDevice;
+
+    fn device(name: &str) -> SharedDevice<DynamicMessage> {
+        GenericDevice::new("zigbee2mqtt", name, DynamicMessage::new(json!({})), false).shared()
+    }
+
+    #[test]
+    fn the_exact_device_wins_over_a_wildcard_device() {
+        let all_sensors = device("ts_+");
+        let salon_sensor = device("ts_salon");
+        let lamp = device("lamp");
+        let wildcard_loop = HardLoop::new("all".to_string(), vec![all_sensors.clone(), lamp.clone()]);
+        let exact_loop = HardLoop::new("salon".to_string(), vec![salon_sensor.clone(), lamp.clone()]);
+
+        for all_loops in [vec![wildcard_loop.clone(), exact_loop.clone()], vec![exact_loop.clone(), wildcard_loop.clone()]] {
+            let (loops, dev) = HardLoop::find_loops("zigbee2mqtt/ts_salon", &all_loops);
+            assert_eq!(2, loops.len());
+            assert!(Arc::ptr_eq(&salon_sensor, &dev.unwrap()));
+        }
+
+        // The other sensors still get an instance of the wildcard device
+        let (loops, dev) = HardLoop::find_loops("zigbee2mqtt/ts_bureau", &vec![exact_loop, wildcard_loop]);
+        assert_eq!(1, loops.len());
+        assert_eq!("ts_bureau", dev.unwrap().name);
+
+        let mixed_loop = HardLoop::new("mixed".to_string(), vec![all_sensors, salon_sensor.clone(), lamp]);
+        assert!(Arc::ptr_eq(&salon_sensor, &mixed_loop.find_device_by_topic("zigbee2mqtt/ts_salon").unwrap()));
+    }
+}
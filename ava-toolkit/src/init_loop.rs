@@ -1,108 +1,464 @@
-use std::cell::RefCell;
-use std::ops::{Deref, DerefMut};
-use std::sync::Arc;
-use log::info;
-use rumqttc::v5::{AsyncClient, Event, EventLoop, Incoming};
-use rumqttc::v5::mqttbytes::QoS;
+use std::collections::HashMap;
+use std::fmt;
+use std::time::Duration;
+use log::{info, warn, error};
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
 use serde::de::DeserializeOwned;
-use crate::generic_device::{GenericDevice, Locality};
+use serde::Deserialize;
+use tokio::time::{timeout_at, Instant};
+use crate::generic_device::{Locality, SharedDevice};
+use crate::reconnect::{set_connected, Reconnect};
+use crate::replay::{record, Direction, RecordedMessage};
+use crate::transport::{while_polling, TransportClient, TransportEvent, TransportEvents};
+
+/// What to do with a device that never answered its `/get` requests
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum InitPolicy {
+    /// Keep waiting for the answer after the last request, the service does not start without it
+    #[default]
+    Wait,
+    /// The initialization stage fails
+    Fail,
+    /// Start with the factory message of the device
+    UseDefault,
+    /// Start with the factory message, but never trust it: the first message sent to the device is always published
+    MarkUnknown,
+}
+
+/// Per device initialization settings, the "init" object of a device definition
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(default)]
+pub struct InitSettings {
+    pub timeout_ms: u64,  // wait for an answer, for each request
+    pub retries: u32,     // number of /get requests sent again after the first one
+    pub policy: InitPolicy,
+}
+
+impl Default for InitSettings {
+    fn default() -> Self {
+        Self {
+            timeout_ms: 10_000,
+            retries: 2,
+            policy: InitPolicy::Wait,
+        }
+    }
+}
+
+impl InitSettings {
+    pub fn timeout(&self) -> Duration {
+        Duration::from_millis(self.timeout_ms)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum InitStatus {
+    Ready,
+    Defaulted,
+    Unknown,
+    Failed,
+}
+
+#[derive(Debug, Clone)]
+pub struct DeviceInitOutcome {
+    pub topic: String,
+    pub status: InitStatus,
+    pub requests: u32,       // number of /get requests sent
+    pub elapsed: Duration,
+}
+
+/// Result of the initialization stage, one outcome per device
+#[derive(Debug, Clone, Default)]
+pub struct InitSummary {
+    pub outcomes: Vec<DeviceInitOutcome>,
+}
+
+impl InitSummary {
+    pub fn count(&self, status: InitStatus) -> usize {
+        self.outcomes.iter().filter(|o| o.status == status).count()
+    }
+
+    pub fn failed(&self) -> Vec<&DeviceInitOutcome> {
+        self.outcomes.iter().filter(|o| o.status == InitStatus::Failed).collect()
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.failed().is_empty()
+    }
+}
+
+impl fmt::Display for InitSummary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "ready=[{}], defaulted=[{}], unknown=[{}], failed=[{}]",
+            self.count(InitStatus::Ready),
+            self.count(InitStatus::Defaulted),
+            self.count(InitStatus::Unknown),
+            self.count(InitStatus::Failed)
+        )?;
+        for o in &self.outcomes {
+            write!(f, "\n  {} : {:?} after {} request(s), {:?}", o.topic, o.status, o.requests, o.elapsed)?;
+        }
+        Ok(())
+    }
+}
+
+/// A device still waiting for its answer
+struct PendingInit<T: Locality> {
+    device: SharedDevice<T>,
+    requests: u32,
+    deadline: Option<Instant>,  // None once the device is waited for without limit
+}
+
+/// Ask the device for its state, the answers received meanwhile go to the devices to init
+async fn request_info<T>(client: &dyn TransportClient, events: &mut dyn TransportEvents, device: &SharedDevice<T>, device_to_init: &[SharedDevice<T>]) -> Result<(), String>
+where
+    T: Locality + DeserializeOwned,
+{
+    let data = device.trigger_info().await;
+    let topic = format!("{}/get", device.get_topic());
+    let request = client.publish(&topic, data, PublishProperties::default());
+    while_polling(request, events, |event| handle_event(event, device_to_init))
+        .await
+        .map_err(|e| format!("Publish failed: {}", e))
+}
+
+/// Apply the policy of a device that did not answer, None if the device is still waited for
+async fn give_up<T>(device: &SharedDevice<T>) -> Option<InitStatus>
+where
+    T: Locality + DeserializeOwned,
+{
+    match device.init_settings.policy {
+        InitPolicy::Wait => {
+            warn!("⏳ Device [{}] did not answer, keep waiting for it", device.get_topic());
+            None
+        }
+        InitPolicy::Fail => {
+            error!("💀 Device [{}] did not answer, the initialization fails", device.get_topic());
+            Some(InitStatus::Failed)
+        }
+        InitPolicy::UseDefault => {
+            warn!("Device [{}] did not answer, use the factory message", device.get_topic());
+            device.init_with_default().await;
+            Some(InitStatus::Defaulted)
+        }
+        InitPolicy::MarkUnknown => {
+            warn!("Device [{}] did not answer, its state is unknown", device.get_topic());
+            device.mark_unknown().await;
+            Some(InitStatus::Unknown)
+        }
+    }
+}
 
 /// Send an information request for all devices that need initialization.
 /// Each device publishes its `trigger_info` on the `<topic>/get` channel.
 /// Then we listen to responses from Mosquitto and run the initialization routine
 /// for each device until all are marked as initialized.
 ///
-/// # Warning
-///
-/// ⚠️ If one of the devices never replies, this loop will **never terminate**.
-/// Consider adding a timeout or a safeguard to avoid blocking forever.
+/// A device that does not answer within its `timeout_ms` is asked again, up to `retries` times,
+/// then its `InitPolicy` applies. The stage returns an error if one of the devices has the `Fail` policy.
+/// When the connection drops, the channels are subscribed again once the broker is back,
+/// and the devices still pending are asked again, these requests are not counted as retries.
 pub async fn process_initialization_message<T>(
-    client: &mut AsyncClient,
-    eventloop: &mut EventLoop,
-    device_to_init: &Vec<Arc<RefCell<GenericDevice<T>>>>,
-) -> Result<(), String>
+    client: &dyn TransportClient,
+    events: &mut dyn TransportEvents,
+    device_to_init: &[SharedDevice<T>],
+    reconnect: &Reconnect<T>,
+) -> Result<InitSummary, String>
 where
     T: Locality + DeserializeOwned,
 {
     info!("Initialization stage starts");
+    let start = Instant::now();
+    let mut summary = InitSummary::default();
+
+    // There is no /get topic for a filter, the instances get their state from their first message
+    let (wildcards, device_to_init): (Vec<_>, Vec<_>) = device_to_init.iter().cloned().partition(|d| d.is_wildcard());
+    for dev in &wildcards {
+        warn!("Wildcard device [{}] cannot be initialized, ignored", dev.get_topic());
+    }
+    let device_to_init = &device_to_init;
 
     if !device_to_init.is_empty() {
         // Ask every device for its info
+        let mut pending: HashMap<String, PendingInit<T>> = HashMap::new();
         for dev in device_to_init {
-            let borr = dev.as_ref().borrow();
-            let dd = borr.deref();
-
-            let data = dd.trigger_info();
-            client
-                .publish(&format!("{}/get", dd.get_topic()), QoS::AtLeastOnce, false, data)
-                .await
-                .map_err(|e| format!("Publish failed: {}", e))?;
+            dev.reset_init();
+            request_info(client, events, dev, device_to_init).await?;
+            pending.insert(dev.get_topic(), PendingInit {
+                device: dev.clone(),
+                requests: 1,
+                deadline: Some(Instant::now() + dev.init_settings.timeout()),
+            });
         }
 
-        // Wait for all devices to acknowledge initialization
-        while let Ok(notification) = eventloop.poll().await {
-            let mut end_loop = true;
-
-            handle_event(notification, device_to_init).await;
-
-            for dev in device_to_init {
-                let borr = dev.as_ref().borrow();
-                let dd = borr.deref();
-                info!(
-                    "Devices before check : topic=[{}], init =[{}]",
-                    dd.get_topic(),
-                    dd.is_init()
-                );
-                if !dd.is_init() {
-                    end_loop = false;
+        // Wait for all devices to acknowledge initialization, or to run out of time
+        let mut connected = true;
+        let mut backoff = reconnect.min_backoff;
+        while !pending.is_empty() {
+            // No deadline while the broker is away, the devices cannot answer
+            let next_deadline = pending.values().filter_map(|p| p.deadline).min().filter(|_| connected);
+            let polled = match next_deadline {
+                Some(deadline) => timeout_at(deadline, events.poll()).await,
+                None => Ok(events.poll().await),
+            };
+            match polled {
+                Ok(Ok(TransportEvent::Connected)) if !connected => {
+                    info!("🔌 Connection to the broker is back, ask the [{}] pending device(s) again", pending.len());
+                    connected = true;
+                    backoff = reconnect.min_backoff;
+                    set_connected(true);
+                    reconnect.resubscribe(client).await;
+                    for p in pending.values_mut() {
+                        request_info(client, events, &p.device, device_to_init).await?;
+                        p.deadline = Some(Instant::now() + p.device.init_settings.timeout());
+                    }
+                }
+                Ok(Ok(event)) => {
+                    handle_event(event, device_to_init).await;
+                }
+                Ok(Err(e)) => {
+                    if connected {
+                        warn!("🔌 Connection to the broker lost during the initialization, e=[{}]", e);
+                        connected = false;
+                        set_connected(false);
+                    } else {
+                        error!("💀 Cannot reconnect to the broker, e=[{}]", e);
+                    }
+                    tokio::time::sleep(backoff).await;
+                    backoff = reconnect.next_backoff(backoff);
+                    continue;
+                }
+                Err(_) => {
+                    // The deadline is reached, it's handled below
+                }
+            }
+
+            let answered: Vec<String> = pending.iter()
+                .filter(|(_, p)| p.device.is_init())
+                .map(|(topic, _)| topic.clone())
+                .collect();
+            for topic in answered {
+                if let Some(p) = pending.remove(&topic) {
+                    info!("Device [{}] initialized", &topic);
+                    summary.outcomes.push(DeviceInitOutcome { topic, status: InitStatus::Ready, requests: p.requests, elapsed: start.elapsed() });
                 }
             }
 
-            if end_loop {
-                break;
+            if !connected {
+                continue;
+            }
+            let now = Instant::now();
+            let expired: Vec<String> = pending.iter()
+                .filter(|(_, p)| p.deadline.is_some_and(|deadline| deadline <= now))
+                .map(|(topic, _)| topic.clone())
+                .collect();
+            for topic in expired {
+                let Some(mut p) = pending.remove(&topic) else { continue };
+                if p.requests <= p.device.init_settings.retries {
+                    info!("🔁 No answer from device [{}], request [{}]", &topic, p.requests + 1);
+                    request_info(client, events, &p.device, device_to_init).await?;
+                    p.requests += 1;
+                    p.deadline = Some(Instant::now() + p.device.init_settings.timeout());
+                    pending.insert(topic, p);
+                } else {
+                    match give_up(&p.device).await {
+                        Some(status) => summary.outcomes.push(DeviceInitOutcome { topic, status, requests: p.requests, elapsed: start.elapsed() }),
+                        None => {
+                            p.deadline = None;
+                            pending.insert(topic, p);
+                        }
+                    }
+                }
             }
         }
     } else {
         info!("No devices to initialize");
     }
 
-    info!("Initialization stage finished");
+    info!("Initialization stage finished, {}", &summary);
 
-    Ok(())
+    if summary.is_success() {
+        Ok(summary)
+    } else {
+        Err(format!("Initialization failed, {}", &summary))
+    }
 }
 
 
-pub async fn handle_event<T>(event: Event, device_to_init: &Vec<Arc<RefCell<GenericDevice<T>>>>) 
+pub async fn handle_event<T>(event: TransportEvent, device_to_init: &[SharedDevice<T>]) 
 where T : Locality  + DeserializeOwned {
     info!("Message reçu = {:?}", &event);
     match event {
-        Event::Incoming(Incoming::Publish(publish)) => {
+        TransportEvent::Publish { topic, payload, .. } => {
             // Votre logique de traitement des messages ici
 
-            let msg = std::str::from_utf8(&publish.payload).unwrap();
-            let topic = std::str::from_utf8(publish.topic.as_ref()).unwrap(); // TODO
+            let msg = payload.as_str();
+            let topic = topic.as_str();
 
             info!( "Message reçu sur le topic {:?}: {:?}",topic  , msg);
             info!("Publish ({}): {}", topic, msg);
+            record(RecordedMessage { init: true, ..RecordedMessage::new(Direction::In, topic, msg) });
 
             // TODO is it necessary to loop over all the devices ?
-            for dev in device_to_init {
-                let mut borr = dev.as_ref().borrow_mut();
-                let dd = borr.deref_mut();
-                dd.init(topic, msg);
+            for dd in device_to_init {
+                dd.init(topic, msg).await;
             }
 
         }
-        Event::Incoming(Incoming::ConnAck(connack)) => {
-            // Accéder aux métadonnées de la réponse de connexion (Connack)
-            info!("ConnaAck ({:?})", &connack);
-            if let Some(properties) = connack.properties {
-                info!("Propriétés de la réponse de connexion: {:?}", properties);
-            }
-        }
-        Event::Incoming(Incoming::PubAck(pub_ack)) => {
-            info!("PubAck ({:?})", &pub_ack);
+        TransportEvent::Connected => {
+            info!("ConnaAck");
+            set_connected(true);
         }
         _ => {}
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::sync::Mutex;
+
+    use async_trait::async_trait;
+    use serde_json::json;
+
+    use crate::dynamic_message::DynamicMessage;
+    use crate::generic_device::GenericDevice;
+
+    use super::*;
+
+    #[derive(Default)]
+    struct FakeClient {
+        published: Mutex<Vec<String>>,
+        subscribed: Mutex<Vec<String>>,
+    }
+
+    #[async_trait]
+    impl TransportClient for FakeClient {
+        async fn publish(&self, topic: &str, _payload: Vec<u8>, _properties: PublishProperties) -> Result<(), String> {
+            self.published.lock().unwrap().push(topic.to_string());
+            Ok(())
+        }
+
+        async fn subscribe(&self, filter: &str) -> Result<(), String> {
+            self.subscribed.lock().unwrap().push(filter.to_string());
+            Ok(())
+        }
+
+        async fn unsubscribe(&self, _filter: &str) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn disconnect(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    /// The events come after their delay, nothing comes once the script is over
+    struct FakeEvents {
+        script: VecDeque<(Duration, Result<TransportEvent, String>)>,
+    }
+
+    #[async_trait]
+    impl TransportEvents for FakeEvents {
+        async fn poll(&mut self) -> Result<TransportEvent, String> {
+            let Some((delay, _)) = self.script.front() else {
+                return std::future::pending().await;
+            };
+            tokio::time::sleep(*delay).await;
+            self.script.pop_front().map(|(_, event)| event).unwrap_or_else(|| Err("Empty script".to_string()))
+        }
+    }
+
+    fn answer(delay_ms: u64) -> (Duration, Result<TransportEvent, String>) {
+        let event = TransportEvent::Publish { topic: "zigbee2mqtt/lamp".to_string(), payload: r#"{"state":"ON"}"#.to_string(), properties: None };
+        (Duration::from_millis(delay_ms), Ok(event))
+    }
+
+    fn lamp(settings: InitSettings) -> SharedDevice<DynamicMessage> {
+        let mut device = GenericDevice::new("zigbee2mqtt", "lamp", DynamicMessage::new(json!({"state": "OFF"})), false);
+        device.init_settings = settings;
+        device.shared()
+    }
+
+    fn reconnect() -> Reconnect<DynamicMessage> {
+        Reconnect::new(&["zigbee2mqtt/lamp".to_string()])
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(10))
+    }
+
+    fn settings(policy: InitPolicy) -> InitSettings {
+        InitSettings { timeout_ms: 50, retries: 1, policy }
+    }
+
+    #[test]
+    fn waits_by_default() {
+        assert_eq!(InitPolicy::Wait, InitSettings::default().policy);
+        let settings: InitSettings = serde_json::from_value(json!({"timeout_ms": 500})).unwrap();
+        assert_eq!(InitSettings { timeout_ms: 500, retries: 2, policy: InitPolicy::Wait }, settings);
+    }
+
+    #[tokio::test]
+    async fn answer_within_the_timeout() {
+        let client = FakeClient::default();
+        let mut events = FakeEvents { script: VecDeque::from([answer(20)]) };
+        let device = lamp(settings(InitPolicy::Fail));
+
+        let summary = process_initialization_message(&client, &mut events, std::slice::from_ref(&device), &reconnect()).await.unwrap();
+        assert_eq!(1, summary.count(InitStatus::Ready));
+        assert_eq!(vec!["zigbee2mqtt/lamp/get"], *client.published.lock().unwrap());
+        assert_eq!(Some(json!({"state": "ON"})), device.last_state());
+    }
+
+    #[tokio::test]
+    async fn wait_policy_keeps_waiting_after_the_retries() {
+        let client = FakeClient::default();
+        let mut events = FakeEvents { script: VecDeque::from([answer(300)]) };
+
+        let summary = process_initialization_message(&client, &mut events, &[lamp(settings(InitPolicy::Wait))], &reconnect()).await.unwrap();
+        assert_eq!(1, summary.count(InitStatus::Ready));
+        assert_eq!(2, summary.outcomes[0].requests);
+        assert_eq!(2, client.published.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn fail_policy_stops_the_stage() {
+        let client = FakeClient::default();
+        let mut events = FakeEvents { script: VecDeque::new() };
+
+        let e = process_initialization_message(&client, &mut events, &[lamp(settings(InitPolicy::Fail))], &reconnect()).await.unwrap_err();
+        assert!(e.starts_with("Initialization failed, ready=[0], defaulted=[0], unknown=[0], failed=[1]"), "{}", e);
+        assert_eq!(2, client.published.lock().unwrap().len());
+    }
+
+    #[tokio::test]
+    async fn use_default_policy_starts_from_the_factory_message() {
+        let client = FakeClient::default();
+        let mut events = FakeEvents { script: VecDeque::new() };
+        let device = lamp(settings(InitPolicy::UseDefault));
+
+        let summary = process_initialization_message(&client, &mut events, std::slice::from_ref(&device), &reconnect()).await.unwrap();
+        assert_eq!(1, summary.count(InitStatus::Defaulted));
+        assert_eq!(Some(json!({"state": "OFF"})), device.last_state());
+    }
+
+    #[tokio::test]
+    async fn connection_error_asks_the_pending_devices_again() {
+        let client = FakeClient::default();
+        let mut events = FakeEvents {
+            script: VecDeque::from([
+                (Duration::from_millis(10), Err("Connection refused".to_string())),
+                // Longer than the timeout and the retries, the deadlines wait for the broker
+                (Duration::from_millis(500), Err("Connection refused".to_string())),
+                (Duration::ZERO, Ok(TransportEvent::Connected)),
+                answer(20),
+            ]),
+        };
+
+        let summary = process_initialization_message(&client, &mut events, &[lamp(settings(InitPolicy::Fail))], &reconnect()).await.unwrap();
+        assert_eq!(1, summary.count(InitStatus::Ready));
+        // The first request, then the one after the reconnection
+        assert_eq!(vec!["zigbee2mqtt/lamp/get", "zigbee2mqtt/lamp/get"], *client.published.lock().unwrap());
+        assert_eq!(vec!["zigbee2mqtt/lamp"], *client.subscribed.lock().unwrap());
+    }
+}
@@ -0,0 +1,71 @@
+use chrono::{DateTime, Utc};
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::{json, Map, Value};
+
+/// Raw trace of a message received on a subscribed topic.
+/// It is handed to `Locality::journal` for the devices flagged with `journal` in the module config.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct JournalEntry {
+    pub topic: String,
+    pub family: String,
+    pub device_name: String,
+    pub payload: Value,
+    pub received_at: DateTime<Utc>,
+    pub properties: Value,
+}
+
+impl JournalEntry {
+    pub fn new(topic: &str, family: &str, device_name: &str, raw_payload: &str, o_properties: Option<&PublishProperties>) -> Self {
+        Self {
+            topic: topic.to_string(),
+            family: family.to_string(),
+            device_name: device_name.to_string(),
+            payload: payload_to_json(raw_payload),
+            received_at: Utc::now(),
+            properties: properties_to_json(o_properties),
+        }
+    }
+}
+
+/// The payload is stored as is when it's a valid json, otherwise as a json string
+fn payload_to_json(raw_payload: &str) -> Value {
+    serde_json::from_str(raw_payload).unwrap_or_else(|_| Value::String(raw_payload.to_string()))
+}
+
+/// Flatten the MQTT v5 properties, only the ones that are set
+fn properties_to_json(o_properties: Option<&PublishProperties>) -> Value {
+    let mut map = Map::new();
+    if let Some(properties) = o_properties {
+        if let Some(v) = properties.payload_format_indicator {
+            map.insert("payload_format_indicator".to_string(), json!(v));
+        }
+        if let Some(v) = properties.message_expiry_interval {
+            map.insert("message_expiry_interval".to_string(), json!(v));
+        }
+        if let Some(v) = properties.topic_alias {
+            map.insert("topic_alias".to_string(), json!(v));
+        }
+        if let Some(v) = &properties.response_topic {
+            map.insert("response_topic".to_string(), json!(v));
+        }
+        if let Some(v) = &properties.correlation_data {
+            map.insert("correlation_data".to_string(), json!(String::from_utf8_lossy(v)));
+        }
+        if !properties.user_properties.is_empty() {
+            let user_properties: Map<String, Value> = properties
+                .user_properties
+                .iter()
+                .map(|(k, v)| (k.clone(), json!(v)))
+                .collect();
+            map.insert("user_properties".to_string(), Value::Object(user_properties));
+        }
+        if !properties.subscription_identifiers.is_empty() {
+            map.insert("subscription_identifiers".to_string(), json!(properties.subscription_identifiers));
+        }
+        if let Some(v) = &properties.content_type {
+            map.insert("content_type".to_string(), json!(v));
+        }
+    }
+    Value::Object(map)
+}
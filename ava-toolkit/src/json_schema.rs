@@ -0,0 +1,237 @@
+//! The part of JSON Schema used by the message schemas of the factory folder :
+//! `type`, `enum`, `const`, `properties`, `required`, `additionalProperties` (boolean),
+//! `items`, `minimum`, `maximum`, `minLength`, `maxLength`.
+//! The other keywords are ignored.
+
+use serde_json::Value;
+
+#[derive(Debug, Clone)]
+pub struct JsonSchema {
+    schema: Value,
+}
+
+fn type_matches(type_name: &str, value: &Value) -> bool {
+    match type_name {
+        "object" => value.is_object(),
+        "array" => value.is_array(),
+        "string" => value.is_string(),
+        "number" => value.is_number(),
+        "integer" => value.is_i64() || value.is_u64() || value.as_f64().is_some_and(|f| f.fract() == 0.0),
+        "boolean" => value.is_boolean(),
+        "null" => value.is_null(),
+        _ => false,
+    }
+}
+
+fn field_path(path: &str, key: &str) -> String {
+    if path.is_empty() {
+        key.to_string()
+    } else {
+        format!("{}.{}", path, key)
+    }
+}
+
+fn validate_node(schema: &Value, value: &Value, path: &str) -> Result<(), String> {
+    let Some(schema) = schema.as_object() else {
+        // `true` or `{}` accept everything, `false` nothing
+        return match schema.as_bool() {
+            Some(false) => Err(format!("[{}] is not allowed", path)),
+            _ => Ok(()),
+        };
+    };
+
+    if let Some(types) = schema.get("type") {
+        let allowed: Vec<&str> = match types {
+            Value::String(t) => vec![t.as_str()],
+            Value::Array(ts) => ts.iter().filter_map(|t| t.as_str()).collect(),
+            _ => vec![],
+        };
+        if !allowed.is_empty() && !allowed.iter().any(|t| type_matches(t, value)) {
+            return Err(format!("[{}] must be of type {:?}, found [{}]", path, allowed, value));
+        }
+    }
+
+    if let Some(Value::Array(values)) = schema.get("enum") {
+        if !values.contains(value) {
+            return Err(format!("[{}] must be one of {}, found [{}]", path, Value::Array(values.clone()), value));
+        }
+    }
+    if let Some(expected) = schema.get("const") {
+        if expected != value {
+            return Err(format!("[{}] must be [{}], found [{}]", path, expected, value));
+        }
+    }
+
+    if let Some(number) = value.as_f64() {
+        if let Some(min) = schema.get("minimum").and_then(Value::as_f64) {
+            if number < min {
+                return Err(format!("[{}] must be at least [{}], found [{}]", path, min, number));
+            }
+        }
+        if let Some(max) = schema.get("maximum").and_then(Value::as_f64) {
+            if number > max {
+                return Err(format!("[{}] must be at most [{}], found [{}]", path, max, number));
+            }
+        }
+    }
+
+    if let Some(text) = value.as_str() {
+        let length = text.chars().count() as u64;
+        if schema.get("minLength").and_then(Value::as_u64).is_some_and(|min| length < min) {
+            return Err(format!("[{}] is too short", path));
+        }
+        if schema.get("maxLength").and_then(Value::as_u64).is_some_and(|max| length > max) {
+            return Err(format!("[{}] is too long", path));
+        }
+    }
+
+    if let Some(object) = value.as_object() {
+        if let Some(Value::Array(required)) = schema.get("required") {
+            for key in required.iter().filter_map(|k| k.as_str()) {
+                if !object.contains_key(key) {
+                    return Err(format!("[{}] is missing", field_path(path, key)));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        let closed = schema.get("additionalProperties").and_then(Value::as_bool) == Some(false);
+        for (key, field) in object {
+            match properties.and_then(|p| p.get(key)) {
+                Some(sub_schema) => validate_node(sub_schema, field, &field_path(path, key))?,
+                None if closed => return Err(format!("[{}] is not allowed", field_path(path, key))),
+                None => {}
+            }
+        }
+    }
+
+    if let (Some(items), Some(array)) = (schema.get("items"), value.as_array()) {
+        for (index, item) in array.iter().enumerate() {
+            validate_node(items, item, &format!("{}[{}]", path, index))?;
+        }
+    }
+
+    Ok(())
+}
+
+impl JsonSchema {
+    pub fn new(schema: Value) -> Self {
+        Self { schema }
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), String> {
+        validate_node(&self.schema, value, "")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn validate(schema: Value, value: Value) -> Result<(), String> {
+        JsonSchema::new(schema).validate(&value)
+    }
+
+    #[test]
+    fn type_single_or_list() {
+        assert!(validate(json!({"type": "object"}), json!({})).is_ok());
+        assert!(validate(json!({"type": "integer"}), json!(3)).is_ok());
+        assert!(validate(json!({"type": "integer"}), json!(3.0)).is_ok());
+        assert!(validate(json!({"type": "number"}), json!(3.5)).is_ok());
+        assert!(validate(json!({"type": ["string", "null"]}), json!(null)).is_ok());
+        assert_eq!(
+            Err(r#"[] must be of type ["integer"], found [3.5]"#.to_string()),
+            validate(json!({"type": "integer"}), json!(3.5))
+        );
+        assert_eq!(
+            Err(r#"[] must be of type ["string", "null"], found [true]"#.to_string()),
+            validate(json!({"type": ["string", "null"]}), json!(true))
+        );
+    }
+
+    #[test]
+    fn required_fields() {
+        let schema = json!({"type": "object", "required": ["state", "brightness"]});
+        assert!(validate(schema.clone(), json!({"state": "ON", "brightness": 10, "linkquality": 80})).is_ok());
+        assert_eq!(Err("[brightness] is missing".to_string()), validate(schema, json!({"state": "ON"})));
+    }
+
+    #[test]
+    fn enum_and_const() {
+        assert!(validate(json!({"enum": ["ON", "OFF"]}), json!("ON")).is_ok());
+        assert_eq!(
+            Err(r#"[] must be one of ["ON","OFF"], found ["on"]"#.to_string()),
+            validate(json!({"enum": ["ON", "OFF"]}), json!("on"))
+        );
+        assert!(validate(json!({"const": 1}), json!(1)).is_ok());
+        assert_eq!(Err("[] must be [1], found [2]".to_string()), validate(json!({"const": 1}), json!(2)));
+    }
+
+    #[test]
+    fn bounds_of_numbers_and_strings() {
+        let level = json!({"minimum": 0, "maximum": 254});
+        assert!(validate(level.clone(), json!(254)).is_ok());
+        assert_eq!(Err("[] must be at least [0], found [-1]".to_string()), validate(level.clone(), json!(-1)));
+        assert_eq!(Err("[] must be at most [254], found [255]".to_string()), validate(level, json!(255)));
+
+        let name = json!({"minLength": 2, "maxLength": 3});
+        assert!(validate(name.clone(), json!("été")).is_ok());
+        assert_eq!(Err("[] is too short".to_string()), validate(name.clone(), json!("a")));
+        assert_eq!(Err("[] is too long".to_string()), validate(name, json!("abcd")));
+    }
+
+    #[test]
+    fn nested_properties_tell_the_path() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "color": {
+                    "type": "object",
+                    "required": ["x"],
+                    "properties": {"x": {"type": "number", "maximum": 1}},
+                },
+                "scenes": {"type": "array", "items": {"type": "object", "properties": {"id": {"type": "integer"}}}},
+            },
+        });
+        assert!(validate(schema.clone(), json!({"color": {"x": 0.3, "y": 0.2}, "scenes": [{"id": 1}]})).is_ok());
+        assert_eq!(Err("[color.x] must be at most [1], found [1.5]".to_string()), validate(schema.clone(), json!({"color": {"x": 1.5}})));
+        assert_eq!(Err("[color.x] is missing".to_string()), validate(schema.clone(), json!({"color": {"y": 0.2}})));
+        assert_eq!(
+            Err(r#"[scenes[1].id] must be of type ["integer"], found ["2"]"#.to_string()),
+            validate(schema, json!({"scenes": [{"id": 1}, {"id": "2"}]}))
+        );
+    }
+
+    #[test]
+    fn additional_properties() {
+        let schema = json!({"properties": {"state": {"type": "string"}}, "additionalProperties": false});
+        assert!(validate(schema.clone(), json!({"state": "ON"})).is_ok());
+        assert_eq!(Err("[linkquality] is not allowed".to_string()), validate(schema, json!({"state": "ON", "linkquality": 80})));
+
+        // Open by default
+        assert!(validate(json!({"properties": {"state": {"type": "string"}}}), json!({"linkquality": 80})).is_ok());
+        // A schema as additionalProperties is not supported, the fields stay open
+        assert!(validate(json!({"additionalProperties": {"type": "string"}}), json!({"linkquality": 80})).is_ok());
+    }
+
+    #[test]
+    fn boolean_schemas() {
+        assert!(validate(json!(true), json!({"any": 1})).is_ok());
+        assert!(validate(json!({}), json!([1, "a"])).is_ok());
+        assert_eq!(Err("[state] is not allowed".to_string()), validate(json!({"properties": {"state": false}}), json!({"state": "ON"})));
+    }
+
+    #[test]
+    fn other_keywords_are_ignored() {
+        let schema = json!({
+            "$schema": "https://json-schema.org/draft/2020-12/schema",
+            "title": "Lamp",
+            "pattern": "^[0-9]+$",
+            "multipleOf": 5,
+            "oneOf": [{"type": "string"}],
+            "format": "date-time",
+        });
+        assert!(validate(schema, json!(7)).is_ok());
+    }
+}
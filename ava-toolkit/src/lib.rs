@@ -1,7 +1,36 @@
 pub mod device_lock;
 pub mod device_message;
+pub mod dynamic_message;
 pub mod generic_device;
 pub mod hard_loop;
 pub mod init_loop;
+pub mod journal;
+pub mod logging;
+pub mod loopback;
+pub mod loop_guard;
+pub mod json_schema;
+pub mod metrics;
+pub mod module_watcher;
 pub mod processing;
-pub mod domotic_factory;
\ No newline at end of file
+pub mod reconnect;
+pub mod replay;
+pub mod rules;
+pub mod schedule;
+pub mod service;
+pub mod throttle;
+pub mod topic;
+pub mod transport;
+pub mod config_check;
+pub mod correlation;
+pub mod dead_letter;
+pub mod domotic_factory;
+// The tests derive `Locality` inside this crate, the generated code names `::ava_toolkit`
+#[cfg(test)]
+extern crate self as ava_toolkit;
+
+/// Used by the code of `#[derive(Locality)]`
+#[doc(hidden)]
+pub mod __private {
+    pub use log;
+    pub use serde_json;
+}
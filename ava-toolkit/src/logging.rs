@@ -0,0 +1,26 @@
+//! The logger of the AVA binaries. The `log` lines go through `tracing`, so they carry the fields of the current span,
+//! like the correlation ID of the message being processed.
+//! RUST_LOG sets the filter (info by default), AVA_LOG_FORMAT=json writes one JSON object per line.
+
+use std::env;
+use std::io::IsTerminal;
+
+use tracing_subscriber::EnvFilter;
+
+pub const LOG_FORMAT_VAR: &str = "AVA_LOG_FORMAT";
+
+pub fn init_logging() {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"));
+    // On stderr, like env_logger before
+    let builder = tracing_subscriber::fmt()
+        .with_env_filter(filter)
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal());
+    let result = match env::var(LOG_FORMAT_VAR).as_deref() {
+        Ok("json") => builder.json().flatten_event(true).try_init(),
+        _ => builder.try_init(),
+    };
+    if let Err(e) = result {
+        eprintln!("💀 Cannot start the logger, e=[{}]", e);
+    }
+}
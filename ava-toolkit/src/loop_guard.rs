@@ -0,0 +1,317 @@
+//! Guards of a loop, written in the module file. A loop without guard is always active.
+//!
+//! ```json
+//! { "loop_name": "hall_night", "devices": ["hall_switch", "hall_lamp"],
+//!   "time_windows": [{ "from": "20:00", "to": "07:00" }],
+//!   "days": ["mon", "tue", "wed", "thu", "fri"],
+//!   "conditions": ["lux_sensor.illuminance < 50"] }
+//! ```
+//!
+//! A window with `from` after `to` crosses midnight. The time and the days are the local ones.
+//! A condition reads the last known message of a device of the module, it's false while the field is unknown.
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, Weekday};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::generic_device::{Locality, SharedDevice};
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct TimeWindowDefinition {
+    pub from: String,   // "HH:MM"
+    pub to: String,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct GuardDefinition {
+    #[serde(default)]
+    pub time_windows: Vec<TimeWindowDefinition>,
+    #[serde(default)]
+    pub days: Vec<String>,
+    #[serde(default)]
+    pub conditions: Vec<String>,
+}
+
+impl GuardDefinition {
+    /// Check the syntax of the guards, return the devices read by the conditions
+    pub fn check(&self) -> Result<Vec<String>, String> {
+        for window in &self.time_windows {
+            parse_time(&window.from)?;
+            parse_time(&window.to)?;
+        }
+        for day in &self.days {
+            parse_day(day)?;
+        }
+        self.conditions
+            .iter()
+            .map(|text| StateCondition::parse(text).map(|c| c.device_name))
+            .collect()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Operator {
+    Lower,
+    LowerOrEqual,
+    Greater,
+    GreaterOrEqual,
+    Equal,
+    NotEqual,
+}
+
+// The two-char operators first, "<=" must not be read as "<"
+const OPERATORS: [(&str, Operator); 6] = [
+    ("<=", Operator::LowerOrEqual),
+    (">=", Operator::GreaterOrEqual),
+    ("==", Operator::Equal),
+    ("!=", Operator::NotEqual),
+    ("<", Operator::Lower),
+    (">", Operator::Greater),
+];
+
+/// `<device>.<field> <operator> <value>`, ex : `lux_sensor.illuminance < 50`
+#[derive(Debug, Clone, PartialEq)]
+pub struct StateCondition {
+    pub device_name: String,
+    path: Vec<String>,
+    operator: Operator,
+    value: Value,
+}
+
+impl StateCondition {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let (left, operator, right) = OPERATORS
+            .iter()
+            .find_map(|(symbol, operator)| text.split_once(symbol).map(|(l, r)| (l, *operator, r)))
+            .ok_or(format!("No operator in the condition [{}], expected <, <=, >, >=, == or !=", text))?;
+        let mut path = left.trim().split('.').map(|s| s.to_string());
+        let device_name = path.next().unwrap_or_default();
+        let path: Vec<String> = path.collect();
+        if device_name.is_empty() || path.is_empty() || path.iter().any(|p| p.is_empty()) {
+            return Err(format!("Expected <device>.<field> in the condition [{}]", text));
+        }
+        let right = right.trim();
+        let value = serde_json::from_str(right).unwrap_or_else(|_| Value::String(right.to_string()));
+        Ok(Self { device_name, path, operator, value })
+    }
+
+    pub fn is_true(&self, message: &Value) -> bool {
+        let Some(field) = self.path.iter().try_fold(message, |v, key| v.get(key)) else {
+            return false;
+        };
+        match self.operator {
+            Operator::Equal => field == &self.value,
+            Operator::NotEqual => field != &self.value,
+            _ => match (field.as_f64(), self.value.as_f64()) {
+                (Some(left), Some(right)) => match self.operator {
+                    Operator::Lower => left < right,
+                    Operator::LowerOrEqual => left <= right,
+                    Operator::Greater => left > right,
+                    _ => left >= right,
+                },
+                _ => false,
+            },
+        }
+    }
+}
+
+fn parse_time(text: &str) -> Result<NaiveTime, String> {
+    NaiveTime::parse_from_str(text.trim(), "%H:%M").map_err(|e| format!("Wrong time [{}], expected HH:MM, e=[{}]", text, e))
+}
+
+fn parse_day(text: &str) -> Result<Weekday, String> {
+    text.parse::<Weekday>().map_err(|_| format!("Wrong day [{}], expected mon, tue, ...", text))
+}
+
+/// The guards of a loop, compiled with the devices of the module
+#[derive(Debug, Clone)]
+pub struct LoopGuard<T: Locality> {
+    time_windows: Vec<(NaiveTime, NaiveTime)>,
+    days: Vec<Weekday>,
+    conditions: Vec<(StateCondition, SharedDevice<T>)>,
+}
+
+impl <T: Locality> Default for LoopGuard<T> {
+    fn default() -> Self {
+        Self {
+            time_windows: vec![],
+            days: vec![],
+            conditions: vec![],
+        }
+    }
+}
+
+impl <T> LoopGuard<T> where T: Locality + DeserializeOwned {
+    pub fn compile(definition: &GuardDefinition, devices: &HashMap<String, SharedDevice<T>>) -> Result<Self, String> {
+        let mut guard = Self::default();
+        for window in &definition.time_windows {
+            guard.time_windows.push((parse_time(&window.from)?, parse_time(&window.to)?));
+        }
+        for day in &definition.days {
+            guard.days.push(parse_day(day)?);
+        }
+        for text in &definition.conditions {
+            let condition = StateCondition::parse(text)?;
+            let device = devices
+                .get(&condition.device_name)
+                .ok_or(format!("Unknown device [{}] in the condition [{}]", &condition.device_name, text))?;
+            guard.conditions.push((condition, device.clone()));
+        }
+        Ok(guard)
+    }
+
+    pub fn is_active(&self, now: &DateTime<Local>) -> bool {
+        if !self.days.is_empty() && !self.days.contains(&now.weekday()) {
+            return false;
+        }
+        let time = now.time();
+        let in_window = |(from, to): &(NaiveTime, NaiveTime)| {
+            if from <= to {
+                *from <= time && time < *to
+            } else {
+                time >= *from || time < *to
+            }
+        };
+        if !self.time_windows.is_empty() && !self.time_windows.iter().any(in_window) {
+            return false;
+        }
+        self.conditions.iter().all(|(condition, device)| {
+            device.last_state().is_some_and(|state| condition.is_true(&state))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use serde_json::json;
+
+    use crate::dynamic_message::DynamicMessage;
+    use crate::generic_device::GenericDevice;
+
+    use super::*;
+
+    /// 2026-10-19 is a monday
+    fn local(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(2026, 10, day, hour, minute, 0).unwrap()
+    }
+
+    fn guard(definition: Value, devices: &HashMap<String, SharedDevice<DynamicMessage>>) -> LoopGuard<DynamicMessage> {
+        let definition: GuardDefinition = serde_json::from_value(definition).unwrap();
+        LoopGuard::compile(&definition, devices).unwrap()
+    }
+
+    fn lux_sensor() -> HashMap<String, SharedDevice<DynamicMessage>> {
+        let device = GenericDevice::new("zigbee2mqtt", "lux_sensor", DynamicMessage::new(json!({})), false).shared();
+        HashMap::from([("lux_sensor".to_string(), device)])
+    }
+
+    #[test]
+    fn no_guard_is_always_active() {
+        assert!(guard(json!({}), &HashMap::new()).is_active(&local(19, 12, 0)));
+    }
+
+    #[test]
+    fn time_window_within_the_day() {
+        let g = guard(json!({"time_windows": [{"from": "08:00", "to": "12:30"}]}), &HashMap::new());
+        assert!(!g.is_active(&local(19, 7, 59)));
+        assert!(g.is_active(&local(19, 8, 0)));
+        assert!(g.is_active(&local(19, 12, 29)));
+        assert!(!g.is_active(&local(19, 12, 30)));
+    }
+
+    #[test]
+    fn time_window_crossing_midnight() {
+        let g = guard(json!({"time_windows": [{"from": "20:00", "to": "07:00"}]}), &HashMap::new());
+        assert!(g.is_active(&local(19, 20, 0)));
+        assert!(g.is_active(&local(19, 23, 59)));
+        assert!(g.is_active(&local(20, 0, 0)));
+        assert!(g.is_active(&local(20, 6, 59)));
+        assert!(!g.is_active(&local(20, 7, 0)));
+        assert!(!g.is_active(&local(19, 19, 59)));
+    }
+
+    #[test]
+    fn any_window_will_do() {
+        let g = guard(json!({"time_windows": [{"from": "06:00", "to": "08:00"}, {"from": "22:00", "to": "01:00"}]}), &HashMap::new());
+        assert!(g.is_active(&local(19, 7, 0)));
+        assert!(g.is_active(&local(20, 0, 30)));
+        assert!(!g.is_active(&local(19, 12, 0)));
+    }
+
+    #[test]
+    fn days_filter() {
+        let g = guard(json!({"days": ["mon", "wed"]}), &HashMap::new());
+        assert!(g.is_active(&local(19, 12, 0)));
+        assert!(!g.is_active(&local(20, 12, 0)));
+        assert!(g.is_active(&local(21, 12, 0)));
+        assert!(!g.is_active(&local(25, 12, 0)));
+    }
+
+    #[test]
+    fn a_window_crossing_midnight_ends_with_its_day() {
+        // The day is the one of the current time, tuesday morning is out
+        let g = guard(json!({"days": ["mon"], "time_windows": [{"from": "20:00", "to": "07:00"}]}), &HashMap::new());
+        assert!(g.is_active(&local(19, 23, 0)));
+        assert!(!g.is_active(&local(20, 1, 0)));
+    }
+
+    #[tokio::test]
+    async fn state_condition_reads_the_last_message() {
+        let devices = lux_sensor();
+        let g = guard(json!({"conditions": ["lux_sensor.illuminance < 50"]}), &devices);
+        let now = local(19, 12, 0);
+        // Unknown state
+        assert!(!g.is_active(&now));
+
+        let device = &devices["lux_sensor"];
+        device.init("zigbee2mqtt/lux_sensor", r#"{"illuminance": 20}"#).await;
+        assert!(g.is_active(&now));
+        device.init("zigbee2mqtt/lux_sensor", r#"{"illuminance": 50}"#).await;
+        assert!(!g.is_active(&now));
+        // Missing field
+        device.init("zigbee2mqtt/lux_sensor", r#"{"linkquality": 80}"#).await;
+        assert!(!g.is_active(&now));
+    }
+
+    #[tokio::test]
+    async fn all_guards_must_hold() {
+        let devices = lux_sensor();
+        let g = guard(json!({"days": ["mon"], "time_windows": [{"from": "20:00", "to": "07:00"}], "conditions": ["lux_sensor.illuminance <= 50"]}), &devices);
+        devices["lux_sensor"].init("zigbee2mqtt/lux_sensor", r#"{"illuminance": 50}"#).await;
+        assert!(g.is_active(&local(19, 21, 0)));
+        assert!(!g.is_active(&local(19, 19, 0)));
+        assert!(!g.is_active(&local(21, 21, 0)));
+        devices["lux_sensor"].init("zigbee2mqtt/lux_sensor", r#"{"illuminance": 51}"#).await;
+        assert!(!g.is_active(&local(19, 21, 0)));
+    }
+
+    #[test]
+    fn conditions_compare_numbers_and_values() {
+        let message = json!({"illuminance": 30, "state": "ON", "color": {"x": 0.5}});
+        let is_true = |text: &str| StateCondition::parse(text).unwrap().is_true(&message);
+        assert!(is_true("lux.illuminance >= 30"));
+        assert!(!is_true("lux.illuminance > 30"));
+        assert!(is_true("lux.state == ON"));
+        assert!(is_true("lux.state == \"ON\""));
+        assert!(is_true("lux.state != OFF"));
+        assert!(is_true("lux.color.x < 0.6"));
+        // Not a number
+        assert!(!is_true("lux.state > 3"));
+    }
+
+    #[test]
+    fn wrong_guards_tell_why() {
+        let check = |definition: Value| serde_json::from_value::<GuardDefinition>(definition).unwrap().check().unwrap_err();
+        assert!(check(json!({"time_windows": [{"from": "8h", "to": "12:00"}]})).starts_with("Wrong time [8h]"));
+        assert_eq!("Wrong day [monday!], expected mon, tue, ...", check(json!({"days": ["monday!"]})));
+        assert_eq!("Expected <device>.<field> in the condition [lux < 3]", check(json!({"conditions": ["lux < 3"]})));
+        assert!(check(json!({"conditions": ["lux.illuminance ~ 3"]})).starts_with("No operator"));
+
+        let e = LoopGuard::compile(&serde_json::from_value(json!({"conditions": ["lamp.state == ON"]})).unwrap(), &lux_sensor()).unwrap_err();
+        assert_eq!("Unknown device [lamp] in the condition [lamp.state == ON]", e);
+    }
+}
@@ -0,0 +1,136 @@
+//! In-process broker, no network : a message published by a connection goes to every connection
+//! subscribed to its topic, the publisher included. There is no retained message and no session.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+
+use async_trait::async_trait;
+use log::info;
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::topic::topic_matches;
+use crate::transport::{SharedClient, TransportClient, TransportEvent, TransportEvents};
+
+struct Connection {
+    id: u64,
+    filters: Vec<String>,
+    sender: UnboundedSender<TransportEvent>,
+}
+
+/// The broker, its clones share the connections
+#[derive(Clone, Default)]
+pub struct Loopback {
+    connections: Arc<Mutex<Vec<Connection>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Loopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A new connection, its first event is `Connected`
+    pub fn connect(&self) -> (SharedClient, LoopbackEvents) {
+        let id = self.next_id.fetch_add(1, Ordering::SeqCst);
+        let (sender, receiver) = unbounded_channel();
+        let _ = sender.send(TransportEvent::Connected);
+        self.lock().push(Connection { id, filters: vec![], sender });
+        info!("🔁 Loopback connection [{}]", id);
+        (Arc::new(LoopbackClient { id, broker: self.clone() }), LoopbackEvents { receiver })
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Vec<Connection>> {
+        self.connections.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// Run `f` on the connection, false if it's closed
+    fn with_connection(&self, id: u64, f: impl FnOnce(&mut Connection)) -> bool {
+        match self.lock().iter_mut().find(|c| c.id == id) {
+            Some(connection) => {
+                f(connection);
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+pub struct LoopbackClient {
+    id: u64,
+    broker: Loopback,
+}
+
+impl LoopbackClient {
+    fn closed(&self) -> String {
+        format!("The loopback connection [{}] is closed", self.id)
+    }
+}
+
+#[async_trait]
+impl TransportClient for LoopbackClient {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, properties: PublishProperties) -> Result<(), String> {
+        let mut connections = self.broker.lock();
+        if !connections.iter().any(|c| c.id == self.id) {
+            return Err(self.closed());
+        }
+        let payload = String::from_utf8_lossy(&payload);
+        // A connection whose events are dropped is gone
+        connections.retain(|c| {
+            if !c.filters.iter().any(|filter| topic_matches(filter, topic)) {
+                return true;
+            }
+            c.sender.send(TransportEvent::Publish {
+                topic: topic.to_string(),
+                payload: payload.to_string(),
+                properties: Some(properties.clone()),
+            }).is_ok()
+        });
+        if let Some(publisher) = connections.iter().find(|c| c.id == self.id) {
+            let _ = publisher.sender.send(TransportEvent::Acknowledged);
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&self, filter: &str) -> Result<(), String> {
+        let subscribed = self.broker.with_connection(self.id, |c| {
+            if !c.filters.iter().any(|f| f == filter) {
+                c.filters.push(filter.to_string());
+            }
+        });
+        if subscribed { Ok(()) } else { Err(self.closed()) }
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), String> {
+        let unsubscribed = self.broker.with_connection(self.id, |c| c.filters.retain(|f| f != filter));
+        if unsubscribed { Ok(()) } else { Err(self.closed()) }
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        let mut connections = self.broker.lock();
+        let Some(position) = connections.iter().position(|c| c.id == self.id) else {
+            return Err(self.closed());
+        };
+        let connection = connections.remove(position);
+        let _ = connection.sender.send(TransportEvent::Disconnected);
+        Ok(())
+    }
+}
+
+pub struct LoopbackEvents {
+    receiver: UnboundedReceiver<TransportEvent>,
+}
+
+impl LoopbackEvents {
+    /// The next event if there is one already, without waiting
+    pub fn try_next(&mut self) -> Option<TransportEvent> {
+        self.receiver.try_recv().ok()
+    }
+}
+
+#[async_trait]
+impl TransportEvents for LoopbackEvents {
+    async fn poll(&mut self) -> Result<TransportEvent, String> {
+        self.receiver.recv().await.ok_or_else(|| "The loopback connection is closed".to_string())
+    }
+}
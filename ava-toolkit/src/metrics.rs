@@ -0,0 +1,135 @@
+//! Prometheus metrics of the AVA services.
+//! They live in the default registry of the `prometheus` crate, so a binary can register its own next to them.
+//! `start_metrics` serves them all on `GET /metrics` when the `metrics.port` property is set.
+
+use std::net::SocketAddr;
+use std::sync::LazyLock;
+use std::time::Duration;
+
+use axum::http::{header, StatusCode};
+use axum::response::IntoResponse;
+use axum::routing::get;
+use axum::Router;
+use common_config::properties::get_prop_value;
+use log::{error, info};
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, Encoder, HistogramVec, IntCounter,
+    IntCounterVec, TextEncoder,
+};
+
+struct Metrics {
+    messages: IntCounterVec,
+    parse_failures: IntCounterVec,
+    device_messages: IntCounterVec,
+    lock_waits: IntCounterVec,
+    commands: IntCounterVec,
+    publishes: IntCounterVec,
+    processing: HistogramVec,
+    reconnections: IntCounter,
+    heatzy_requests: HistogramVec,
+    heatzy_errors: IntCounterVec,
+}
+
+static METRICS: LazyLock<Metrics> = LazyLock::new(|| Metrics {
+    messages: register_int_counter_vec!("ava_messages_received_total", "Messages received from the broker", &["topic"])
+        .expect("Cannot register ava_messages_received_total"),
+    parse_failures: register_int_counter_vec!("ava_message_parse_failures_total", "Messages the device could not read", &["topic"])
+        .expect("Cannot register ava_message_parse_failures_total"),
+    device_messages: register_int_counter_vec!("ava_device_messages_total", "Messages of a device by outcome : processed, same, echo, throttled", &["device", "outcome"])
+        .expect("Cannot register ava_device_messages_total"),
+    lock_waits: register_int_counter_vec!("ava_device_lock_waits_total", "Times the lock of a device was already taken", &["device"])
+        .expect("Cannot register ava_device_lock_waits_total"),
+    commands: register_int_counter_vec!("ava_device_commands_total", "Messages computed for a device by the loops, by outcome : published, same", &["device", "outcome"])
+        .expect("Cannot register ava_device_commands_total"),
+    publishes: register_int_counter_vec!("ava_publishes_total", "Messages published to the broker, by outcome : ok, error", &["topic", "outcome"])
+        .expect("Cannot register ava_publishes_total"),
+    processing: register_histogram_vec!("ava_message_processing_seconds", "Time to process a message and run its loops", &["device"])
+        .expect("Cannot register ava_message_processing_seconds"),
+    reconnections: register_int_counter!("ava_mqtt_reconnections_total", "Times the connection to the broker came back")
+        .expect("Cannot register ava_mqtt_reconnections_total"),
+    heatzy_requests: register_histogram_vec!("ava_heatzy_request_seconds", "Latency of the Heatzy API calls", &["operation"])
+        .expect("Cannot register ava_heatzy_request_seconds"),
+    heatzy_errors: register_int_counter_vec!("ava_heatzy_errors_total", "Failed Heatzy API calls", &["operation"])
+        .expect("Cannot register ava_heatzy_errors_total"),
+});
+
+pub fn message_received(topic: &str) {
+    METRICS.messages.with_label_values(&[topic]).inc();
+}
+
+pub fn parse_failure(topic: &str) {
+    METRICS.parse_failures.with_label_values(&[topic]).inc();
+}
+
+pub fn device_message(device: &str, outcome: &str) {
+    METRICS.device_messages.with_label_values(&[device, outcome]).inc();
+}
+
+pub fn lock_wait(device: &str) {
+    METRICS.lock_waits.with_label_values(&[device]).inc();
+}
+
+pub fn command(device: &str, outcome: &str) {
+    METRICS.commands.with_label_values(&[device, outcome]).inc();
+}
+
+pub fn publish(topic: &str, ok: bool) {
+    METRICS.publishes.with_label_values(&[topic, if ok { "ok" } else { "error" }]).inc();
+}
+
+pub fn processing_time(device: &str, elapsed: Duration) {
+    METRICS.processing.with_label_values(&[device]).observe(elapsed.as_secs_f64());
+}
+
+pub fn reconnection() {
+    METRICS.reconnections.inc();
+}
+
+pub fn heatzy_request(operation: &str, elapsed: Duration, ok: bool) {
+    METRICS.heatzy_requests.with_label_values(&[operation]).observe(elapsed.as_secs_f64());
+    if !ok {
+        METRICS.heatzy_errors.with_label_values(&[operation]).inc();
+    }
+}
+
+/// All the metrics of the default registry, in the Prometheus text format
+pub fn render() -> Result<String, String> {
+    LazyLock::force(&METRICS);
+    let mut buffer = vec![];
+    TextEncoder::new()
+        .encode(&prometheus::gather(), &mut buffer)
+        .map_err(|e| format!("Cannot encode the metrics, e=[{}]", e))?;
+    String::from_utf8(buffer).map_err(|e| format!("Cannot encode the metrics, e=[{}]", e))
+}
+
+/// Handler of `GET /metrics`, for the services with their own HTTP server
+pub async fn metrics() -> impl IntoResponse {
+    match render() {
+        Ok(text) => (StatusCode::OK, [(header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], text).into_response(),
+        Err(e) => {
+            error!("💣 {}", e);
+            (StatusCode::INTERNAL_SERVER_ERROR, e).into_response()
+        }
+    }
+}
+
+/// Serve `GET /metrics` on the port of the `metrics.port` property, nothing if it's not set
+pub async fn start_metrics() -> Result<(), String> {
+    let port = match get_prop_value("metrics.port") {
+        Ok(port) => port.parse::<u16>().map_err(|e| format!("Wrong value for [metrics.port], e=[{}]", e))?,
+        Err(_) => return Ok(()),
+    };
+
+    let app = Router::new().route("/metrics", get(metrics));
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Cannot listen on [{}], e=[{}]", addr, e))?;
+    info!("📊 Metrics endpoint on http://{}/metrics", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("💀 Metrics endpoint stopped, e=[{}]", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,108 @@
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::{error, info};
+use serde::de::DeserializeOwned;
+use tokio::task::JoinHandle;
+
+use crate::domotic_factory::DomoticFactory;
+use crate::generic_device::Locality;
+use crate::hard_loop::HardLoop;
+use crate::reconnect::Reconnect;
+use crate::schedule::SharedSchedules;
+use crate::transport::SharedClient;
+
+/// The loops read by the processing, swapped by the watcher after a reload
+pub type SharedLoops<T> = Arc<RwLock<Vec<HardLoop<T>>>>;
+
+/// Rebuild the devices, loops and schedules when the module file or the factory folder changes.
+/// The files are polled, and read once they stayed the same for one period, so a file being written is not read.
+pub struct ModuleWatcher<T: Locality> {
+    factory: DomoticFactory<T>,
+    loops: SharedLoops<T>,
+    schedules: SharedSchedules,
+    reconnect: Reconnect<T>,
+    client: SharedClient,
+    mqtt_host: String,
+    period: Duration,
+}
+
+impl <T> ModuleWatcher<T> where T: Locality + DeserializeOwned {
+    pub fn new(factory: DomoticFactory<T>, loops: &SharedLoops<T>, schedules: &SharedSchedules, reconnect: &Reconnect<T>, client: &SharedClient, mqtt_host: &str, period: Duration) -> Self {
+        Self {
+            factory,
+            loops: loops.clone(),
+            schedules: schedules.clone(),
+            reconnect: reconnect.clone(),
+            client: client.clone(),
+            mqtt_host: mqtt_host.to_string(),
+            period,
+        }
+    }
+
+    pub fn spawn(mut self) -> JoinHandle<()> {
+        info!("👀 Watch the module files every [{:?}]", self.period);
+        tokio::spawn(async move {
+            let mut seen = self.factory.files_stamp();
+            let mut changed = false;
+            loop {
+                tokio::time::sleep(self.period).await;
+                let stamp = self.factory.files_stamp();
+                if stamp != seen {
+                    seen = stamp;
+                    changed = true;
+                    continue;
+                }
+                if changed {
+                    changed = false;
+                    self.reload().await;
+                }
+            }
+        })
+    }
+
+    async fn reload(&mut self) {
+        let summary = match self.factory.reload() {
+            Ok(summary) => summary,
+            Err(e) => {
+                error!("💣 The module is not reloaded, the current one stays, e=[{}]", e);
+                return;
+            }
+        };
+        info!("♻️ Module reloaded, {}", &summary);
+
+        let loops = self.factory.build_loops();
+        *self.loops.write().unwrap_or_else(|e| e.into_inner()) = loops;
+        let schedules = self.factory.build_schedules();
+        *self.schedules.write().unwrap_or_else(|e| e.into_inner()) = schedules;
+
+        // The new devices did not tell their state yet, the next command is always sent
+        let init_list = self.factory.devices_to_init();
+        for device in init_list.iter().filter(|d| summary.rebuilt.contains(&d.name)) {
+            device.mark_unknown().await;
+        }
+
+        let device_to_listen = self.factory.devices_to_listen();
+        let channels = DomoticFactory::extract_channel_from_devices(&device_to_listen, &self.mqtt_host);
+        self.update_subscriptions(&channels.channel_filters).await;
+        self.reconnect.update(&channels.channel_filters, &init_list);
+    }
+
+    /// Subscribe to the new channels and unsubscribe from the ones no longer used
+    async fn update_subscriptions(&self, channel_filters: &[String]) {
+        let current = self.reconnect.channel_filters();
+
+        for topic in current.iter().filter(|t| !channel_filters.contains(t)) {
+            info!("Unsubscribe from [{}]", topic);
+            if let Err(e) = self.client.unsubscribe(topic).await {
+                error!("💣 Cannot unsubscribe from [{}], e=[{}]", topic, e);
+            }
+        }
+        for topic in channel_filters.iter().filter(|t| !current.contains(t)) {
+            info!("Subscribe to [{}]", topic);
+            if let Err(e) = self.client.subscribe(topic).await {
+                error!("💣 Cannot subscribe to [{}], e=[{}]", topic, e);
+            }
+        }
+    }
+}
@@ -1,78 +1,386 @@
-use std::cell::RefCell;
-use std::ops::Deref;
-use std::str;
-use std::sync::Arc;
-use log::{info, error, debug};
-use rumqttc::v5::{AsyncClient, Event, EventLoop, Incoming};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+use log::{info, warn, error, debug};
+use rumqttc::v5::mqttbytes::v5::PublishProperties;
 use serde::de::DeserializeOwned;
-use crate::generic_device::{GenericDevice, Locality};
+use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use crate::correlation::{ensure_correlation_id, with_correlation_id};
+use crate::dead_letter::send_dead_letter;
+use crate::generic_device::{Locality, SharedDevice};
 use crate::hard_loop::HardLoop;
+use crate::init_loop::process_initialization_message;
+use crate::journal::JournalEntry;
+use crate::metrics;
+use crate::reconnect::{count_reconnection, set_connected, Reconnect};
+use crate::replay::{record, Direction, RecordedMessage};
+use crate::transport::{while_polling, SharedClient, TransportClient, TransportEvent, TransportEvents};
 
+/// A message received for a device, with the loops it belongs to
+struct DeviceJob<T: Locality> {
+    device: SharedDevice<T>,    // the current one, a reload of the module may replace the device of a topic
+    topic: String,
+    msg: String,
+    properties: Option<PublishProperties>,
+    correlation_id: String,
+    loops: Vec<HardLoop<T>>,
+}
+
+/// One task per emitting device.
+/// The messages of a device are processed in their arrival order, the devices of unrelated loops run concurrently.
+/// The clones share the tasks, so the scheduled messages of a device queue up with the ones of the broker.
+#[derive(Clone)]
+pub struct DeviceWorkers<T: Locality> {
+    client: SharedClient,
+    args: Arc<Vec<String>>,
+    senders: Arc<Mutex<HashMap<String, UnboundedSender<DeviceJob<T>>>>>,
+}
+
+impl <T> DeviceWorkers<T> where T: Locality + DeserializeOwned {
+    pub fn new(client: &SharedClient, args: &[String]) -> Self {
+        Self {
+            client: client.clone(),
+            args: Arc::new(args.to_vec()),
+            senders: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Queue a message that did not come from the broker, as if the device sent it on the topic
+    pub fn inject(&self, device: SharedDevice<T>, topic: &str, msg: &str, loops: Vec<HardLoop<T>>) {
+        let mut properties = None;
+        let correlation_id = ensure_correlation_id(&mut properties);
+        self.dispatch(DeviceJob {
+            device,
+            topic: topic.to_string(),
+            msg: msg.to_string(),
+            properties,
+            correlation_id,
+            loops,
+        });
+    }
+
+    /// Send the job to the task of the device, the task is started on the first message.
+    /// The queue is unbounded, the event loop must keep polling for the tasks to publish.
+    fn dispatch(&self, job: DeviceJob<T>) {
+        let device = job.device.clone();
+        let topic = device.get_topic();
+        let mut senders = self.senders.lock().unwrap_or_else(|e| e.into_inner());
+        let sender = senders.entry(topic.clone()).or_insert_with(|| {
+            info!("🧵 Start the processing task for device [{}]", &topic);
+            let (sender, mut receiver) = unbounded_channel::<DeviceJob<T>>();
+            let client = self.client.clone();
+            let args = self.args.clone();
+            tokio::spawn(async move {
+                while let Some(mut job) = receiver.recv().await {
+                    journal_message(&job).await;
+                    // Only the last message of a burst is processed, once the device stayed quiet for the delay
+                    // or once the burst lasted the max wait
+                    let burst_start = Instant::now();
+                    while let Some(wait) = job.device.throttle.debounce_wait(burst_start.elapsed()) {
+                        let Ok(Some(next)) = tokio::time::timeout(wait, receiver.recv()).await else {
+                            break;
+                        };
+                        debug!("Debounce device [{}], message dropped <{}>", &job.topic, &job.msg);
+                        journal_message(&next).await;
+                        job = next;
+                    }
+                    let device = job.device.clone();
+                    let correlation_id = job.correlation_id.clone();
+                    with_correlation_id(correlation_id, process_device_message(&device, job, &args, client.as_ref())).await;
+                }
+            });
+            sender
+        });
+        if sender.send(job).is_err() {
+            error!("💀 The processing task of device [{}] is gone", device.get_topic());
+        }
+    }
+}
+
+/// Process a message that did not come from the broker, as if the device sent it on the topic, right away.
+/// Only for the replay, the services go through `DeviceWorkers::inject` to keep the order of the device messages.
+pub(crate) async fn inject_message<T>(device: SharedDevice<T>, topic: &str, msg: &str, loops: Vec<HardLoop<T>>, args: &[String], client: &dyn TransportClient)
+where
+    T: Locality + DeserializeOwned,
+{
+    let mut properties = None;
+    let correlation_id = ensure_correlation_id(&mut properties);
+    let job = DeviceJob {
+        device: device.clone(),
+        topic: topic.to_string(),
+        msg: msg.to_string(),
+        properties,
+        correlation_id: correlation_id.clone(),
+        loops,
+    };
+    journal_message(&job).await;
+    with_correlation_id(correlation_id, process_device_message(&device, job, args, client)).await;
+}
+
+/// Every message is recorded, even the ones dropped by the debounce
+async fn journal_message<T: Locality>(job: &DeviceJob<T>) {
+    let device = &job.device;
+    if device.journal {
+        let entry = JournalEntry::new(&job.topic, &device.family, &device.name, &job.msg, job.properties.as_ref());
+        device.message_type.journal(&entry).await;
+    }
+}
+
+async fn process_device_message<T>(device: &SharedDevice<T>, job: DeviceJob<T>, args: &[String], client: &dyn TransportClient)
+where
+    T: Locality + DeserializeOwned,
+{
+    let started = Instant::now();
+    let original_message = match device.message_type.json_to_local(&job.msg) {
+        Ok(om) => om,
+        Err(e) => {
+            metrics::parse_failure(&job.topic);
+            error!(
+                "💀 Cannot parse the message locally for device {}, msg=<{}>, \n e={}",
+                &device.get_topic().to_uppercase(),
+                &job.msg,
+                e
+            );
+            send_dead_letter(client, &job.topic, &job.msg, &e).await;
+            return;
+        }
+    };
+
+    let o_ext_data = original_message.compute().await;
+
+    // Echo, throttle and same message are decided once, for all the loops of the device.
+    // The device processes its own message even when none of its loops is active.
+    if device.process_and_continue(&original_message, args).await {
+        for lp in job.loops {
+            lp.loop_devices(&job.topic, &original_message, o_ext_data.as_ref(), client).await;
+        }
+    }
+    metrics::processing_time(&device.name, started.elapsed());
+}
+
+/// Send a message from the broker to the task of its device
+fn dispatch_publish<T, F>(workers: &DeviceWorkers<T>, find_loop_fn: &F, topic: &str, msg: String, mut properties: Option<PublishProperties>)
+where
+    T: Locality + DeserializeOwned,
+    F: Fn(&str) -> (Vec<HardLoop<T>>, Option<SharedDevice<T>>),
+{
+    // A payload that is not UTF-8 fails the parsing of the device, and goes to the dead letters
+    info!("🧶 Publish on topic: [{}], message: <{}>", topic, msg);
+    metrics::message_received(topic);
+    record(RecordedMessage::new(Direction::In, topic, &msg));
+
+    let correlation_id = ensure_correlation_id(&mut properties);
+    debug!("Correlation ID [{}] for topic [{}]", &correlation_id, topic);
+
+    let (loops, opt_device) = find_loop_fn(topic);
+
+    match opt_device {
+        None => {
+            info!("No device to process the message");
+        }
+        Some(device) => {
+            let job = DeviceJob {
+                device,
+                topic: topic.to_string(),
+                msg,
+                properties,
+                correlation_id,
+                loops,
+            };
+            workers.dispatch(job);
+        }
+    }
+}
+
+/// Dispatch the incoming messages to the device tasks, forever.
+/// On a connection error, the event loop is polled again after a growing delay, rumqttc reconnects on the next poll.
+/// Once the broker is back, the channels are subscribed again and the init stage runs again if `reconnect` asks for it.
+/// The messages of the other devices received during this init stage are not processed.
 pub async fn process_incoming_message<T, F>(
-    mut client: &mut AsyncClient,
-    eventloop: &mut EventLoop,
+    client: &SharedClient,
+    events: &mut dyn TransportEvents,
     args: &[String],
     find_loop_fn: F,
+    reconnect: &Reconnect<T>,
 )
 where
     T: Locality + DeserializeOwned ,
-    F: Fn(&str) -> (Vec<HardLoop<T>>, Option<Arc<RefCell<GenericDevice<T>>>>),
+    F: Fn(&str) -> (Vec<HardLoop<T>>, Option<SharedDevice<T>>),
 {
-    info!("Process incoming message");
-
-    while let Ok(notification) = eventloop.poll().await {
-        match notification {
-            Event::Incoming(Incoming::Publish(publish)) => {
-                let msg = std::str::from_utf8(&publish.payload).unwrap();
-                let topic = std::str::from_utf8(publish.topic.as_ref()).unwrap();
+    // The tasks stop with the process
+    let workers = DeviceWorkers::new(client, args);
+    dispatch_incoming_message(&workers, events, find_loop_fn, reconnect).await;
+}
 
-                info!("🧶 Publish on topic: [{}], message: <{}>", topic, msg);
+/// Same as `process_incoming_message`, on workers shared with the scheduler
+pub async fn dispatch_incoming_message<T, F>(
+    workers: &DeviceWorkers<T>,
+    events: &mut dyn TransportEvents,
+    find_loop_fn: F,
+    reconnect: &Reconnect<T>,
+)
+where
+    T: Locality + DeserializeOwned ,
+    F: Fn(&str) -> (Vec<HardLoop<T>>, Option<SharedDevice<T>>),
+{
+    info!("Process incoming message");
 
-                let (loops, opt_device) = find_loop_fn(topic);
+    let client = &workers.client;
+    let mut connected = true;
+    let mut backoff = reconnect.min_backoff;
 
-                match opt_device {
-                    None => {
-                        info!("No device to process the message");
-                    }
-                    Some(dev) => {
-                        let device_ref = dev.as_ref().borrow();
-                        let device = device_ref.deref();
-
-                        let original_message = match device.message_type.json_to_local(msg) {
-                            Ok(om) => om,
-                            Err(e) => {
-                                error!(
-                                    "💀 Cannot parse the message locally for device {}, msg=<{}>, \n e={}",
-                                    &device.get_topic().to_uppercase(),
-                                    msg,
-                                    e
-                                );
-                                continue;
-                            }
-                        };
+    loop {
+        let event = match events.poll().await {
+            Ok(event) => event,
+            Err(e) => {
+                if connected {
+                    warn!("🔌 Connection to the broker lost, e=[{}]", e);
+                    connected = false;
+                    set_connected(false);
+                } else {
+                    error!("💀 Cannot reconnect to the broker, e=[{}]", e);
+                }
+                info!("Next connection attempt in [{:?}]", backoff);
+                tokio::time::sleep(backoff).await;
+                backoff = reconnect.next_backoff(backoff);
+                continue;
+            }
+        };
 
-                        let o_ext_data = original_message.compute().await;
-                        
-                        for lp in loops {
-                            if device.process_and_continue(&original_message, args).await {
-                                lp.loop_devices(&topic, &original_message, o_ext_data.as_ref(), &mut client).await;
-                            }
+        match event {
+            TransportEvent::Publish { topic, payload, properties } => {
+                dispatch_publish(workers, &find_loop_fn, &topic, payload, properties);
+            }
+            TransportEvent::Connected => {
+                info!("Réponse à la connection ack");
+                set_connected(true);
+                if !connected {
+                    connected = true;
+                    backoff = reconnect.min_backoff;
+                    let count = count_reconnection();
+                    info!("🔌 Connection to the broker is back, reconnection [{}]", count);
+                    // The messages received until the subscription is queued are processed as usual
+                    let resubscribe = reconnect.resubscribe(client.as_ref());
+                    while_polling(resubscribe, events, |event| {
+                        if let TransportEvent::Publish { topic, payload, properties } = event {
+                            dispatch_publish(workers, &find_loop_fn, &topic, payload, properties);
+                        }
+                        async {}
+                    }).await;
+                    let devices_to_init = reconnect.devices_to_init();
+                    if !devices_to_init.is_empty() {
+                        if let Err(e) = process_initialization_message(client.as_ref(), events, &devices_to_init, reconnect).await {
+                            error!("💀 The init stage failed after the reconnection, e=[{}]", e);
                         }
                     }
                 }
             }
-            Event::Incoming(Incoming::ConnAck(connack)) => {
-                // Accéder aux métadonnées de la réponse de connexion (Connack)
-                info!("Réponse à la connection ack");
-                debug!("ConnaAck ({:?})", &connack);
-            }
-            Event::Incoming(Incoming::PubAck(pub_ack)) => {
-                debug!("PubAck ({:?})", &pub_ack);
+            TransportEvent::Acknowledged => {
+                debug!("PubAck");
             }
-            _ => {
+            TransportEvent::Disconnected | TransportEvent::Other => {
                 debug!("Other cases!");
             }
         }
     }
 }
 
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    use async_trait::async_trait;
+    use serde_json::json;
+    use tokio::sync::mpsc;
+
+    use crate::dynamic_message::DynamicMessage;
+    use crate::generic_device::GenericDevice;
+
+    use super::*;
+
+    /// Like the rumqttc client, the requests wait in a bounded channel
+    struct BoundedClient {
+        requests: mpsc::Sender<String>,
+    }
+
+    #[async_trait]
+    impl TransportClient for BoundedClient {
+        async fn publish(&self, topic: &str, _payload: Vec<u8>, _properties: PublishProperties) -> Result<(), String> {
+            self.requests.send(format!("publish {}", topic)).await.map_err(|e| e.to_string())
+        }
+
+        async fn subscribe(&self, filter: &str) -> Result<(), String> {
+            self.requests.send(format!("subscribe {}", filter)).await.map_err(|e| e.to_string())
+        }
+
+        async fn unsubscribe(&self, _filter: &str) -> Result<(), String> {
+            Ok(())
+        }
+
+        async fn disconnect(&self) -> Result<(), String> {
+            Ok(())
+        }
+    }
+
+    /// Like the rumqttc event loop, a poll sends one waiting request, only while connected
+    struct BoundedEvents {
+        requests: mpsc::Receiver<String>,
+        sent: Arc<Mutex<Vec<String>>>,
+        connected: bool,
+        script: VecDeque<Result<TransportEvent, String>>,
+    }
+
+    #[async_trait]
+    impl TransportEvents for BoundedEvents {
+        async fn poll(&mut self) -> Result<TransportEvent, String> {
+            if self.connected {
+                if let Ok(request) = self.requests.try_recv() {
+                    self.sent.lock().unwrap().push(request);
+                    return Ok(TransportEvent::Other);
+                }
+            }
+            match self.script.pop_front() {
+                Some(event) => {
+                    self.connected = event.is_ok();
+                    event
+                }
+                None => std::future::pending().await,
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn reconnection_with_a_full_request_channel() {
+        let (sender, receiver) = mpsc::channel(1);
+        let client: SharedClient = Arc::new(BoundedClient { requests: sender });
+        // Queued while the broker is away, the channel is full
+        client.publish("zigbee2mqtt/lamp/set", vec![], PublishProperties::default()).await.unwrap();
+
+        let sent = Arc::new(Mutex::new(vec![]));
+        let answer = TransportEvent::Publish { topic: "zigbee2mqtt/lamp".to_string(), payload: r#"{"state":"ON"}"#.to_string(), properties: None };
+        let mut events = BoundedEvents {
+            requests: receiver,
+            sent: sent.clone(),
+            connected: false,
+            script: VecDeque::from([Err("Connection refused".to_string()), Ok(TransportEvent::Connected), Ok(answer)]),
+        };
+
+        let lamp = GenericDevice::new("zigbee2mqtt", "lamp", DynamicMessage::new(json!({"state": "OFF"})), false).shared();
+        let reconnect = Reconnect::new(&["zigbee2mqtt/lamp".to_string()])
+            .with_init(std::slice::from_ref(&lamp))
+            .with_backoff(Duration::from_millis(10), Duration::from_millis(10));
+        let workers = DeviceWorkers::new(&client, &[]);
+
+        let dispatch = dispatch_incoming_message(&workers, &mut events, |_| (vec![], None), &reconnect);
+        // The dispatch runs forever
+        assert!(tokio::time::timeout(Duration::from_secs(2), dispatch).await.is_err());
+
+        assert_eq!(
+            vec!["publish zigbee2mqtt/lamp/set", "subscribe zigbee2mqtt/lamp", "publish zigbee2mqtt/lamp/get"],
+            *sent.lock().unwrap()
+        );
+        assert!(lamp.is_init());
+        assert_eq!(Some(json!({"state": "ON"})), lamp.last_state());
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+use log::{info, error};
+use crate::generic_device::{Locality, SharedDevice};
+use crate::transport::TransportClient;
+
+static RECONNECTIONS: AtomicU64 = AtomicU64::new(0);
+static CONNECTED: AtomicBool = AtomicBool::new(false);
+
+/// Number of times the connection to the broker came back since the start of the process
+pub fn reconnect_count() -> u64 {
+    RECONNECTIONS.load(Ordering::SeqCst)
+}
+
+/// True between a CONNACK and the next connection error
+pub fn is_connected() -> bool {
+    CONNECTED.load(Ordering::SeqCst)
+}
+
+pub(crate) fn set_connected(connected: bool) {
+    CONNECTED.store(connected, Ordering::SeqCst);
+}
+
+pub(crate) fn count_reconnection() -> u64 {
+    crate::metrics::reconnection();
+    RECONNECTIONS.fetch_add(1, Ordering::SeqCst) + 1
+}
+
+struct ReconnectChannels<T: Locality> {
+    channel_filters: Vec<String>,
+    devices_to_init: Vec<SharedDevice<T>>, // the init stage is run again for these devices, empty for none
+    reinit: bool,
+}
+
+/// How `process_incoming_message` comes back after a connection loss.
+/// The sessions are opened with clean_start, so the broker forgets the subscriptions.
+/// The clones share the channels, the module watcher updates them after a reload.
+#[derive(Clone)]
+pub struct Reconnect<T: Locality> {
+    channels: Arc<RwLock<ReconnectChannels<T>>>,
+    pub min_backoff: Duration,
+    pub max_backoff: Duration,
+}
+
+impl <T> Reconnect<T> where T: Locality {
+    pub fn new(channel_filters: &[String]) -> Self {
+        Self {
+            channels: Arc::new(RwLock::new(ReconnectChannels {
+                channel_filters: channel_filters.to_vec(),
+                devices_to_init: vec![],
+                reinit: false,
+            })),
+            min_backoff: Duration::from_secs(1),
+            max_backoff: Duration::from_secs(60),
+        }
+    }
+
+    pub fn with_init(self, devices_to_init: &[SharedDevice<T>]) -> Self {
+        {
+            let mut channels = self.channels.write().unwrap_or_else(|e| e.into_inner());
+            channels.devices_to_init = devices_to_init.to_vec();
+            channels.reinit = true;
+        }
+        self
+    }
+
+    pub fn channel_filters(&self) -> Vec<String> {
+        self.channels.read().unwrap_or_else(|e| e.into_inner()).channel_filters.clone()
+    }
+
+    pub fn devices_to_init(&self) -> Vec<SharedDevice<T>> {
+        self.channels.read().unwrap_or_else(|e| e.into_inner()).devices_to_init.clone()
+    }
+
+    /// The channels and devices of a new version of the module.
+    /// The devices to init are only kept if the init stage runs again on reconnection.
+    pub fn update(&self, channel_filters: &[String], devices_to_init: &[SharedDevice<T>]) {
+        let mut channels = self.channels.write().unwrap_or_else(|e| e.into_inner());
+        channels.channel_filters = channel_filters.to_vec();
+        if channels.reinit {
+            channels.devices_to_init = devices_to_init.to_vec();
+        }
+    }
+
+    pub fn with_backoff(mut self, min_backoff: Duration, max_backoff: Duration) -> Self {
+        self.min_backoff = min_backoff;
+        self.max_backoff = max_backoff.max(min_backoff);
+        self
+    }
+
+    /// Wait before the next connection attempt, twice as long as the previous one up to max_backoff
+    pub(crate) fn next_backoff(&self, backoff: Duration) -> Duration {
+        (backoff * 2).min(self.max_backoff)
+    }
+
+    /// Subscribe again to all the channels, in one request.
+    pub(crate) async fn resubscribe(&self, client: &dyn TransportClient) {
+        let channel_filters = self.channel_filters();
+        if channel_filters.is_empty() {
+            return;
+        }
+        match client.subscribe_many(&channel_filters).await {
+            Ok(_) => info!("Subscribe again to [{}] channel(s)", channel_filters.len()),
+            Err(e) => error!("💀 Cannot subscribe again, e=[{}]", e),
+        }
+    }
+}
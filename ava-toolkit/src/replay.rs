@@ -0,0 +1,209 @@
+//! Record and replay of the MQTT traffic of a service, to test the loops without the house.
+//! The `mqtt.record_file` property appends every message received and every command published to a JSON-lines file.
+//! `Replay` feeds the received messages of such a file to the devices and loops, like the broker would,
+//! and collects the commands through the in-process `Loopback`. `compare_transcripts` checks them against the recorded ones.
+
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Mutex, OnceLock};
+
+use chrono::{DateTime, Utc};
+use log::{error, info, warn};
+use serde::de::DeserializeOwned;
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::domotic_factory::DomoticFactory;
+use crate::generic_device::{Locality, SharedDevice};
+use crate::hard_loop::HardLoop;
+use crate::loopback::Loopback;
+use crate::processing::inject_message;
+use crate::transport::TransportEvent;
+
+static RECORDER: OnceLock<Mutex<File>> = OnceLock::new();
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Direction {
+    /// Received from the broker
+    In,
+    /// Published by the service
+    Out,
+}
+
+/// One line of a record file
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RecordedMessage {
+    pub ts: DateTime<Utc>,
+    pub direction: Direction,
+    pub topic: String,
+    pub payload: String,
+    /// Received during the init stage, the answer of a device to its `/get` request
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub init: bool,
+}
+
+impl RecordedMessage {
+    pub fn new(direction: Direction, topic: &str, payload: &str) -> Self {
+        Self {
+            ts: Utc::now(),
+            direction,
+            topic: topic.to_string(),
+            payload: payload.to_string(),
+            init: false,
+        }
+    }
+}
+
+/// Record the traffic of the service in the file, appended to the previous records
+pub fn init_recorder(path: &str) -> Result<(), String> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .map_err(|e| format!("Cannot open the record file [{}], e=[{}]", path, e))?;
+    RECORDER
+        .set(Mutex::new(file))
+        .map_err(|_| "The record file is already set".to_string())?;
+    info!("📼 Record the MQTT traffic in [{}]", path);
+    Ok(())
+}
+
+/// Append the message to the record file, if any
+pub(crate) fn record(message: RecordedMessage) {
+    let Some(recorder) = RECORDER.get() else {
+        return;
+    };
+    let line = match serde_json::to_string(&message) {
+        Ok(line) => line,
+        Err(e) => {
+            error!("💣 Cannot serialize the record of [{}], e=[{}]", &message.topic, e);
+            return;
+        }
+    };
+    let mut file = recorder.lock().unwrap_or_else(|e| e.into_inner());
+    if let Err(e) = writeln!(file, "{}", line) {
+        error!("💣 Cannot write the record of [{}], e=[{}]", &message.topic, e);
+    }
+}
+
+/// Read a record file, or a transcript written by hand in the same format
+pub fn read_transcript(path: impl AsRef<Path>) -> Result<Vec<RecordedMessage>, String> {
+    let path = path.as_ref();
+    let file = File::open(path).map_err(|e| format!("Cannot open [{}], e=[{}]", path.display(), e))?;
+    let mut messages = vec![];
+    for (i, line) in BufReader::new(file).lines().enumerate() {
+        let line = line.map_err(|e| format!("Cannot read [{}], e=[{}]", path.display(), e))?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let message = serde_json::from_str(&line)
+            .map_err(|e| format!("Wrong record at [{}:{}], e=[{}]", path.display(), i + 1, e))?;
+        messages.push(message);
+    }
+    Ok(messages)
+}
+
+/// Check the published messages of `actual` against the ones of `expected`, in order.
+/// The received messages and the timestamps are ignored, the JSON payloads are compared as JSON.
+pub fn compare_transcripts(expected: &[RecordedMessage], actual: &[RecordedMessage]) -> Result<(), String> {
+    let published = |messages: &[RecordedMessage]| -> Vec<RecordedMessage> {
+        messages.iter().filter(|m| m.direction == Direction::Out).cloned().collect()
+    };
+    let (expected, actual) = (published(expected), published(actual));
+
+    for (i, (e, a)) in expected.iter().zip(actual.iter()).enumerate() {
+        if e.topic != a.topic || !same_payload(&e.payload, &a.payload) {
+            return Err(format!(
+                "Message [{}] differs, expected [{}] <{}>, got [{}] <{}>",
+                i + 1, &e.topic, &e.payload, &a.topic, &a.payload
+            ));
+        }
+    }
+    if expected.len() != actual.len() {
+        return Err(format!("Expected [{}] published messages, got [{}]", expected.len(), actual.len()));
+    }
+    Ok(())
+}
+
+fn same_payload(expected: &str, actual: &str) -> bool {
+    match (serde_json::from_str::<Value>(expected), serde_json::from_str::<Value>(actual)) {
+        (Ok(e), Ok(a)) => e == a,
+        _ => expected == actual,
+    }
+}
+
+/// Replay driver, the devices and loops of a module without a broker.
+/// The messages are processed one after the other, as fast as possible : the time of the loop guards
+/// and of the throttle settings is the time of the replay, not the one of the record.
+pub struct Replay<T: Locality> {
+    loops: Vec<HardLoop<T>>,
+    devices_to_init: Vec<SharedDevice<T>>,
+    args: Vec<String>,
+}
+
+impl <T> Replay<T> where T: Locality + DeserializeOwned {
+    pub fn new(loops: Vec<HardLoop<T>>, devices_to_init: Vec<SharedDevice<T>>) -> Self {
+        Self {
+            loops,
+            devices_to_init,
+            args: vec![],
+        }
+    }
+
+    /// The devices and loops of the module file, like the service builds them
+    pub fn from_module(module_file: impl AsRef<Path>, factory_message_dir: impl AsRef<Path>) -> Self {
+        let mut domo_factory: DomoticFactory<T> = DomoticFactory::new(module_file, factory_message_dir);
+        domo_factory.build_devices();
+        Self::new(domo_factory.build_loops(), domo_factory.devices_to_init())
+    }
+
+    /// Arguments given to `Locality::process`
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Process the received messages of the record, return the messages the service published.
+    /// The init messages set the state of the devices to init, the devices without one keep their factory message.
+    pub async fn run(&self, record: &[RecordedMessage]) -> Vec<RecordedMessage> {
+        let broker = Loopback::new();
+        let (client, _) = broker.connect();
+        // Everything the devices publish
+        let (probe, mut probe_events) = broker.connect();
+        if let Err(e) = probe.subscribe("#").await {
+            error!("💣 Cannot listen to the loopback, e=[{}]", e);
+        }
+        let mut published = vec![];
+
+        for message in record.iter().filter(|m| m.direction == Direction::In) {
+            if message.init {
+                for device in &self.devices_to_init {
+                    device.init(&message.topic, &message.payload).await;
+                }
+                continue;
+            }
+
+            info!("📼 Replay topic [{}], message <{}>", &message.topic, &message.payload);
+            let (loops, opt_device) = HardLoop::find_loops(&message.topic, &self.loops);
+            match opt_device {
+                None => warn!("No device for the topic [{}]", &message.topic),
+                Some(device) => inject_message(device, &message.topic, &message.payload, loops, &self.args, client.as_ref()).await,
+            }
+
+            while let Some(event) = probe_events.try_next() {
+                if let TransportEvent::Publish { topic, payload, .. } = event {
+                    published.push(RecordedMessage {
+                        ts: message.ts,
+                        direction: Direction::Out,
+                        topic,
+                        payload,
+                        init: false,
+                    });
+                }
+            }
+        }
+        published
+    }
+}
@@ -0,0 +1,324 @@
+//! Message transformation rules, written in the module file on the target device.
+//!
+//! ```text
+//! on action=single, toggle target.state
+//! on action=brightness_up and state=ON, set target.brightness=254
+//! copy brightness from source
+//! copy target.color from source.color
+//! ```
+//!
+//! The conditions read the message of the source device, the actions change the last message of the target device.
+//! A value is read as JSON (`254`, `true`, `"ON"`), or as a plain string otherwise.
+//! A condition value with a comma, or with ` and `, must be a JSON string : `on name="a, b", ...`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use serde_json::Value;
+
+type Path = Vec<String>;
+/// A field of the source and the value it must have
+type Condition = (Path, Value);
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuleAction {
+    /// "ON" <-> "OFF" (any case), true <-> false
+    Toggle(Path),
+    Set(Path, Value),
+    Copy { target: Path, source: Path },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Rule {
+    pub conditions: Vec<Condition>,
+    pub action: RuleAction,
+    text: String,
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.text)
+    }
+}
+
+fn parse_value(text: &str) -> Value {
+    serde_json::from_str(text).unwrap_or_else(|_| Value::String(text.to_string()))
+}
+
+/// "target.a.b" or "a.b" -> ["a", "b"]
+fn parse_path(text: &str, prefix: &str) -> Result<Path, String> {
+    let text = text.trim();
+    let text = text.strip_prefix(&format!("{}.", prefix)).unwrap_or(text);
+    let path: Path = text.split('.').map(|s| s.trim().to_string()).collect();
+    if path.iter().any(|s| s.is_empty() || s.contains(char::is_whitespace)) {
+        return Err(format!("Wrong field [{}]", text));
+    }
+    Ok(path)
+}
+
+fn parse_action(text: &str) -> Result<RuleAction, String> {
+    let text = text.trim();
+    let (verb, rest) = text.split_once(char::is_whitespace).ok_or(format!("Missing field in [{}]", text))?;
+    match verb {
+        "toggle" => Ok(RuleAction::Toggle(parse_path(rest, "target")?)),
+        "set" => {
+            let (path, value) = rest.split_once('=').ok_or(format!("Missing value in [{}]", text))?;
+            Ok(RuleAction::Set(parse_path(path, "target")?, parse_value(value.trim())))
+        }
+        "copy" => {
+            let (target, source) = rest.split_once(" from ").ok_or(format!("Missing 'from' in [{}]", text))?;
+            let target = parse_path(target, "target")?;
+            let source = match source.trim() {
+                "source" => target.clone(),
+                s if s.starts_with("source.") => parse_path(s, "source")?,
+                s => return Err(format!("The copy must be from the source, [{}]", s)),
+            };
+            Ok(RuleAction::Copy { target, source })
+        }
+        _ => Err(format!("Unknown action [{}], expected toggle, set or copy", verb)),
+    }
+}
+
+/// The value at the start of the text and what follows it.
+/// A JSON string, array or object is read to its end, a plain value stops at the first ',' or ` and `.
+fn split_condition_value(text: &str) -> Result<(Value, &str), String> {
+    if text.starts_with(['"', '[', '{']) {
+        let mut values = serde_json::Deserializer::from_str(text).into_iter::<Value>();
+        let value = match values.next() {
+            Some(Ok(value)) => value,
+            _ => return Err(format!("Wrong JSON value [{}]", text)),
+        };
+        return Ok((value, &text[values.byte_offset()..]));
+    }
+    let end = [text.find(','), text.find(" and ")].into_iter().flatten().min().unwrap_or(text.len());
+    Ok((parse_value(text[..end].trim()), &text[end..]))
+}
+
+/// `a=b and c=d, <action>` -> the conditions and the action
+fn parse_conditions(text: &str) -> Result<(Vec<Condition>, &str), String> {
+    let mut conditions = vec![];
+    let mut rest = text;
+    loop {
+        let (path, value_text) = rest.split_once('=')
+            .filter(|(path, _)| !path.contains(','))
+            .ok_or(format!("Wrong condition [{}]", rest.trim()))?;
+        let (value, after) = split_condition_value(value_text.trim_start())?;
+        conditions.push((parse_path(path, "source")?, value));
+
+        let after = after.trim_start();
+        if let Some(action) = after.strip_prefix(',') {
+            return Ok((conditions, action));
+        }
+        rest = after.strip_prefix("and ").ok_or(format!("Missing ',' after the condition in [{}]", text.trim()))?;
+    }
+}
+
+impl FromStr for Rule {
+    type Err = String;
+
+    fn from_str(text: &str) -> Result<Self, Self::Err> {
+        let text = text.trim();
+        let (conditions, action) = match text.strip_prefix("on ") {
+            Some(rest) => parse_conditions(rest)?,
+            None => (vec![], text),
+        };
+        Ok(Rule {
+            conditions,
+            action: parse_action(action)?,
+            text: text.to_string(),
+        })
+    }
+}
+
+fn get_path<'a>(value: &'a Value, path: &Path) -> Option<&'a Value> {
+    path.iter().try_fold(value, |v, key| v.get(key))
+}
+
+/// Set the value, the missing objects on the path are created
+fn set_path(value: &mut Value, path: &Path, new_value: Value) -> Result<(), String> {
+    let mut current = value;
+    for key in path {
+        if current.is_null() {
+            *current = Value::Object(serde_json::Map::new());
+        }
+        if !current.is_object() {
+            return Err(format!("[{}] is not an object", key));
+        }
+        current = current
+            .as_object_mut()
+            .map(|o| o.entry(key.clone()).or_insert(Value::Null))
+            .ok_or(format!("[{}] is not an object", key))?;
+    }
+    *current = new_value;
+    Ok(())
+}
+
+fn toggled(value: Option<&Value>) -> Result<Value, String> {
+    match value {
+        Some(Value::Bool(b)) => Ok(Value::Bool(!b)),
+        Some(Value::String(s)) if s.eq_ignore_ascii_case("on") => Ok(Value::String(if s == "on" { "off" } else { "OFF" }.to_string())),
+        Some(Value::String(s)) if s.eq_ignore_ascii_case("off") => Ok(Value::String(if s == "off" { "on" } else { "ON" }.to_string())),
+        other => Err(format!("Cannot toggle the value [{:?}]", other)),
+    }
+}
+
+impl Rule {
+    pub fn matches(&self, source: &Value) -> bool {
+        self.conditions.iter().all(|(path, expected)| {
+            get_path(source, path) == Some(expected)
+        })
+    }
+
+    /// Apply the action on the target message, if the conditions are true. Return true if the rule applied.
+    pub fn apply(&self, source: &Value, target: &mut Value) -> Result<bool, String> {
+        if !self.matches(source) {
+            return Ok(false);
+        }
+        match &self.action {
+            RuleAction::Toggle(path) => {
+                let new_value = toggled(get_path(target, path))?;
+                set_path(target, path, new_value)?;
+            }
+            RuleAction::Set(path, value) => set_path(target, path, value.clone())?,
+            RuleAction::Copy { target: target_path, source: source_path } => {
+                // Nothing to copy, the source message does not always carry all the fields
+                let Some(value) = get_path(source, source_path) else {
+                    return Ok(false);
+                };
+                set_path(target, target_path, value.clone())?;
+            }
+        }
+        Ok(true)
+    }
+}
+
+/// Compile the rules of a device definition
+pub fn compile_rules(texts: &[String]) -> Result<Vec<Rule>, String> {
+    texts.iter().map(|t| t.parse::<Rule>().map_err(|e| format!("Wrong rule [{}], {}", t, e))).collect()
+}
+
+/// Run all the rules on a copy of the target message
+pub fn apply_rules(rules: &[Rule], source: &Value, target: &Value) -> Result<Value, String> {
+    let mut result = target.clone();
+    for rule in rules {
+        rule.apply(source, &mut result).map_err(|e| format!("Rule [{}] failed, {}", rule, e))?;
+    }
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn rule(text: &str) -> Rule {
+        text.parse().unwrap()
+    }
+
+    fn path(text: &str) -> Path {
+        text.split('.').map(str::to_string).collect()
+    }
+
+    #[test]
+    fn toggle_switches_on_off_and_booleans() {
+        let toggle = rule("toggle target.state");
+        let mut target = json!({"state": "ON", "brightness": 120});
+        assert!(toggle.apply(&json!({}), &mut target).unwrap());
+        assert_eq!(json!({"state": "OFF", "brightness": 120}), target);
+
+        let mut target = json!({"state": "off"});
+        toggle.apply(&json!({}), &mut target).unwrap();
+        assert_eq!(json!({"state": "on"}), target);
+
+        let mut target = json!({"state": true});
+        toggle.apply(&json!({}), &mut target).unwrap();
+        assert_eq!(json!({"state": false}), target);
+    }
+
+    #[test]
+    fn toggle_fails_on_other_values() {
+        let mut target = json!({"state": 3});
+        let e = rule("toggle target.state").apply(&json!({}), &mut target).unwrap_err();
+        assert!(e.starts_with("Cannot toggle"), "{}", e);
+    }
+
+    #[test]
+    fn set_reads_the_value_as_json_and_creates_the_objects() {
+        let mut target = json!({"state": "OFF"});
+        rule("set target.brightness=254").apply(&json!({}), &mut target).unwrap();
+        rule("set target.color.x=0.3").apply(&json!({}), &mut target).unwrap();
+        rule("set state=ON").apply(&json!({}), &mut target).unwrap();
+        assert_eq!(json!({"state": "ON", "brightness": 254, "color": {"x": 0.3}}), target);
+    }
+
+    #[test]
+    fn copy_from_the_source() {
+        let source = json!({"brightness": 80, "color": {"x": 0.5}});
+        let mut target = json!({"brightness": 10});
+        assert!(rule("copy brightness from source").apply(&source, &mut target).unwrap());
+        assert!(rule("copy target.tint from source.color").apply(&source, &mut target).unwrap());
+        assert_eq!(json!({"brightness": 80, "tint": {"x": 0.5}}), target);
+
+        // Nothing to copy
+        assert!(!rule("copy state from source").apply(&source, &mut target).unwrap());
+    }
+
+    #[test]
+    fn conditions_must_all_hold() {
+        let r = rule("on action=brightness_up and state=ON, set target.brightness=254");
+        assert_eq!(vec![(path("action"), json!("brightness_up")), (path("state"), json!("ON"))], r.conditions);
+
+        let mut target = json!({});
+        assert!(!r.apply(&json!({"action": "brightness_up", "state": "OFF"}), &mut target).unwrap());
+        assert!(!r.apply(&json!({"action": "brightness_up"}), &mut target).unwrap());
+        assert!(r.apply(&json!({"action": "brightness_up", "state": "ON"}), &mut target).unwrap());
+        assert_eq!(json!({"brightness": 254}), target);
+    }
+
+    #[test]
+    fn condition_values_are_json() {
+        let r = rule("on source.level=3 and ready=true and mode=\"eco\", toggle target.state");
+        assert_eq!(
+            vec![(path("level"), json!(3)), (path("ready"), json!(true)), (path("mode"), json!("eco"))],
+            r.conditions
+        );
+    }
+
+    #[test]
+    fn condition_values_with_a_comma_are_quoted() {
+        let r = rule(r#"on name="hall, left" and tags=["a","b"], set target.state=ON"#);
+        assert_eq!(
+            vec![(path("name"), json!("hall, left")), (path("tags"), json!(["a", "b"]))],
+            r.conditions
+        );
+        assert_eq!(RuleAction::Set(path("state"), json!("ON")), r.action);
+
+        let r = rule(r#"on name="rock and roll", toggle target.state"#);
+        assert_eq!(vec![(path("name"), json!("rock and roll"))], r.conditions);
+    }
+
+    #[test]
+    fn wrong_rules_tell_why() {
+        let error = |text: &str| text.parse::<Rule>().unwrap_err();
+        assert_eq!("Unknown action [flip], expected toggle, set or copy", error("flip target.state"));
+        assert_eq!("Missing field in [toggle]", error("toggle"));
+        assert_eq!("Missing value in [set target.state]", error("set target.state"));
+        assert_eq!("Missing 'from' in [copy brightness]", error("copy brightness"));
+        assert_eq!("The copy must be from the source, [target]", error("copy brightness from target"));
+        assert_eq!("Wrong field [sta te]", error("toggle target.sta te"));
+        assert_eq!("Missing ',' after the condition in [action=single toggle target.state]", error("on action=single toggle target.state"));
+        assert_eq!("Wrong condition [action, toggle target.state]", error("on action, toggle target.state"));
+        assert_eq!(r#"Wrong JSON value ["single, toggle target.state]"#, error(r#"on action="single, toggle target.state"#));
+    }
+
+    #[test]
+    fn compile_and_apply_name_the_rule() {
+        let e = compile_rules(&["toggle target.state".to_string(), "flip it".to_string()]).unwrap_err();
+        assert_eq!("Wrong rule [flip it], Unknown action [flip], expected toggle, set or copy", e);
+
+        let rules = compile_rules(&["toggle target.state".to_string()]).unwrap();
+        let e = apply_rules(&rules, &json!({}), &json!({"state": 1})).unwrap_err();
+        assert!(e.starts_with("Rule [toggle target.state] failed, Cannot toggle"), "{}", e);
+    }
+}
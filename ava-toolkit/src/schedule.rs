@@ -0,0 +1,324 @@
+//! Scheduled triggers, written in the module file.
+//!
+//! ```json
+//! "schedules": [
+//!     { "name": "heart_beat", "device": "regulate_radiator", "cron": "*/5 * * * *" },
+//!     { "name": "night", "device": "night_trigger", "every_s": 3600, "message": { "action": "night" } }
+//! ]
+//! ```
+//!
+//! At each time, the message (the factory template of the device by default) is processed as if the device sent it,
+//! after the messages of the device already received, and goes through the active loops of the device. The cron has 5 fields : minute, hour, day of month, month, day of week (0 or 7 is Sunday),
+//! with `*`, `a-b`, `a,b` and `/step`. The time is the local one.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, TimeZone, Timelike};
+use log::{info, warn};
+use serde::de::DeserializeOwned;
+use serde::Deserialize;
+use serde_json::Value;
+use tokio::task::JoinHandle;
+
+use crate::generic_device::Locality;
+use crate::hard_loop::HardLoop;
+use crate::module_watcher::SharedLoops;
+use crate::processing::DeviceWorkers;
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct ScheduleDefinition {
+    pub name: String,
+    pub device: String,
+    #[serde(default)]
+    pub cron: Option<String>,
+    #[serde(default)]
+    pub every_s: Option<u64>,
+    #[serde(default)]
+    pub message: Option<Value>,
+}
+
+/// One field of a cron expression, `allowed[v]` is true if the value matches
+#[derive(Debug, Clone, PartialEq)]
+struct CronField {
+    allowed: Vec<bool>,
+    any: bool,  // every value of the range, `*` or the like, used for the day of month / day of week rule
+}
+
+impl CronField {
+    fn parse(text: &str, min: u32, max: u32) -> Result<Self, String> {
+        let mut allowed = vec![false; max as usize + 1];
+        for part in text.split(',') {
+            let (range, step) = match part.split_once('/') {
+                Some((range, step)) => (range, step.parse::<u32>().map_err(|_| format!("Wrong step in [{}]", part))?),
+                None => (part, 1),
+            };
+            if step == 0 {
+                return Err(format!("Wrong step in [{}]", part));
+            }
+            let (start, end) = match range {
+                "*" => (min, max),
+                _ => match range.split_once('-') {
+                    Some((a, b)) => (
+                        a.parse::<u32>().map_err(|_| format!("Wrong value in [{}]", part))?,
+                        b.parse::<u32>().map_err(|_| format!("Wrong value in [{}]", part))?,
+                    ),
+                    None => {
+                        let v = range.parse::<u32>().map_err(|_| format!("Wrong value in [{}]", part))?;
+                        // "5/15" goes from 5 to the end
+                        (v, if part.contains('/') { max } else { v })
+                    }
+                },
+            };
+            if start < min || end > max || start > end {
+                return Err(format!("[{}] is out of [{}-{}]", part, min, max));
+            }
+            for v in (start..=end).step_by(step as usize) {
+                allowed[v as usize] = true;
+            }
+        }
+        let any = allowed[min as usize..=max as usize].iter().all(|v| *v);
+        Ok(Self { allowed, any })
+    }
+
+    fn matches(&self, value: u32) -> bool {
+        self.allowed.get(value as usize).copied().unwrap_or(false)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct CronExpr {
+    minutes: CronField,
+    hours: CronField,
+    days_of_month: CronField,
+    months: CronField,
+    days_of_week: CronField,
+}
+
+impl CronExpr {
+    pub fn parse(text: &str) -> Result<Self, String> {
+        let fields: Vec<&str> = text.split_whitespace().collect();
+        let [minutes, hours, days_of_month, months, days_of_week] = fields[..] else {
+            return Err(format!("The cron [{}] must have 5 fields", text));
+        };
+        // 0 and 7 are both Sunday
+        let mut days_of_week = CronField::parse(days_of_week, 0, 7)?;
+        if days_of_week.allowed[7] {
+            days_of_week.allowed[0] = true;
+        }
+        days_of_week.any = days_of_week.allowed[..7].iter().all(|v| *v);
+        Ok(Self {
+            minutes: CronField::parse(minutes, 0, 59)?,
+            hours: CronField::parse(hours, 0, 23)?,
+            days_of_month: CronField::parse(days_of_month, 1, 31)?,
+            months: CronField::parse(months, 1, 12)?,
+            days_of_week,
+        })
+    }
+
+    /// As in cron, when both day fields are restricted, one of them is enough
+    fn matches_day(&self, date: &NaiveDate) -> bool {
+        let dom = self.days_of_month.matches(date.day());
+        let dow = self.days_of_week.matches(date.weekday().num_days_from_sunday());
+        match (self.days_of_month.any, self.days_of_week.any) {
+            (false, false) => dom || dow,
+            _ => dom && dow,
+        }
+    }
+
+    /// The first matching minute after `now`, None if there is none within 4 years (ex : 30th of February)
+    pub fn next_after(&self, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        let start = now.naive_local().with_second(0)?.with_nanosecond(0)? + chrono::Duration::minutes(1);
+        let mut date = start.date();
+        let last_date = date + chrono::Duration::days(4 * 366);
+        while date <= last_date {
+            if self.months.matches(date.month()) && self.matches_day(&date) {
+                for hour in (0..24).filter(|h| self.hours.matches(*h)) {
+                    for minute in (0..60).filter(|m| self.minutes.matches(*m)) {
+                        let candidate = date.and_hms_opt(hour, minute, 0)?;
+                        if candidate < start {
+                            continue;
+                        }
+                        // A time skipped by a DST change is ignored
+                        if let Some(time) = Local.from_local_datetime(&candidate).earliest() {
+                            return Some(time);
+                        }
+                    }
+                }
+            }
+            date = date.succ_opt()?;
+        }
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Trigger {
+    Every(Duration),
+    Cron(CronExpr),
+}
+
+impl Trigger {
+    fn next_after(&self, now: &DateTime<Local>) -> Option<DateTime<Local>> {
+        match self {
+            Trigger::Every(period) => chrono::Duration::from_std(*period).ok().map(|d| *now + d),
+            Trigger::Cron(cron) => cron.next_after(now),
+        }
+    }
+}
+
+/// A schedule of the module, the device is found by its topic in the current loops
+#[derive(Debug, Clone, PartialEq)]
+pub struct Schedule {
+    pub name: String,
+    pub topic: String,
+    pub trigger: Trigger,
+    pub message: Option<String>,    // the template of the device if None
+}
+
+impl ScheduleDefinition {
+    pub fn trigger(&self) -> Result<Trigger, String> {
+        match (&self.cron, self.every_s) {
+            (Some(cron), None) => Ok(Trigger::Cron(CronExpr::parse(cron)?)),
+            (None, Some(every_s)) if every_s > 0 => Ok(Trigger::Every(Duration::from_secs(every_s))),
+            (None, Some(_)) => Err("every_s must be positive".to_string()),
+            _ => Err("either cron or every_s is needed".to_string()),
+        }
+    }
+}
+
+/// The schedules read by the scheduler, swapped by the watcher after a reload
+pub type SharedSchedules = Arc<RwLock<Vec<Schedule>>>;
+
+pub struct Scheduler<T: Locality> {
+    schedules: SharedSchedules,
+    loops: SharedLoops<T>,
+    workers: DeviceWorkers<T>,
+}
+
+impl <T> Scheduler<T> where T: Locality + DeserializeOwned {
+    pub fn new(schedules: &SharedSchedules, loops: &SharedLoops<T>, workers: &DeviceWorkers<T>) -> Self {
+        Self {
+            schedules: schedules.clone(),
+            loops: loops.clone(),
+            workers: workers.clone(),
+        }
+    }
+
+    /// Check the schedules every second. A changed schedule starts again from now.
+    pub fn spawn(self) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut next_times: HashMap<String, (Schedule, Option<DateTime<Local>>)> = HashMap::new();
+            loop {
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                let schedules = self.schedules.read().unwrap_or_else(|e| e.into_inner()).clone();
+                let now = Local::now();
+                next_times.retain(|name, _| schedules.iter().any(|s| &s.name == name));
+
+                for schedule in schedules {
+                    let entry = next_times.entry(schedule.name.clone()).or_insert((schedule.clone(), None));
+                    if entry.0 != schedule || entry.1.is_none() {
+                        let next = schedule.trigger.next_after(&now);
+                        info!("⏰ Schedule [{}] next time [{:?}]", &schedule.name, next);
+                        *entry = (schedule.clone(), next);
+                    }
+                    if entry.1.is_some_and(|next| next <= now) {
+                        entry.1 = schedule.trigger.next_after(&now);
+                        self.fire(&schedule);
+                    }
+                }
+            }
+        })
+    }
+
+    fn fire(&self, schedule: &Schedule) {
+        let (loops, o_device) = {
+            let all_loops = self.loops.read().unwrap_or_else(|e| e.into_inner());
+            HardLoop::find_loops(&schedule.topic, &all_loops)
+        };
+        let Some(device) = o_device else {
+            warn!("⏰ Schedule [{}] : no loop for [{}]", &schedule.name, &schedule.topic);
+            return;
+        };
+        let msg = schedule.message.clone().unwrap_or_else(|| device.message_type.raw_message());
+        info!("⏰ Schedule [{}] triggers [{}], message=<{}>", &schedule.name, &schedule.topic, &msg);
+        self.workers.inject(device, &schedule.topic, &msg, loops);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local(y: i32, mo: u32, d: u32, h: u32, mi: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, mo, d, h, mi, 0).single().unwrap()
+    }
+
+    #[test]
+    fn parse_lists_ranges_and_steps() {
+        let field = CronField::parse("1-3,10,20/15", 0, 59).unwrap();
+        let allowed: Vec<u32> = (0..60).filter(|v| field.matches(*v)).collect();
+        assert_eq!(vec![1, 2, 3, 10, 20, 35, 50], allowed);
+        assert!(!field.any);
+
+        let field = CronField::parse("*/20", 0, 59).unwrap();
+        assert_eq!(vec![0, 20, 40], (0..60).filter(|v| field.matches(*v)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parse_rejects_wrong_fields() {
+        assert!(CronField::parse("60", 0, 59).is_err());
+        assert!(CronField::parse("5-2", 0, 59).is_err());
+        assert!(CronField::parse("*/0", 0, 59).is_err());
+        assert!(CronField::parse("a", 0, 59).is_err());
+        assert!(CronExpr::parse("* * * *").is_err());
+    }
+
+    #[test]
+    fn any_is_a_field_covering_its_range() {
+        assert!(CronField::parse("*", 1, 31).unwrap().any);
+        assert!(CronField::parse("*/1", 1, 31).unwrap().any);
+        assert!(CronField::parse("1-31", 1, 31).unwrap().any);
+        assert!(!CronField::parse("*/2", 1, 31).unwrap().any);
+
+        assert!(CronExpr::parse("0 0 * * 0-6").unwrap().days_of_week.any);
+        assert!(CronExpr::parse("0 0 * * 1-7").unwrap().days_of_week.any);
+        assert!(!CronExpr::parse("0 0 * * 1-5").unwrap().days_of_week.any);
+    }
+
+    #[test]
+    fn next_after_steps_in_the_hour() {
+        let cron = CronExpr::parse("*/15 * * * *").unwrap();
+        assert_eq!(Some(local(2024, 6, 12, 10, 15)), cron.next_after(&local(2024, 6, 12, 10, 0)));
+        assert_eq!(Some(local(2024, 6, 12, 11, 0)), cron.next_after(&local(2024, 6, 12, 10, 50)));
+    }
+
+    #[test]
+    fn next_after_goes_to_the_next_day_and_month() {
+        let cron = CronExpr::parse("30 6 1 * *").unwrap();
+        assert_eq!(Some(local(2024, 7, 1, 6, 30)), cron.next_after(&local(2024, 6, 12, 10, 0)));
+    }
+
+    #[test]
+    fn next_after_with_a_full_day_of_month_only_follows_the_day_of_week() {
+        // Wednesday 12 June 2024, the next Monday is the 17th
+        let monday = local(2024, 6, 17, 8, 0);
+        for cron in ["0 8 * * 1", "0 8 */1 * 1", "0 8 1-31 * 1"] {
+            assert_eq!(Some(monday), CronExpr::parse(cron).unwrap().next_after(&local(2024, 6, 12, 10, 0)), "{}", cron);
+        }
+    }
+
+    #[test]
+    fn next_after_with_both_days_restricted_takes_either() {
+        // The 15th (a Saturday) comes before the next Monday
+        let cron = CronExpr::parse("0 8 15 * 1").unwrap();
+        assert_eq!(Some(local(2024, 6, 15, 8, 0)), cron.next_after(&local(2024, 6, 12, 10, 0)));
+    }
+
+    #[test]
+    fn next_after_never_for_an_impossible_date() {
+        let cron = CronExpr::parse("0 0 30 2 *").unwrap();
+        assert_eq!(None, cron.next_after(&local(2024, 6, 12, 10, 0)));
+    }
+}
@@ -0,0 +1,368 @@
+use std::fs;
+use std::marker::PhantomData;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use axum::routing::get;
+use axum::{Json, Router};
+use common_config::conf_reader::{read_config, read_env};
+use common_config::properties::{get_prop_value, set_prop_values};
+use log::{info, warn, error};
+use rumqttc::v5::MqttOptions;
+use rumqttc::Transport;
+use serde::de::DeserializeOwned;
+use serde_json::{json, Value};
+
+use crate::dead_letter::init_dead_letter;
+use crate::domotic_factory::DomoticFactory;
+use crate::generic_device::Locality;
+use crate::hard_loop::HardLoop;
+use crate::init_loop::process_initialization_message;
+use crate::logging::init_logging;
+use crate::loopback::Loopback;
+use crate::metrics::start_metrics;
+use crate::module_watcher::{ModuleWatcher, SharedLoops};
+use crate::processing::{dispatch_incoming_message, DeviceWorkers};
+use crate::schedule::{Scheduler, SharedSchedules};
+use crate::reconnect::{is_connected, reconnect_count, Reconnect};
+use crate::replay::init_recorder;
+use crate::transport::{rumqttc_transport, SharedClient, TransportClient, TransportEvent, TransportEvents};
+
+const VAR_NAME: &str = "AVA_ENV";
+
+/// Time given to the queued messages to reach the broker after a shutdown request
+const SHUTDOWN_GRACE: Duration = Duration::from_secs(5);
+
+/// Builder of an `AvaService`, the logger and the config are set up by `build`
+pub struct AvaServiceBuilder<T: Locality> {
+    project_code: String,
+    version: String,
+    args: Vec<String>,
+    loopback: Option<Loopback>,
+    _message: PhantomData<T>,
+}
+
+impl <T> AvaServiceBuilder<T> where T: Locality + DeserializeOwned {
+    pub fn version(mut self, version: &str) -> Self {
+        self.version = version.to_string();
+        self
+    }
+
+    /// Arguments given to `Locality::process`
+    pub fn args(mut self, args: Vec<String>) -> Self {
+        self.args = args;
+        self
+    }
+
+    /// Use the in-process broker instead of the one of the mqtt.* properties.
+    /// The services of a process can share it, but the properties are global to the process.
+    pub fn loopback(mut self, broker: &Loopback) -> Self {
+        self.loopback = Some(broker.clone());
+        self
+    }
+
+    /// Start the logger and read the config of the project, the properties can be read once it's done
+    pub fn build(self) -> AvaService<T> {
+        init_logging();
+
+        info!("Starting AVA {} {}", &self.project_code, &self.version);
+
+        let o_config_file = read_env(VAR_NAME);
+
+        // Read the application config's file
+        println!(
+            "😎 Config file using PROJECT_CODE={} VAR_NAME={}",
+            &self.project_code, VAR_NAME
+        );
+
+        let props = read_config(
+            &self.project_code,
+            &o_config_file,
+            &Some("AVA_CLUSTER_PROFILE".to_string()),
+        );
+        set_prop_values(props);
+
+        AvaService {
+            project_code: self.project_code,
+            version: self.version,
+            args: self.args,
+            loopback: self.loopback,
+            _message: PhantomData,
+        }
+    }
+}
+
+/// The common life of the MQTT daemons: devices and loops from the module file, broker connection,
+/// init stage, then message processing until SIGTERM or SIGINT.
+///
+/// Properties : factory.dir, module, mqtt.host, mqtt.port, mqtt.user, mqtt.password,
+/// mqtt.tls, mqtt.ca_file, mqtt.client_cert_file, mqtt.client_key_file, mqtt.reinit_on_reconnect, health.port,
+/// metrics.port, module.reload_interval, mqtt.dead_letter_topic, mqtt.record_file
+/// The mqtt.host ... mqtt.client_key_file properties are not read with the loopback.
+pub struct AvaService<T: Locality> {
+    project_code: String,
+    version: String,
+    args: Vec<String>,
+    loopback: Option<Loopback>,
+    _message: PhantomData<T>,
+}
+
+impl <T> AvaService<T> where T: Locality + DeserializeOwned {
+    pub fn builder(project_code: &str) -> AvaServiceBuilder<T> {
+        AvaServiceBuilder {
+            project_code: project_code.to_string(),
+            version: "0.5.0".to_string(),
+            args: vec![],
+            loopback: None,
+            _message: PhantomData,
+        }
+    }
+
+    /// Run the service, return when a shutdown is requested.
+    pub async fn run(self) -> Result<(), String> {
+        let factory_message_dir = read_prop("factory.dir")?;
+        let module_file = read_prop("module")?;
+        let mqtt_host = match &self.loopback {
+            Some(_) => "loopback".to_string(),
+            None => read_prop("mqtt.host")?,
+        };
+
+        let mut domo_factory: DomoticFactory<T> = DomoticFactory::new(module_file, factory_message_dir);
+        domo_factory.build_devices();
+
+        let all_loops = domo_factory.build_loops();
+        let init_list = domo_factory.devices_to_init();
+        let device_to_listen = domo_factory.devices_to_listen();
+
+        let channels = DomoticFactory::extract_channel_from_devices(&device_to_listen, &mqtt_host);
+
+        let (client, mut events): (SharedClient, Box<dyn TransportEvents>) = match &self.loopback {
+            Some(broker) => {
+                info!("🔁 MQTT through the in-process loopback");
+                let (client, events) = broker.connect();
+                (client, Box::new(events))
+            }
+            None => {
+                let mqtt_port = read_prop("mqtt.port")?
+                    .parse::<u16>()
+                    .map_err(|e| format!("Wrong value for [mqtt.port], e=[{}]", e))?;
+                let mut mqttoptions = MqttOptions::new(&channels.client_id, &channels.server_addr, mqtt_port);
+                mqttoptions.set_keep_alive(Duration::from_secs(channels.keep_alive as u64));
+                mqttoptions.set_clean_start(true);
+                mqttoptions.set_credentials(read_prop("mqtt.user")?, read_prop("mqtt.password")?);
+                if let Some(transport) = read_tls_transport()? {
+                    mqttoptions.set_transport(transport);
+                }
+                let (client, events) = rumqttc_transport(mqttoptions, 15);
+                (client, Box::new(events))
+            }
+        };
+
+        for topic in &channels.channel_filters {
+            info!("Subscribe to [{}]", topic);
+            client
+                .subscribe(topic)
+                .await
+                .map_err(|e| format!("Subscribe failed, topic=[{}], e=[{}]", topic, e))?;
+        }
+
+        let ready = Arc::new(AtomicBool::new(false));
+        start_health(&self.project_code, &self.version, ready.clone()).await?;
+        start_metrics().await?;
+
+        let loops: SharedLoops<T> = Arc::new(RwLock::new(all_loops));
+        let schedules: SharedSchedules = Arc::new(RwLock::new(domo_factory.build_schedules()));
+        let loop_finder = |topic: &str| HardLoop::find_loops(topic, &loops.read().unwrap_or_else(|e| e.into_inner()));
+
+        // Subscribe again after a broker restart, and ask the devices for their state if mqtt.reinit_on_reconnect is true
+        let mut reconnect = Reconnect::new(&channels.channel_filters);
+        if get_prop_value("mqtt.reinit_on_reconnect").map(|v| v == "true").unwrap_or(false) {
+            reconnect = reconnect.with_init(&init_list);
+        }
+
+        // The messages the devices cannot read are republished there, mqtt.dead_letter_topic (ex: ava/dlq/luminator)
+        if let Ok(topic) = get_prop_value("mqtt.dead_letter_topic") {
+            init_dead_letter(&self.project_code, &topic)?;
+        }
+
+        // The MQTT traffic goes to mqtt.record_file, to replay it with ava_toolkit::replay::Replay
+        if let Ok(path) = get_prop_value("mqtt.record_file") {
+            init_recorder(&path)?;
+        }
+
+        // Rebuild the devices and loops when the module changes, module.reload_interval seconds (5 by default, 0 to disable)
+        let reload_interval = match get_prop_value("module.reload_interval") {
+            Ok(v) => v.parse::<u64>().map_err(|e| format!("Wrong value for [module.reload_interval], e=[{}]", e))?,
+            Err(_) => 5,
+        };
+        if reload_interval > 0 {
+            ModuleWatcher::new(domo_factory, &loops, &schedules, &reconnect, &client, &mqtt_host, Duration::from_secs(reload_interval)).spawn();
+        }
+
+        // Shared by the broker messages and the schedules, one task per device keeps their order
+        let workers = DeviceWorkers::new(&client, &self.args);
+        let mut scheduler = None;
+        let processing = async {
+            process_initialization_message(client.as_ref(), events.as_mut(), &init_list, &reconnect).await?;
+            ready.store(true, Ordering::SeqCst);
+            // The schedules start once the devices told their state
+            scheduler = Some(Scheduler::new(&schedules, &loops, &workers).spawn());
+            info!("Process incoming messages");
+            dispatch_incoming_message(&workers, events.as_mut(), loop_finder, &reconnect).await;
+            Ok::<(), String>(())
+        };
+
+        let outcome = tokio::select! {
+            r = processing => r,
+            _ = shutdown_signal() => {
+                info!("🛑 Shutdown requested");
+                Ok(())
+            }
+        };
+
+        ready.store(false, Ordering::SeqCst);
+        if let Some(scheduler) = scheduler {
+            scheduler.abort();
+        }
+        disconnect(client.as_ref(), events.as_mut()).await;
+        info!("🏁 End of AVA {}", &self.project_code);
+        outcome
+    }
+}
+
+fn read_prop(property_name: &str) -> Result<String, String> {
+    get_prop_value(property_name).map_err(|e| format!("Cannot find the property [{}], e=[{}]", property_name, e))
+}
+
+/// TLS transport when mqtt.tls is true, with the CA of mqtt.ca_file or the platform ones,
+/// and the client certificate of mqtt.client_cert_file / mqtt.client_key_file if any
+fn read_tls_transport() -> Result<Option<Transport>, String> {
+    if !get_prop_value("mqtt.tls").map(|v| v == "true").unwrap_or(false) {
+        return Ok(None);
+    }
+
+    let read_file = |property_name: &str| -> Result<Option<Vec<u8>>, String> {
+        match get_prop_value(property_name) {
+            Ok(path) => fs::read(&path)
+                .map(Some)
+                .map_err(|e| format!("Cannot read [{}] for [{}], e=[{}]", &path, property_name, e)),
+            Err(_) => Ok(None),
+        }
+    };
+
+    let client_auth = match (read_file("mqtt.client_cert_file")?, read_file("mqtt.client_key_file")?) {
+        (Some(cert), Some(key)) => Some((cert, key)),
+        (None, None) => None,
+        _ => return Err("mqtt.client_cert_file and mqtt.client_key_file go together".to_string()),
+    };
+
+    info!("🔒 MQTT over TLS");
+    match read_file("mqtt.ca_file")? {
+        Some(ca) => Ok(Some(Transport::tls(ca, client_auth, None))),
+        None if client_auth.is_none() => Ok(Some(Transport::tls_with_default_config())),
+        None => Err("mqtt.ca_file is needed with a client certificate".to_string()),
+    }
+}
+
+struct HealthState {
+    service: String,
+    version: String,
+    ready: Arc<AtomicBool>,
+}
+
+/// Serve GET /health on health.port if the property is set.
+/// 200 once the init stage is over and the broker is connected, 503 otherwise.
+async fn start_health(service: &str, version: &str, ready: Arc<AtomicBool>) -> Result<(), String> {
+    let port = match get_prop_value("health.port") {
+        Ok(port) => port.parse::<u16>().map_err(|e| format!("Wrong value for [health.port], e=[{}]", e))?,
+        Err(_) => return Ok(()),
+    };
+
+    let state = Arc::new(HealthState {
+        service: service.to_string(),
+        version: version.to_string(),
+        ready,
+    });
+    let app = Router::new()
+        .route("/health", get(health))
+        .with_state(state);
+
+    let addr = SocketAddr::from(([0, 0, 0, 0], port));
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .map_err(|e| format!("Cannot listen on [{}], e=[{}]", addr, e))?;
+    info!("❤️ Health endpoint on http://{}/health", addr);
+    tokio::spawn(async move {
+        if let Err(e) = axum::serve(listener, app).await {
+            error!("💀 Health endpoint stopped, e=[{}]", e);
+        }
+    });
+    Ok(())
+}
+
+async fn health(State(state): State<Arc<HealthState>>) -> (StatusCode, Json<Value>) {
+    let ready = state.ready.load(Ordering::SeqCst);
+    let connected = is_connected();
+    let status = if ready && connected { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    let body = json!({
+        "service": state.service,
+        "version": state.version,
+        "ready": ready,
+        "connected": connected,
+        "reconnections": reconnect_count(),
+    });
+    (status, Json(body))
+}
+
+/// Wait for SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        if let Err(e) = tokio::signal::ctrl_c().await {
+            error!("💀 Cannot listen to SIGINT, e=[{}]", e);
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate()) {
+            Ok(mut sig) => {
+                sig.recv().await;
+            }
+            Err(e) => {
+                error!("💀 Cannot listen to SIGTERM, e=[{}]", e);
+                std::future::pending::<()>().await;
+            }
+        }
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
+
+/// Send the queued messages and a DISCONNECT packet to the broker
+async fn disconnect(client: &dyn TransportClient, events: &mut dyn TransportEvents) {
+    if let Err(e) = client.disconnect().await {
+        warn!("Cannot request the disconnection, e=[{}]", e);
+        return;
+    }
+    let flush = async {
+        while let Ok(event) = events.poll().await {
+            if let TransportEvent::Disconnected = event {
+                break;
+            }
+        }
+    };
+    if tokio::time::timeout(SHUTDOWN_GRACE, flush).await.is_err() {
+        warn!("The disconnection did not complete within [{:?}]", SHUTDOWN_GRACE);
+    }
+}
@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Limits on the messages of a noisy device, all optional.
+/// * `debounce_ms` : a burst of messages gives one message, the last one, once the device stayed quiet for the delay
+/// * `debounce_max_ms` : the longest a burst is waited for, so a device that never stays quiet is still processed.
+///   Ten times `debounce_ms` by default.
+/// * `min_interval_ms` : the messages closer than this to the last processed one are ignored
+/// * `thresholds` : the minimal change of numeric fields, ex : `{"temperature": 0.1}`.
+///   When set, a message is processed only if one of these fields moved enough since the last processed message.
+#[derive(Debug, Clone, Default, PartialEq, Deserialize)]
+pub struct ThrottleSettings {
+    #[serde(default)]
+    pub debounce_ms: Option<u64>,
+    #[serde(default)]
+    pub debounce_max_ms: Option<u64>,
+    #[serde(default)]
+    pub min_interval_ms: Option<u64>,
+    #[serde(default)]
+    pub thresholds: HashMap<String, f64>,
+}
+
+/// "a.b" -> value["a"]["b"]
+fn read_number(value: &Value, field: &str) -> Option<f64> {
+    field.split('.').try_fold(value, |v, key| v.get(key)).and_then(Value::as_f64)
+}
+
+impl ThrottleSettings {
+    pub fn debounce(&self) -> Option<Duration> {
+        self.debounce_ms.filter(|ms| *ms > 0).map(Duration::from_millis)
+    }
+
+    /// How long to wait for the next message of a burst that started `burst_elapsed` ago.
+    /// None when there is no debounce, or once the burst reached its maximal wait.
+    pub fn debounce_wait(&self, burst_elapsed: Duration) -> Option<Duration> {
+        let debounce = self.debounce()?;
+        let max_wait = self.debounce_max_ms.map(Duration::from_millis).unwrap_or(debounce * 10);
+        let left = max_wait.checked_sub(burst_elapsed).filter(|left| !left.is_zero())?;
+        Some(debounce.min(left))
+    }
+
+    /// True if the last processed message is too recent
+    pub fn too_soon(&self, since_processed: Option<Duration>) -> bool {
+        match (self.min_interval_ms, since_processed) {
+            (Some(min_interval_ms), Some(elapsed)) => elapsed < Duration::from_millis(min_interval_ms),
+            _ => false,
+        }
+    }
+
+    pub fn has_thresholds(&self) -> bool {
+        !self.thresholds.is_empty()
+    }
+
+    /// True if a field of the thresholds moved enough, or appeared / disappeared, since the last processed message
+    pub fn is_significant(&self, last_processed: Option<&Value>, message: &Value) -> bool {
+        let Some(last) = last_processed else {
+            return true;
+        };
+        self.thresholds.iter().any(|(field, threshold)| {
+            match (read_number(last, field), read_number(message, field)) {
+                (Some(before), Some(now)) => (now - before).abs() >= *threshold,
+                (None, None) => false,
+                _ => true,
+            }
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn settings(json: Value) -> ThrottleSettings {
+        serde_json::from_value(json).unwrap()
+    }
+
+    #[test]
+    fn too_soon_needs_a_min_interval_and_a_processed_message() {
+        let throttle = settings(json!({"min_interval_ms": 1000}));
+        assert!(throttle.too_soon(Some(Duration::from_millis(999))));
+        assert!(!throttle.too_soon(Some(Duration::from_millis(1000))));
+        assert!(!throttle.too_soon(None));
+        assert!(!ThrottleSettings::default().too_soon(Some(Duration::ZERO)));
+    }
+
+    #[test]
+    fn is_significant_compares_the_fields_to_their_threshold() {
+        let throttle = settings(json!({"thresholds": {"temperature": 0.5, "power.active": 10.0}}));
+        let last = json!({"temperature": 19.0, "power": {"active": 100.0}});
+
+        assert!(!throttle.is_significant(Some(&last), &json!({"temperature": 19.4, "power": {"active": 105.0}})));
+        assert!(throttle.is_significant(Some(&last), &json!({"temperature": 18.5, "power": {"active": 100.0}})));
+        assert!(throttle.is_significant(Some(&last), &json!({"temperature": 19.0, "power": {"active": 110.0}})));
+    }
+
+    #[test]
+    fn is_significant_when_a_field_appears_or_disappears() {
+        let throttle = settings(json!({"thresholds": {"temperature": 0.5}}));
+        assert!(throttle.is_significant(Some(&json!({"humidity": 40})), &json!({"temperature": 19.0})));
+        assert!(throttle.is_significant(Some(&json!({"temperature": 19.0})), &json!({"humidity": 40})));
+        assert!(!throttle.is_significant(Some(&json!({"humidity": 40})), &json!({"humidity": 60})));
+    }
+
+    #[test]
+    fn is_significant_without_a_processed_message() {
+        let throttle = settings(json!({"thresholds": {"temperature": 0.5}}));
+        assert!(throttle.is_significant(None, &json!({"temperature": 19.0})));
+    }
+
+    #[test]
+    fn debounce_wait_stops_at_the_max_wait() {
+        let throttle = settings(json!({"debounce_ms": 200, "debounce_max_ms": 500}));
+        assert_eq!(Some(Duration::from_millis(200)), throttle.debounce_wait(Duration::ZERO));
+        assert_eq!(Some(Duration::from_millis(100)), throttle.debounce_wait(Duration::from_millis(400)));
+        assert_eq!(None, throttle.debounce_wait(Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn debounce_wait_defaults_to_ten_debounces() {
+        let throttle = settings(json!({"debounce_ms": 100}));
+        assert_eq!(Some(Duration::from_millis(100)), throttle.debounce_wait(Duration::from_millis(900)));
+        assert_eq!(None, throttle.debounce_wait(Duration::from_millis(1000)));
+        assert_eq!(None, ThrottleSettings::default().debounce_wait(Duration::ZERO));
+    }
+}
@@ -0,0 +1,101 @@
+//! MQTT topic filters for the device definitions.
+//! A `+` matches the characters of one level, a `#` matches everything after it.
+//! Unlike MQTT, they can be used inside a level, ex : `zigbee2mqtt/ts_+`.
+
+pub fn is_wildcard(filter: &str) -> bool {
+    filter.contains('+') || filter.contains('#')
+}
+
+/// True if the concrete topic matches the filter
+pub fn topic_matches(filter: &str, topic: &str) -> bool {
+    glob_match(filter.as_bytes(), topic.as_bytes())
+}
+
+fn glob_match(filter: &[u8], topic: &[u8]) -> bool {
+    match filter.split_first() {
+        None => topic.is_empty(),
+        Some((b'#', _)) => true,
+        // "a/#" also matches the parent level "a", like MQTT
+        Some((b'/', b"#")) if topic.is_empty() => true,
+        Some((b'+', rest)) => {
+            // Try every length of the current level, never crossing a '/'
+            let level_end = topic.iter().position(|c| *c == b'/').unwrap_or(topic.len());
+            (0..=level_end).any(|n| glob_match(rest, &topic[n..]))
+        }
+        Some((c, rest)) => topic.first() == Some(c) && glob_match(rest, &topic[1..]),
+    }
+}
+
+/// The filter sent to the broker : a level holding a wildcard becomes `+`, or `#` with the levels after it
+pub fn subscription_filter(filter: &str) -> String {
+    let mut levels = vec![];
+    for level in filter.split('/') {
+        if level.contains('#') {
+            levels.push("#");
+            break;
+        }
+        levels.push(if level.contains('+') { "+" } else { level });
+    }
+    levels.join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn plain_filters_match_the_same_topic_only() {
+        assert!(!is_wildcard("zigbee2mqtt/hall_lamp"));
+        assert!(topic_matches("zigbee2mqtt/hall_lamp", "zigbee2mqtt/hall_lamp"));
+        assert!(!topic_matches("zigbee2mqtt/hall_lamp", "zigbee2mqtt/hall_lamp/set"));
+        assert!(!topic_matches("zigbee2mqtt/hall_lamp", "zigbee2mqtt/hall"));
+    }
+
+    #[test]
+    fn plus_matches_one_level() {
+        assert!(topic_matches("zigbee2mqtt/+", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("+/ts_salon", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("zigbee2mqtt/+/set", "zigbee2mqtt/lamp/set"));
+        assert!(!topic_matches("zigbee2mqtt/+", "zigbee2mqtt/ts_salon/set"));
+        assert!(!topic_matches("zigbee2mqtt/+", "zigbee2mqtt"));
+        // An empty level, like MQTT
+        assert!(topic_matches("zigbee2mqtt/+", "zigbee2mqtt/"));
+    }
+
+    #[test]
+    fn plus_inside_a_level() {
+        assert!(is_wildcard("zigbee2mqtt/ts_+"));
+        assert!(topic_matches("zigbee2mqtt/ts_+", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("zigbee2mqtt/ts_+", "zigbee2mqtt/ts_"));
+        assert!(topic_matches("zigbee2mqtt/+_lamp", "zigbee2mqtt/hall_lamp"));
+        assert!(!topic_matches("zigbee2mqtt/ts_+", "zigbee2mqtt/lamp"));
+        assert!(!topic_matches("zigbee2mqtt/ts_+", "zigbee2mqtt/ts_salon/availability"));
+        assert!(!topic_matches("zigbee2mqtt/+_lamp", "zigbee2mqtt/hall_lamp_2"));
+    }
+
+    #[test]
+    fn hash_matches_the_levels_after_it() {
+        assert!(topic_matches("#", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("zigbee2mqtt/#", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("zigbee2mqtt/#", "zigbee2mqtt/ts_salon/availability"));
+        assert!(topic_matches("zigbee2mqtt/+/#", "zigbee2mqtt/ts_salon/availability"));
+        assert!(!topic_matches("zigbee2mqtt/#", "external/rad_salon"));
+    }
+
+    #[test]
+    fn hash_matches_the_parent_level() {
+        assert!(topic_matches("zigbee2mqtt/#", "zigbee2mqtt"));
+        assert!(!topic_matches("zigbee2mqtt/#", "zigbee2mqtt2"));
+        assert!(topic_matches("zigbee2mqtt/+/#", "zigbee2mqtt/ts_salon"));
+        assert!(topic_matches("zigbee2mqtt/ts_salon/#", "zigbee2mqtt/ts_salon/"));
+    }
+
+    #[test]
+    fn subscription_filters_use_whole_levels() {
+        assert_eq!("zigbee2mqtt/hall_lamp", subscription_filter("zigbee2mqtt/hall_lamp"));
+        assert_eq!("zigbee2mqtt/+", subscription_filter("zigbee2mqtt/ts_+"));
+        assert_eq!("+/ts_salon", subscription_filter("+/ts_salon"));
+        assert_eq!("zigbee2mqtt/#", subscription_filter("zigbee2mqtt/ts_#"));
+        assert_eq!("zigbee2mqtt/#", subscription_filter("zigbee2mqtt/#/ignored"));
+    }
+}
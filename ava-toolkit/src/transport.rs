@@ -0,0 +1,156 @@
+//! The MQTT side of the toolkit : a client to publish and subscribe, and the events it receives.
+//! `rumqttc_transport` talks to a broker, `loopback::Loopback` is an in-process broker,
+//! for the tests and for several services in one process.
+//! The QoS is always "at least once", as for all the AVA services.
+
+use std::future::Future;
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use log::{debug, warn};
+use rumqttc::v5::mqttbytes::v5::{Filter, PublishProperties};
+use rumqttc::v5::mqttbytes::QoS;
+use rumqttc::v5::{AsyncClient, Event, EventLoop, Incoming, MqttOptions};
+use rumqttc::Outgoing;
+
+/// The client shared by the processing tasks, the scheduler and the module watcher
+pub type SharedClient = Arc<dyn TransportClient>;
+
+#[derive(Debug, Clone)]
+pub enum TransportEvent {
+    /// A message on a subscribed topic, the payload is read as UTF-8, the wrong bytes replaced
+    Publish { topic: String, payload: String, properties: Option<PublishProperties> },
+    /// The connection to the broker is up, or back
+    Connected,
+    /// The broker received a message published by the client
+    Acknowledged,
+    /// The disconnection asked by `TransportClient::disconnect` is done
+    Disconnected,
+    /// Nothing to act on : acks, pings, ...
+    Other,
+}
+
+#[async_trait]
+pub trait TransportClient: Send + Sync {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, properties: PublishProperties) -> Result<(), String>;
+
+    async fn subscribe(&self, filter: &str) -> Result<(), String>;
+
+    /// Subscribe to all the filters, in one request if the transport can
+    async fn subscribe_many(&self, filters: &[String]) -> Result<(), String> {
+        for filter in filters {
+            self.subscribe(filter).await?;
+        }
+        Ok(())
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), String>;
+
+    async fn disconnect(&self) -> Result<(), String>;
+}
+
+#[async_trait]
+pub trait TransportEvents: Send {
+    /// The next event. An error is a lost connection, the next call tries to connect again.
+    async fn poll(&mut self) -> Result<TransportEvent, String>;
+}
+
+/// Wait between two polls when the connection is down, while a request waits
+const POLL_RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Wait for a request of the client while the events are polled and given to `on_event`.
+/// The rumqttc requests wait in a bounded channel that only the poll drains,
+/// so a request awaited without polling may never complete.
+pub(crate) async fn while_polling<R, H, F>(request: R, events: &mut dyn TransportEvents, mut on_event: H) -> R::Output
+where
+    R: Future,
+    H: FnMut(TransportEvent) -> F,
+    F: Future<Output = ()>,
+{
+    tokio::pin!(request);
+    loop {
+        tokio::select! {
+            biased;
+            output = &mut request => return output,
+            polled = events.poll() => match polled {
+                Ok(event) => on_event(event).await,
+                Err(e) => {
+                    warn!("🔌 Connection error while a request waits, e=[{}]", e);
+                    tokio::time::sleep(POLL_RETRY_DELAY).await;
+                }
+            },
+        }
+    }
+}
+
+/// The client and the events of a broker connection, opened on the first poll
+pub fn rumqttc_transport(options: MqttOptions, cap: usize) -> (SharedClient, RumqttcEvents) {
+    let (client, eventloop) = AsyncClient::new(options, cap);
+    (Arc::new(RumqttcClient { client }), RumqttcEvents { eventloop })
+}
+
+pub struct RumqttcClient {
+    client: AsyncClient,
+}
+
+#[async_trait]
+impl TransportClient for RumqttcClient {
+    async fn publish(&self, topic: &str, payload: Vec<u8>, properties: PublishProperties) -> Result<(), String> {
+        self.client
+            .publish_with_properties(topic, QoS::AtLeastOnce, false, payload, properties)
+            .await
+            .map_err(|e| e.to_string())
+    }
+
+    async fn subscribe(&self, filter: &str) -> Result<(), String> {
+        self.client.subscribe(filter, QoS::AtLeastOnce).await.map_err(|e| e.to_string())
+    }
+
+    async fn subscribe_many(&self, filters: &[String]) -> Result<(), String> {
+        let filters: Vec<Filter> = filters.iter()
+            .map(|filter| Filter::new(filter.clone(), QoS::AtLeastOnce))
+            .collect();
+        self.client.subscribe_many(filters).await.map_err(|e| e.to_string())
+    }
+
+    async fn unsubscribe(&self, filter: &str) -> Result<(), String> {
+        self.client.unsubscribe(filter).await.map_err(|e| e.to_string())
+    }
+
+    async fn disconnect(&self) -> Result<(), String> {
+        self.client.disconnect().await.map_err(|e| e.to_string())
+    }
+}
+
+pub struct RumqttcEvents {
+    eventloop: EventLoop,
+}
+
+#[async_trait]
+impl TransportEvents for RumqttcEvents {
+    async fn poll(&mut self) -> Result<TransportEvent, String> {
+        let event = self.eventloop.poll().await.map_err(|e| e.to_string())?;
+        let transport_event = match event {
+            Event::Incoming(Incoming::Publish(publish)) => TransportEvent::Publish {
+                topic: String::from_utf8_lossy(&publish.topic).to_string(),
+                payload: String::from_utf8_lossy(&publish.payload).to_string(),
+                properties: publish.properties,
+            },
+            Event::Incoming(Incoming::ConnAck(connack)) => {
+                debug!("ConnAck ({:?})", &connack);
+                TransportEvent::Connected
+            }
+            Event::Incoming(Incoming::PubAck(pub_ack)) => {
+                debug!("PubAck ({:?})", &pub_ack);
+                TransportEvent::Acknowledged
+            }
+            Event::Outgoing(Outgoing::Disconnect) => TransportEvent::Disconnected,
+            other => {
+                debug!("Event ({:?})", &other);
+                TransportEvent::Other
+            }
+        };
+        Ok(transport_event)
+    }
+}
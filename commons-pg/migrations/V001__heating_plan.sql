@@ -0,0 +1,10 @@
+-- Heating plans, the regulation map holds the target temperature of each zone (tc_bureau, tc_salon_1, ...)
+CREATE TABLE IF NOT EXISTS public.heating_plan (
+    id bigserial NOT NULL PRIMARY KEY,
+    starting_time time NOT NULL,
+    ending_time time NOT NULL,
+    end_the_next_day bool NOT NULL DEFAULT false,
+    boost bool NOT NULL DEFAULT false,
+    regulation_map json NOT NULL,
+    ts_created timestamp NOT NULL DEFAULT timezone('UTC', now())
+);
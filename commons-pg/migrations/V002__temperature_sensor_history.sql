@@ -0,0 +1,13 @@
+-- Every temperature reading, written by event-storage and radiator-api
+CREATE TABLE IF NOT EXISTS public.temperature_sensor_history (
+    id bigserial NOT NULL PRIMARY KEY,
+    device_name varchar(255) NOT NULL,
+    temperature float8 NOT NULL,
+    ts_create timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS temperature_sensor_history_device_ts_idx
+    ON public.temperature_sensor_history (device_name, ts_create);
+
+CREATE INDEX IF NOT EXISTS temperature_sensor_history_ts_idx
+    ON public.temperature_sensor_history (ts_create);
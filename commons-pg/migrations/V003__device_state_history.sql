@@ -0,0 +1,10 @@
+-- Json state of the devices (radiator modes, ...), written by event-storage and radiator-api
+CREATE TABLE IF NOT EXISTS public.device_state_history (
+    id bigserial NOT NULL PRIMARY KEY,
+    device_name varchar(255) NOT NULL,
+    state text NOT NULL,
+    ts_create timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS device_state_history_device_ts_idx
+    ON public.device_state_history (device_name, ts_create);
@@ -0,0 +1,13 @@
+-- Raw messages of the devices flagged with "journal" in the module config
+CREATE TABLE IF NOT EXISTS public.event_journal (
+    id bigserial NOT NULL PRIMARY KEY,
+    topic varchar(255) NOT NULL,
+    family varchar(100) NOT NULL,
+    device_name varchar(255) NOT NULL,
+    payload jsonb NOT NULL,
+    properties jsonb NOT NULL,
+    ts_received timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS event_journal_device_ts_idx
+    ON public.event_journal (device_name, ts_received);
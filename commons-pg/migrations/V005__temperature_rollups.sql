@@ -0,0 +1,20 @@
+-- Temperature aggregates built by the retention job of event-storage
+CREATE TABLE IF NOT EXISTS public.temperature_rollup_5m (
+    device_name varchar(255) NOT NULL,
+    bucket_start timestamp NOT NULL,
+    min_temperature float8 NOT NULL,
+    max_temperature float8 NOT NULL,
+    avg_temperature float8 NOT NULL,
+    sample_count int8 NOT NULL,
+    PRIMARY KEY (device_name, bucket_start)
+);
+
+CREATE TABLE IF NOT EXISTS public.temperature_rollup_1h (
+    device_name varchar(255) NOT NULL,
+    bucket_start timestamp NOT NULL,
+    min_temperature float8 NOT NULL,
+    max_temperature float8 NOT NULL,
+    avg_temperature float8 NOT NULL,
+    sample_count int8 NOT NULL,
+    PRIMARY KEY (device_name, bucket_start)
+);
@@ -0,0 +1,2 @@
+-- Correlation ID of the message that caused the state, see ava-toolkit/src/correlation.rs
+ALTER TABLE public.device_state_history ADD COLUMN IF NOT EXISTS correlation_id varchar(64) NULL;
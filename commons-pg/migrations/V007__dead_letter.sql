@@ -0,0 +1,13 @@
+-- Device messages the services could not read, republished on their dead-letter topic and stored by event-storage
+CREATE TABLE IF NOT EXISTS public.dead_letter (
+    id bigserial NOT NULL PRIMARY KEY,
+    service varchar(100) NOT NULL,
+    topic varchar(255) NOT NULL,
+    payload text NOT NULL,
+    error text NOT NULL,
+    correlation_id varchar(64) NULL,
+    ts_received timestamp NOT NULL
+);
+
+CREATE INDEX IF NOT EXISTS dead_letter_ts_idx
+    ON public.dead_letter (ts_received);
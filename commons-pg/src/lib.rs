@@ -1,2 +1,3 @@
+pub mod migration;
 pub mod sql_transaction;
 pub mod sql_transaction2;
@@ -1,7 +1,7 @@
 
 use std::collections::HashMap;
 use std::sync::OnceLock;
-use std::time::{Duration, UNIX_EPOCH};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 use anyhow::anyhow;
 use chrono::{NaiveDateTime, NaiveTime};
@@ -17,12 +17,15 @@ use sqlx::postgres::{PgArguments, PgPoolOptions};
 
 use commons_error::*;
 
+use crate::migration::check_schema;
 use crate::sql_transaction::{CellValue, naive_datetime_to_system_time, SQLDataSet};
 
 lazy_static! {
     static ref SQL_POOL2: OnceLock<SQLPool2> = OnceLock::new();
 }
 
+/// Create the global pool, once the schema of the database is checked.
+/// The pool is not set when the check fails, so a new call checks again.
 pub async fn init_db_pool2(connect_string: &str, pool_size: u32) -> anyhow::Result<()> {
     if SQL_POOL2.get().is_none() {
         let pool = match SQLPool2::new(connect_string, pool_size)
@@ -32,6 +35,14 @@ pub async fn init_db_pool2(connect_string: &str, pool_size: u32) -> anyhow::Resu
             Ok(p) => p,
             Err(_) => return Err(anyhow!("_")),
         };
+
+        // Refuse to work on a database that misses some migrations
+        let mut cnx = SQLConnection2 { client: pool.pick_connection().await? };
+        check_schema(&mut cnx)
+            .await
+            .map_err(err_fwd!("Schema check failed"))?;
+        drop(cnx);
+
         match SQL_POOL2.set(pool) {
             Ok(_) => {}
             Err(_) => return Err(anyhow!("Impossible to set the pool")),
@@ -40,6 +51,35 @@ pub async fn init_db_pool2(connect_string: &str, pool_size: u32) -> anyhow::Resu
     Ok(())
 }
 
+/// Check the schema on a connection of its own, for the services that do not use the pool
+pub async fn check_db_schema(connect_string: &str) -> anyhow::Result<()> {
+    let mut cnx = SQLConnection2::new(connect_string).await.map_err(err_fwd!("Cannot connect to the database"))?;
+    check_schema(&mut cnx)
+        .await
+        .map_err(err_fwd!("Schema check failed"))
+}
+
+/// True if the error comes from the connection or the pool, not from the statement or its data :
+/// the same statement may succeed later. The errors that are not from sqlx are counted as connection errors.
+/// SQLSTATE classes 08 (connection), 53 (resources), 57P (shutdown) and 40 (rollback) are connection errors.
+pub fn is_connection_error(e: &anyhow::Error) -> bool {
+    let Some(sqlx_error) = e.chain().find_map(|cause| cause.downcast_ref::<sqlx::Error>()) else {
+        return true;
+    };
+    match sqlx_error {
+        sqlx::Error::Io(_)
+        | sqlx::Error::Tls(_)
+        | sqlx::Error::Protocol(_)
+        | sqlx::Error::PoolTimedOut
+        | sqlx::Error::PoolClosed
+        | sqlx::Error::WorkerCrashed => true,
+        sqlx::Error::Database(db_error) => db_error.code().is_some_and(|code| {
+            code.starts_with("08") || code.starts_with("53") || code.starts_with("57P") || code.starts_with("40")
+        }),
+        _ => false,
+    }
+}
+
 /// Analyse the template query with named params and compare it to the list of input parameters.
 /// Return the actual Sql query with $ parameters and an ordered list of usable parameter.
 pub(crate) fn parse_query2<'a>(
@@ -170,20 +210,7 @@ fn bind_cell_to_query<'q>(
         CellValue::Date(value) => query_builder.bind(value),
         // TODO implement CellValue::DateTime(valeue: NaiveDateTime)
         CellValue::SystemTime(value) => {
-            let opt_naive_datetime = match value {
-                None => None,
-                Some(sys_datetime) => {
-                    // Obtenir la durée écoulée depuis l'époque UNIX
-                    let duration_since_epoch = sys_datetime.duration_since(UNIX_EPOCH).unwrap();
-                    // Convertir la durée en secondes
-                    let seconds = duration_since_epoch.as_secs();
-                    // Convertir les secondes en NaiveDateTime
-                    NaiveDateTime::from_timestamp_opt(
-                        seconds as i64,
-                        duration_since_epoch.subsec_nanos(),
-                    )
-                }
-            };
+            let opt_naive_datetime = value.and_then(system_time_to_naive_datetime);
             query_builder.bind(opt_naive_datetime)
         }
         CellValue::Time(value) => query_builder.bind(value),
@@ -191,6 +218,15 @@ fn bind_cell_to_query<'q>(
     }
 }
 
+fn system_time_to_naive_datetime(sys_datetime: SystemTime) -> Option<NaiveDateTime> {
+    // Obtenir la durée écoulée depuis l'époque UNIX
+    let duration_since_epoch = sys_datetime.duration_since(UNIX_EPOCH).unwrap();
+    // Convertir la durée en secondes
+    let seconds = duration_since_epoch.as_secs();
+    // Convertir les secondes en NaiveDateTime
+    NaiveDateTime::from_timestamp_opt(seconds as i64, duration_since_epoch.subsec_nanos())
+}
+
 impl SQLQueryBlock2 {
     /// Main routine to perform a select query
     pub async fn execute(
@@ -398,6 +434,233 @@ impl SQLChange2 {
     }
 }
 
+/// Maximum number of bind parameters in a single PostgreSQL statement
+const PG_MAX_BIND_PARAMS: usize = 65535;
+
+/// How the batch writer sends its rows to the database
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BatchMode {
+    /// Multi-row INSERT ... VALUES (..), (..)
+    Insert,
+    /// COPY ... FROM STDIN in text format, the fastest for big batches
+    Copy,
+}
+
+/// Flush thresholds of a batch, the first one reached wins
+#[derive(Debug, Clone, Copy)]
+pub struct FlushPolicy {
+    pub max_rows: usize,
+    pub max_delay: Duration,
+}
+
+impl Default for FlushPolicy {
+    fn default() -> Self {
+        Self {
+            max_rows: 500,
+            max_delay: Duration::from_secs(1),
+        }
+    }
+}
+
+impl FlushPolicy {
+    /// `oldest` is the time the first pending row was pushed
+    pub fn is_due(&self, pending_rows: usize, oldest: Option<Instant>) -> bool {
+        match oldest {
+            None => false,
+            Some(t) => pending_rows >= self.max_rows || t.elapsed() >= self.max_delay,
+        }
+    }
+}
+
+/// Accumulate rows for one table and write them in a single round-trip.
+/// Each row is a map from column name to value, every column of the writer must be in the row.
+#[derive(Debug)]
+pub struct SQLBatchWriter2 {
+    pub table: String,
+    pub columns: Vec<String>,
+    pub mode: BatchMode,
+    pub policy: FlushPolicy,
+    rows: Vec<HashMap<String, CellValue>>,
+    oldest: Option<Instant>,
+}
+
+impl SQLBatchWriter2 {
+    pub fn new(table: &str, columns: &[&str], mode: BatchMode) -> Self {
+        Self {
+            table: table.to_string(),
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            mode,
+            policy: FlushPolicy::default(),
+            rows: vec![],
+            oldest: None,
+        }
+    }
+
+    pub fn with_policy(mut self, policy: FlushPolicy) -> Self {
+        self.policy = policy;
+        self
+    }
+
+    pub fn push(&mut self, row: HashMap<String, CellValue>) {
+        if self.oldest.is_none() {
+            self.oldest = Some(Instant::now());
+        }
+        self.rows.push(row);
+    }
+
+    pub fn len(&self) -> usize {
+        self.rows.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rows.is_empty()
+    }
+
+    /// True when the size or the time threshold is reached
+    pub fn is_due(&self) -> bool {
+        self.policy.is_due(self.rows.len(), self.oldest)
+    }
+
+    /// Write the pending rows only if a threshold is reached
+    pub async fn flush_if_due(
+        &mut self,
+        sql_transaction: &mut SQLTransaction2<'_>,
+    ) -> anyhow::Result<u64> {
+        if !self.is_due() {
+            return Ok(0);
+        }
+        self.flush(sql_transaction).await
+    }
+
+    /// Write all the pending rows and return the number of rows written.
+    /// The rows are kept if the write fails, so the caller can retry on a new transaction.
+    pub async fn flush(&mut self, sql_transaction: &mut SQLTransaction2<'_>) -> anyhow::Result<u64> {
+        if self.rows.is_empty() {
+            return Ok(0);
+        }
+        let count = match self.mode {
+            BatchMode::Insert => self.write_insert(sql_transaction).await?,
+            BatchMode::Copy => self.write_copy(sql_transaction).await?,
+        };
+        log_debug!("Batch of [{}] row(s) written in [{}]", count, &self.table);
+        self.rows.clear();
+        self.oldest = None;
+        Ok(count)
+    }
+
+    async fn write_insert(&self, sql_transaction: &mut SQLTransaction2<'_>) -> anyhow::Result<u64> {
+        let rows_per_statement = (PG_MAX_BIND_PARAMS / self.columns.len().max(1)).max(1);
+        let mut count = 0;
+        for chunk in self.rows.chunks(rows_per_statement) {
+            let sql = build_multi_row_insert(&self.table, &self.columns, chunk.len());
+            let mut query_builder = sqlx::query(sql.as_str());
+            for row in chunk {
+                for column in &self.columns {
+                    let cell = row_cell(row, column)?;
+                    query_builder = bind_cell_to_query(cell.clone(), query_builder);
+                }
+            }
+            let r = query_builder
+                .execute(&mut *sql_transaction.inner_transaction)
+                .await
+                .map_err(err_fwd!("Batch insert failed, table [{}], [{}] row(s)", &self.table, chunk.len()))?;
+            count += r.rows_affected();
+        }
+        Ok(count)
+    }
+
+    async fn write_copy(&self, sql_transaction: &mut SQLTransaction2<'_>) -> anyhow::Result<u64> {
+        let mut data = String::new();
+        for row in &self.rows {
+            let mut fields = vec![];
+            for column in &self.columns {
+                fields.push(cell_to_copy_text(row_cell(row, column)?));
+            }
+            data.push_str(&fields.join("\t"));
+            data.push('\n');
+        }
+
+        let statement = format!("COPY {} ({}) FROM STDIN", &self.table, self.columns.join(", "));
+        let mut copy_in = sql_transaction
+            .inner_transaction
+            .copy_in_raw(&statement)
+            .await
+            .map_err(err_fwd!("Cannot start the copy, [{}]", &statement))?;
+        if let Err(e) = copy_in.send(data.into_bytes()).await {
+            log_error!("Copy failed, table [{}], e=[{}]", &self.table, e);
+            let _ = copy_in.abort("Send failed").await;
+            return Err(anyhow::Error::from(e).context(format!("Copy failed, table [{}]", &self.table)));
+        }
+        let count = copy_in
+            .finish()
+            .await
+            .map_err(err_fwd!("Copy failed, table [{}], [{}] row(s)", &self.table, self.rows.len()))?;
+        Ok(count)
+    }
+}
+
+fn row_cell<'a>(row: &'a HashMap<String, CellValue>, column: &str) -> anyhow::Result<&'a CellValue> {
+    row.get(column).ok_or_else(|| {
+        anyhow::Error::from(sqlx::Error::ColumnNotFound(column.to_string()))
+            .context(format!("Missing column [{}] in the batch row", column))
+    })
+}
+
+/// INSERT INTO table (a, b) VALUES ($1, $2), ($3, $4)
+fn build_multi_row_insert(table: &str, columns: &[String], row_count: usize) -> String {
+    let mut counter = 1;
+    let mut values = vec![];
+    for _ in 0..row_count {
+        let placeholders: Vec<String> = columns
+            .iter()
+            .map(|_| {
+                let p = format!("${}", counter);
+                counter += 1;
+                p
+            })
+            .collect();
+        values.push(format!("({})", placeholders.join(", ")));
+    }
+    format!(
+        "INSERT INTO {} ({}) VALUES {}",
+        table,
+        columns.join(", "),
+        values.join(", ")
+    )
+}
+
+/// Text format of the COPY command, \N is the null value
+fn cell_to_copy_text(cell: &CellValue) -> String {
+    const NULL: &str = "\\N";
+    let text = match cell {
+        CellValue::String(v) => v.clone(),
+        CellValue::Bool(v) => v.map(|b| if b { "t" } else { "f" }.to_string()),
+        CellValue::Int(v) => v.map(|i| i.to_string()),
+        CellValue::Int32(v) => v.map(|i| i.to_string()),
+        CellValue::Int16(v) => v.map(|i| i.to_string()),
+        CellValue::Double(v) => v.map(|f| match f {
+            f if f.is_nan() => "NaN".to_string(),
+            f if f == f64::INFINITY => "Infinity".to_string(),
+            f if f == f64::NEG_INFINITY => "-Infinity".to_string(),
+            f => f.to_string(),
+        }),
+        CellValue::Date(v) => v.map(|d| d.format("%Y-%m-%d").to_string()),
+        CellValue::Time(v) => v.map(|t| t.format("%H:%M:%S%.f").to_string()),
+        CellValue::SystemTime(v) => v
+            .and_then(system_time_to_naive_datetime)
+            .map(|dt| dt.format("%Y-%m-%d %H:%M:%S%.f").to_string()),
+        CellValue::Json(v) => v.as_ref().map(|j| j.to_string()),
+    };
+    match text {
+        None => NULL.to_string(),
+        Some(t) => t
+            .replace('\\', "\\\\")
+            .replace('\t', "\\t")
+            .replace('\n', "\\n")
+            .replace('\r', "\\r"),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
@@ -413,7 +676,8 @@ mod tests {
     use tokio::task::JoinHandle;
 
     use crate::sql_transaction2::{
-        init_db_pool2, SQLChange2, SQLConnection2, SQLQueryBlock2, SQLTransaction2,
+        build_multi_row_insert, cell_to_copy_text, init_db_pool2, is_connection_error, row_cell, BatchMode, FlushPolicy,
+        SQLBatchWriter2, SQLChange2, SQLConnection2, SQLQueryBlock2, SQLTransaction2,
     };
     use crate::sql_transaction::CellValue;
 
@@ -861,4 +1125,120 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn b10_multi_row_insert_sql() {
+        let columns = vec!["id".to_string(), "title".to_string()];
+        let sql = build_multi_row_insert("public.book", &columns, 3);
+        assert_eq!(
+            "INSERT INTO public.book (id, title) VALUES ($1, $2), ($3, $4), ($5, $6)",
+            sql
+        );
+    }
+
+    #[test]
+    fn b11_copy_text_format() {
+        assert_eq!("\\N", cell_to_copy_text(&CellValue::from_opt_str(None)));
+        assert_eq!(
+            "Tab\\there\\nand a \\\\",
+            cell_to_copy_text(&CellValue::from_raw_str("Tab\there\nand a \\"))
+        );
+        assert_eq!("t", cell_to_copy_text(&CellValue::from_raw_bool(true)));
+        assert_eq!("19.5", cell_to_copy_text(&CellValue::from_raw_double(19.5)));
+        assert_eq!(
+            "2024-08-15",
+            cell_to_copy_text(&CellValue::from_raw_naivedate(NaiveDate::from_ymd_opt(2024, 8, 15).unwrap()))
+        );
+        assert_eq!(
+            "1970-01-01 00:00:01.500",
+            cell_to_copy_text(&CellValue::from_raw_systemtime(
+                SystemTime::UNIX_EPOCH + Duration::from_millis(1500)
+            ))
+        );
+    }
+
+    #[test]
+    fn b12_flush_policy() {
+        let mut writer = SQLBatchWriter2::new("public.book", &["id", "title"], BatchMode::Insert)
+            .with_policy(FlushPolicy {
+                max_rows: 2,
+                max_delay: Duration::from_secs(3600),
+            });
+        assert!(!writer.is_due());
+        writer.push(book_row(1, "Dune"));
+        assert!(!writer.is_due());
+        writer.push(book_row(2, "Hyperion"));
+        assert!(writer.is_due());
+
+        let policy = FlushPolicy {
+            max_rows: 100,
+            max_delay: Duration::from_millis(0),
+        };
+        assert!(!policy.is_due(0, None));
+        assert!(policy.is_due(1, Some(std::time::Instant::now())));
+    }
+
+    #[test]
+    fn b13_connection_errors() {
+        assert!(is_connection_error(&anyhow::Error::from(sqlx::Error::PoolTimedOut)));
+        assert!(is_connection_error(&anyhow::Error::from(sqlx::Error::Io(
+            std::io::Error::from(std::io::ErrorKind::ConnectionRefused)
+        ))));
+        assert!(is_connection_error(&anyhow::anyhow!("Cannot read the pool")));
+        assert!(!is_connection_error(&anyhow::Error::from(sqlx::Error::ColumnNotFound("title".to_string()))));
+        assert!(!is_connection_error(
+            &anyhow::Error::from(sqlx::Error::RowNotFound).context("Batch insert failed")
+        ));
+        // A missing column is a data error, never spooled
+        let row = book_row(1, "Dune");
+        let e = row_cell(&row, "author").unwrap_err();
+        assert!(!is_connection_error(&e));
+    }
+
+    fn book_row(id: i32, title: &str) -> HashMap<String, CellValue> {
+        let mut row = HashMap::new();
+        row.insert("id".to_owned(), CellValue::from_raw_int_32(id));
+        row.insert("title".to_owned(), CellValue::from_raw_str(title));
+        row.insert(
+            "precision_time".to_owned(),
+            CellValue::from_raw_systemtime(SystemTime::now()),
+        );
+        row
+    }
+
+    /// Write the same rows with a multi-row insert and with a copy
+    #[tokio::test]
+    async fn b20_batch_insert_and_copy() -> anyhow::Result<()> {
+        init_pool_once().await;
+
+        let mut cnx = SQLConnection2::from_pool().await?;
+        let mut trans = cnx.begin().await?;
+
+        let columns = ["id", "title", "precision_time"];
+        let mut insert_writer = SQLBatchWriter2::new("public.book", &columns, BatchMode::Insert);
+        let mut copy_writer = SQLBatchWriter2::new("public.book", &columns, BatchMode::Copy);
+        for i in 0..3 {
+            insert_writer.push(book_row(9000 + i, "Batch\tbook"));
+            copy_writer.push(book_row(9100 + i, "Batch\tbook"));
+        }
+
+        assert_eq!(3, insert_writer.flush(&mut trans).await?);
+        assert_eq!(3, copy_writer.flush(&mut trans).await?);
+        assert!(insert_writer.is_empty());
+        assert!(copy_writer.is_empty());
+
+        let mut params = HashMap::new();
+        params.insert("p_title".to_owned(), CellValue::from_raw_str("Batch\tbook"));
+        let query = SQLQueryBlock2 {
+            sql_query: "SELECT id FROM public.book WHERE title = :p_title AND id >= 9000".to_string(),
+            start: 0,
+            length: None,
+            params,
+        };
+        let sql_result = query.execute(&mut trans).await?;
+        trans.rollback().await;
+
+        assert_eq!(6, sql_result.len());
+        Ok(())
+    }
 }
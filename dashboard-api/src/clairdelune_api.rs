@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use anyhow::anyhow;
 use ava_toolkit::device_message::RegulationMapMsg;
-use chrono::{DateTime, NaiveDateTime, Utc};
+use chrono::{DateTime, Duration, NaiveDateTime, Utc};
 use commons_error::*;
 use commons_pg::sql_transaction::CellValue;
 use commons_pg::sql_transaction2::{SQLConnection2, SQLQueryBlock2};
@@ -72,6 +72,10 @@ pub struct TemperatureReading {
     sensor_name: String,
     temperature: f64,
     measured_at: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    min_temperature: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    max_temperature: Option<f64>,
 }
 
 #[derive(Serialize)]
@@ -89,6 +93,7 @@ pub struct RoomTemperatureByModeResponse {
     room: String,
     start_date_time: String,
     end_date_time: String,
+    resolution: String,
     section_count: usize,
     sections: Vec<ModeTemperatureSection>,
 }
@@ -127,6 +132,70 @@ struct TemperatureHistoryRow {
     temperature: f64,
     ts_create: String,
     ts_ms: f64,
+    min_temperature: Option<f64>,
+    max_temperature: Option<f64>,
+}
+
+/// Ages after which event-storage deletes the temperatures, from the same `retention.*` properties
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TemperatureRetention {
+    pub raw_max_age: Duration,
+    pub rollup_5m_max_age: Duration,
+}
+
+impl TemperatureRetention {
+    pub fn from_days(raw_days: i64, rollup_5m_days: i64) -> Self {
+        Self {
+            raw_max_age: Duration::days(raw_days),
+            rollup_5m_max_age: Duration::days(rollup_5m_days),
+        }
+    }
+}
+
+/// Source of the temperatures, the longer the span the coarser the data.
+/// The rollup tables are filled by the retention job of event-storage.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum TemperatureResolution {
+    Raw,
+    FiveMinutes,
+    Hourly,
+}
+
+impl TemperatureResolution {
+    fn for_span(span: Duration) -> Self {
+        if span <= Duration::days(2) {
+            TemperatureResolution::Raw
+        } else if span <= Duration::days(14) {
+            TemperatureResolution::FiveMinutes
+        } else {
+            TemperatureResolution::Hourly
+        }
+    }
+
+    /// The finest data still kept at `start`, the rows older than the retention are gone
+    fn for_age(start: DateTime<Utc>, now: DateTime<Utc>, retention: &TemperatureRetention) -> Self {
+        let age = now - start;
+        if age > retention.rollup_5m_max_age {
+            TemperatureResolution::Hourly
+        } else if age > retention.raw_max_age {
+            TemperatureResolution::FiveMinutes
+        } else {
+            TemperatureResolution::Raw
+        }
+    }
+
+    /// Coarse enough for the span, and still kept for the start of the range
+    fn for_range(start: DateTime<Utc>, end: DateTime<Utc>, now: DateTime<Utc>, retention: &TemperatureRetention) -> Self {
+        Self::for_span(end - start).max(Self::for_age(start, now, retention))
+    }
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            TemperatureResolution::Raw => "raw",
+            TemperatureResolution::FiveMinutes => "5m",
+            TemperatureResolution::Hourly => "1h",
+        }
+    }
 }
 
 const HEATING_PLAN_SQL: &str = r"
@@ -292,6 +361,8 @@ fn readings_for_section(
             sensor_name: row.device_name.clone(),
             temperature: row.temperature,
             measured_at: row.ts_create.clone(),
+            min_temperature: row.min_temperature,
+            max_temperature: row.max_temperature,
         })
         .collect()
 }
@@ -357,16 +428,38 @@ fn to_sections(
     Ok(sections)
 }
 
-fn build_temperature_history_query(devices: &[&str]) -> (String, HashMap<String, CellValue>) {
-    let mut sql = String::from(
-        "SELECT device_name, temperature, \
-         to_char(ts_create, 'YYYY-MM-DD\"T\"HH24:MI:SS.MS') AS ts_create, \
-         EXTRACT(EPOCH FROM ts_create) * 1000 AS ts_ms \
-         FROM temperature_sensor_history \
-         WHERE ts_create >= :p_start_datetime::timestamp \
-           AND ts_create <= :p_end_datetime::timestamp \
-           AND device_name IN (",
-    );
+fn build_temperature_history_query(
+    devices: &[&str],
+    resolution: TemperatureResolution,
+) -> (String, HashMap<String, CellValue>) {
+    let mut sql = match resolution {
+        TemperatureResolution::Raw => String::from(
+            "SELECT device_name, temperature, \
+             to_char(ts_create, 'YYYY-MM-DD\"T\"HH24:MI:SS.MS') AS ts_create, \
+             EXTRACT(EPOCH FROM ts_create) * 1000 AS ts_ms \
+             FROM temperature_sensor_history \
+             WHERE ts_create >= :p_start_datetime::timestamp \
+               AND ts_create <= :p_end_datetime::timestamp \
+               AND device_name IN (",
+        ),
+        TemperatureResolution::FiveMinutes | TemperatureResolution::Hourly => {
+            let table = if resolution == TemperatureResolution::FiveMinutes {
+                "temperature_rollup_5m"
+            } else {
+                "temperature_rollup_1h"
+            };
+            format!(
+                "SELECT device_name, avg_temperature AS temperature, min_temperature, max_temperature, \
+                 to_char(bucket_start, 'YYYY-MM-DD\"T\"HH24:MI:SS.MS') AS ts_create, \
+                 EXTRACT(EPOCH FROM bucket_start) * 1000 AS ts_ms \
+                 FROM {} \
+                 WHERE bucket_start >= :p_start_datetime::timestamp \
+                   AND bucket_start <= :p_end_datetime::timestamp \
+                   AND device_name IN (",
+                table
+            )
+        }
+    };
 
     let mut params = HashMap::new();
     params.insert(
@@ -495,6 +588,7 @@ pub async fn get_heating_plan_by_room() -> anyhow::Result<HeatingPlanByRoomRespo
 
 pub async fn get_room_temperature_by_mode(
     query_params: &RoomTemperatureByModeQuery,
+    retention: &TemperatureRetention,
 ) -> anyhow::Result<RoomTemperatureByModeResponse> {
     let config = room_config(&query_params.room).ok_or(anyhow!(
         "Unknown room. Expected one of: bureau, chambre, salon, couloir."
@@ -554,8 +648,9 @@ pub async fn get_room_temperature_by_mode(
         });
     }
 
+    let resolution = TemperatureResolution::for_range(start_dt, end_dt, Utc::now(), retention);
     let (temperature_sql, mut temperature_params) =
-        build_temperature_history_query(config.temperature_devices);
+        build_temperature_history_query(config.temperature_devices, resolution);
     temperature_params.insert(
         "p_start_datetime".to_string(),
         CellValue::from_raw_string(query_params.start_date_time.clone()),
@@ -591,6 +686,8 @@ pub async fn get_room_temperature_by_mode(
             ts_ms: temperature_sql_result
                 .get_double("ts_ms")
                 .ok_or(anyhow!("Wrong ts_ms"))?,
+            min_temperature: temperature_sql_result.get_double("min_temperature"),
+            max_temperature: temperature_sql_result.get_double("max_temperature"),
         });
     }
 
@@ -607,7 +704,64 @@ pub async fn get_room_temperature_by_mode(
         room: config.room.to_string(),
         start_date_time: query_params.start_date_time.clone(),
         end_date_time: query_params.end_date_time.clone(),
+        resolution: resolution.as_str().to_string(),
         section_count: sections.len(),
         sections,
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn at(raw: &str) -> DateTime<Utc> {
+        parse_iso_datetime(raw).unwrap()
+    }
+
+    #[test]
+    fn resolution_for_span() {
+        assert_eq!(TemperatureResolution::Raw, TemperatureResolution::for_span(Duration::hours(6)));
+        assert_eq!(TemperatureResolution::Raw, TemperatureResolution::for_span(Duration::days(2)));
+        assert_eq!(TemperatureResolution::FiveMinutes, TemperatureResolution::for_span(Duration::days(2) + Duration::seconds(1)));
+        assert_eq!(TemperatureResolution::FiveMinutes, TemperatureResolution::for_span(Duration::days(14)));
+        assert_eq!(TemperatureResolution::Hourly, TemperatureResolution::for_span(Duration::days(15)));
+    }
+
+    #[test]
+    fn resolution_at_the_retention_cut_off() {
+        let retention = TemperatureRetention::from_days(30, 90);
+        let now = at("2024-12-31T12:00:00");
+
+        let raw_cut_off = now - Duration::days(30);
+        assert_eq!(TemperatureResolution::Raw, TemperatureResolution::for_age(raw_cut_off, now, &retention));
+        assert_eq!(
+            TemperatureResolution::FiveMinutes,
+            TemperatureResolution::for_age(raw_cut_off - Duration::seconds(1), now, &retention)
+        );
+
+        let rollup_cut_off = now - Duration::days(90);
+        assert_eq!(TemperatureResolution::FiveMinutes, TemperatureResolution::for_age(rollup_cut_off, now, &retention));
+        assert_eq!(
+            TemperatureResolution::Hourly,
+            TemperatureResolution::for_age(rollup_cut_off - Duration::seconds(1), now, &retention)
+        );
+    }
+
+    #[test]
+    fn short_range_older_than_the_raw_retention_reads_the_rollups() {
+        let retention = TemperatureRetention::from_days(30, 90);
+        let now = at("2024-12-31T12:00:00");
+
+        // One day, two months ago
+        let resolution = TemperatureResolution::for_range(at("2024-10-30T00:00:00"), at("2024-10-31T00:00:00"), now, &retention);
+        assert_eq!(TemperatureResolution::FiveMinutes, resolution);
+
+        // One day, yesterday
+        let resolution = TemperatureResolution::for_range(at("2024-12-30T00:00:00"), at("2024-12-31T00:00:00"), now, &retention);
+        assert_eq!(TemperatureResolution::Raw, resolution);
+
+        // A long recent range keeps the coarse data of its span
+        let resolution = TemperatureResolution::for_range(at("2024-12-01T00:00:00"), at("2024-12-31T00:00:00"), now, &retention);
+        assert_eq!(TemperatureResolution::Hourly, resolution);
+    }
+}
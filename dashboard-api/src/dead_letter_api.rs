@@ -0,0 +1,110 @@
+use std::collections::HashMap;
+
+use anyhow::anyhow;
+use commons_error::*;
+use commons_pg::sql_transaction::CellValue;
+use commons_pg::sql_transaction2::{SQLConnection2, SQLQueryBlock2};
+use serde_derive::{Deserialize, Serialize};
+
+const DEFAULT_LIMIT: u32 = 50;
+const MAX_LIMIT: u32 = 1000;
+
+const DEAD_LETTER_SQL: &str = r#"
+SELECT
+    id,
+    service,
+    topic,
+    payload,
+    error,
+    correlation_id,
+    to_char(ts_received, 'YYYY-MM-DD"T"HH24:MI:SS.MS') AS ts_received
+FROM dead_letter
+WHERE (:p_service = '' OR service = :p_service)
+  AND (:p_topic = '' OR topic = :p_topic)
+ORDER BY ts_received DESC, id DESC"#;
+
+#[derive(Debug, Deserialize)]
+pub struct DeadLetterQuery {
+    pub service: Option<String>,
+    pub topic: Option<String>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct DeadLetterItem {
+    id: i64,
+    service: String,
+    topic: String,
+    payload: String,
+    error: String,
+    correlation_id: Option<String>,
+    ts_received: String,
+}
+
+#[derive(Serialize)]
+pub struct DeadLetterResponse {
+    count: usize,
+    items: Vec<DeadLetterItem>,
+}
+
+/// The last dead letters stored by event-storage, the most recent first
+pub async fn get_dead_letters(
+    query_params: &DeadLetterQuery,
+) -> anyhow::Result<DeadLetterResponse> {
+    let limit = query_params.limit.unwrap_or(DEFAULT_LIMIT).min(MAX_LIMIT);
+
+    let mut params = HashMap::new();
+    params.insert(
+        "p_service".to_string(),
+        CellValue::from_raw_string(query_params.service.clone().unwrap_or_default()),
+    );
+    params.insert(
+        "p_topic".to_string(),
+        CellValue::from_raw_string(query_params.topic.clone().unwrap_or_default()),
+    );
+
+    let mut cnx = SQLConnection2::from_pool().await.map_err(tr_fwd!())?;
+    let mut trans = cnx.begin().await.map_err(tr_fwd!())?;
+    let query = SQLQueryBlock2 {
+        sql_query: DEAD_LETTER_SQL.to_string(),
+        start: 0,
+        length: Some(limit),
+        params,
+    };
+
+    let mut sql_result = query
+        .execute(&mut trans)
+        .await
+        .map_err(err_fwd!("Dead letter query failed"))?;
+    let mut items = Vec::new();
+
+    while sql_result.next() {
+        items.push(DeadLetterItem {
+            id: sql_result.get_int("id").ok_or(anyhow!("Wrong id"))?,
+            service: sql_result
+                .get_string("service")
+                .ok_or(anyhow!("Wrong service"))?,
+            topic: sql_result
+                .get_string("topic")
+                .ok_or(anyhow!("Wrong topic"))?,
+            payload: sql_result
+                .get_string("payload")
+                .ok_or(anyhow!("Wrong payload"))?,
+            error: sql_result
+                .get_string("error")
+                .ok_or(anyhow!("Wrong error"))?,
+            correlation_id: sql_result.get_string("correlation_id"),
+            ts_received: sql_result
+                .get_string("ts_received")
+                .ok_or(anyhow!("Wrong ts_received"))?,
+        });
+    }
+
+    trans.commit().await?;
+
+    Ok(DeadLetterResponse {
+        count: items.len(),
+        items,
+    })
+}
@@ -19,12 +19,14 @@ use tower_http::cors::{Any, CorsLayer};
 
 use crate::clairdelune_api::{
     get_heating_plan, get_heating_plan_by_room, get_room_temperature_by_mode,
-    RoomTemperatureByModeQuery,
+    RoomTemperatureByModeQuery, TemperatureRetention,
 };
 use crate::dao::get_current_regulation_map;
+use crate::dead_letter_api::{get_dead_letters, DeadLetterQuery};
 use crate::dao_db::RadiatorStatus;
 use commons_error::*;
 use commons_pg::sql_transaction2::init_db_pool2;
+use ava_toolkit::metrics::start_metrics;
 use conf_reader::*;
 
 use crate::dao_db::build_current_temp_context;
@@ -33,6 +35,7 @@ mod clairdelune_api;
 mod conf_reader;
 mod dao;
 mod dao_db;
+mod dead_letter_api;
 
 // PROPERTIES must be locked when on write, but not locked on read actions
 // It contains a double map { 0 : { "server.port" : 30040, "app.secret-folder" : "/secret", .... },... }
@@ -91,7 +94,7 @@ async fn heating_plan_by_room() -> Result<Json<serde_json::Value>, (StatusCode,
 async fn room_temperature_by_mode(
     Query(params): Query<RoomTemperatureByModeQuery>,
 ) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
-    let payload = get_room_temperature_by_mode(&params).await.map_err(|e| {
+    let payload = get_room_temperature_by_mode(&params, &temperature_retention()).await.map_err(|e| {
         let message = e.to_string();
         let status = if message.contains("Unknown room")
             || message.contains("valid ISO")
@@ -115,6 +118,24 @@ async fn room_temperature_by_mode(
     })?))
 }
 
+async fn dead_letters(
+    Query(params): Query<DeadLetterQuery>,
+) -> Result<Json<serde_json::Value>, (StatusCode, String)> {
+    let payload = get_dead_letters(&params).await.map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to load dead_letters from PostgreSQL: {}", e),
+        )
+    })?;
+
+    Ok(Json(serde_json::to_value(payload).map_err(|e| {
+        (
+            StatusCode::INTERNAL_SERVER_ERROR,
+            format!("Unable to serialize dead_letters response: {}", e),
+        )
+    })?))
+}
+
 async fn index2_radiator(
     AxumPath(room): AxumPath<String>,
     Json(payload): Json<RadiatorStatus>,
@@ -197,6 +218,14 @@ fn get_optional_prop_value(prop_name: &str) -> Option<String> {
         .cloned()
 }
 
+/// Same properties and defaults as the retention job of event-storage
+fn temperature_retention() -> TemperatureRetention {
+    let days = |prop_name: &str, default: i64| {
+        get_optional_prop_value(prop_name).and_then(|v| v.parse().ok()).unwrap_or(default)
+    };
+    TemperatureRetention::from_days(days("retention.raw_days", 30), days("retention.rollup_5m_days", 90))
+}
+
 async fn build_dashboard_context() -> HashMap<String, String> {
     let mut context = match build_current_temp_context().await {
         Ok(c) => c,
@@ -243,6 +272,8 @@ async fn main() {
     );
 
     let props = read_config(PROJECT_CODE, VAR_NAME);
+    // The toolkit reads its own properties (metrics.port) from common-config
+    common_config::properties::set_prop_values(props.clone());
     set_props(props);
     let port = get_prop_value("server.port").parse::<u16>().unwrap();
     let log_config: String = get_prop_value("log4rs.config");
@@ -271,7 +302,15 @@ async fn main() {
 
     log_info!("Connnect String : [{}]", &connect_string);
 
-    let _r = init_db_pool2(&connect_string, db_pool_size).await;
+    if let Err(e) = init_db_pool2(&connect_string, db_pool_size).await {
+        log_error!("Cannot init the database pool, e=[{:?}]", e);
+        exit(-64);
+    }
+
+    if let Err(e) = start_metrics().await {
+        log_error!("💀 {}", e);
+        exit(-67);
+    }
 
     log_info!("🚀 Start {} on port {}", PROGRAM_NAME, port);
 
@@ -292,6 +331,7 @@ async fn main() {
         .route("/heating_plan", get(heating_plan))
         .route("/heating_plan_by_room", get(heating_plan_by_room))
         .route("/room_temperature_by_mode", get(room_temperature_by_mode))
+        .route("/dead_letters", get(dead_letters))
         .layer(cors);
 
     let app = Router::new().nest(&base_url, key_routes);
@@ -0,0 +1,97 @@
+use std::sync::{LazyLock, OnceLock};
+use std::time::Instant;
+
+use anyhow::anyhow;
+use commons_pg::sql_transaction2::FlushPolicy;
+use log::info;
+use prometheus::{register_histogram, Histogram};
+use tokio::sync::Mutex;
+
+use crate::spool::SpooledEvent;
+
+static BATCH: OnceLock<EventBatch> = OnceLock::new();
+
+static BATCH_ROWS: LazyLock<Histogram> = LazyLock::new(|| {
+    register_histogram!("ava_event_batch_rows", "Events written by a batch", vec![1.0, 5.0, 10.0, 25.0, 50.0, 100.0, 250.0, 500.0])
+        .expect("Cannot register ava_event_batch_rows")
+});
+
+#[derive(Debug, Default)]
+struct PendingEvents {
+    events: Vec<SpooledEvent>,
+    oldest: Option<Instant>,
+}
+
+/// Events waiting to be written together, flushed when the size or the delay of the policy is reached
+#[derive(Debug)]
+pub(crate) struct EventBatch {
+    policy: FlushPolicy,
+    pending: Mutex<PendingEvents>,
+}
+
+/// Register the global batch, `store` writes every event on its own if it's never called
+pub(crate) fn init_batch(policy: FlushPolicy) -> anyhow::Result<&'static EventBatch> {
+    let batch = EventBatch {
+        policy,
+        pending: Mutex::new(PendingEvents::default()),
+    };
+    BATCH.set(batch).map_err(|_| anyhow!("Impossible to set the event batch"))?;
+    info!("📦 Event batch ready, max_rows=[{}], max_delay=[{:?}]", policy.max_rows, policy.max_delay);
+    BATCH.get().ok_or_else(|| anyhow!("Cannot read the event batch"))
+}
+
+/// Queue the event in the batch, or store it right away if there is no batch
+pub(crate) async fn store(event: SpooledEvent) -> anyhow::Result<()> {
+    match BATCH.get() {
+        Some(batch) => batch.push(event).await,
+        None => crate::spool::store(vec![event]).await,
+    }
+}
+
+/// Write the pending events whatever their age, before the process stops
+pub(crate) async fn flush_batch() -> anyhow::Result<()> {
+    match BATCH.get() {
+        Some(batch) => batch.flush().await,
+        None => Ok(()),
+    }
+}
+
+impl EventBatch {
+    /// Add the event, the batch is written as soon as it's full
+    pub(crate) async fn push(&self, event: SpooledEvent) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        if pending.oldest.is_none() {
+            pending.oldest = Some(Instant::now());
+        }
+        pending.events.push(event);
+        self.write_if_due(&mut pending).await
+    }
+
+    /// Write the pending events if the delay of the oldest one is over
+    pub(crate) async fn flush_if_due(&self) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        self.write_if_due(&mut pending).await
+    }
+
+    pub(crate) async fn flush(&self) -> anyhow::Result<()> {
+        let mut pending = self.pending.lock().await;
+        if pending.events.is_empty() {
+            return Ok(());
+        }
+        pending.oldest = None;
+        let events = std::mem::take(&mut pending.events);
+        BATCH_ROWS.observe(events.len() as f64);
+        crate::spool::store(events).await
+    }
+
+    /// The lock is held during the write, so the batches reach the database in order
+    async fn write_if_due(&self, pending: &mut PendingEvents) -> anyhow::Result<()> {
+        if !self.policy.is_due(pending.events.len(), pending.oldest) {
+            return Ok(());
+        }
+        pending.oldest = None;
+        let events = std::mem::take(&mut pending.events);
+        BATCH_ROWS.observe(events.len() as f64);
+        crate::spool::store(events).await
+    }
+}
@@ -0,0 +1,98 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+
+use commons_error::*;
+use commons_pg::sql_transaction::CellValue;
+use commons_pg::sql_transaction2::{BatchMode, SQLBatchWriter2, SQLConnection2};
+use ava_toolkit::dead_letter::DeadLetter;
+use ava_toolkit::journal::JournalEntry;
+
+use crate::spool::SpooledEvent;
+
+const TEMPERATURE_TABLE: &str = "public.temperature_sensor_history";
+const TEMPERATURE_COLUMNS: [&str; 3] = ["device_name", "temperature", "ts_create"];
+
+const DEVICE_STATE_TABLE: &str = "public.device_state_history";
+const DEVICE_STATE_COLUMNS: [&str; 4] = ["device_name", "state", "ts_create", "correlation_id"];
+
+/// See commons-pg/migrations/V004__event_journal.sql
+const EVENT_JOURNAL_TABLE: &str = "public.event_journal";
+const EVENT_JOURNAL_COLUMNS: [&str; 6] = ["topic", "family", "device_name", "payload", "properties", "ts_received"];
+
+/// See commons-pg/migrations/V007__dead_letter.sql
+const DEAD_LETTER_TABLE: &str = "public.dead_letter";
+const DEAD_LETTER_COLUMNS: [&str; 6] = ["service", "topic", "payload", "error", "correlation_id", "ts_received"];
+
+/// Store the events in their tables, with one COPY per table in a single transaction.
+/// One refused row fails the whole batch, the spool then retries the events one by one.
+pub(crate) async fn store_events(events: Vec<SpooledEvent>) -> anyhow::Result<()> {
+    let mut temperatures = SQLBatchWriter2::new(TEMPERATURE_TABLE, &TEMPERATURE_COLUMNS, BatchMode::Copy);
+    let mut device_states = SQLBatchWriter2::new(DEVICE_STATE_TABLE, &DEVICE_STATE_COLUMNS, BatchMode::Copy);
+    let mut journal = SQLBatchWriter2::new(EVENT_JOURNAL_TABLE, &EVENT_JOURNAL_COLUMNS, BatchMode::Copy);
+    let mut dead_letters = SQLBatchWriter2::new(DEAD_LETTER_TABLE, &DEAD_LETTER_COLUMNS, BatchMode::Copy);
+
+    for event in events {
+        match event {
+            SpooledEvent::Temperature { device_name, temperature, ts_create } => {
+                temperatures.push(temperature_row(&device_name, temperature, ts_create));
+            }
+            SpooledEvent::DeviceState { device_name, state, ts_create, correlation_id } => {
+                device_states.push(device_state_row(&device_name, &state, ts_create, correlation_id.as_deref()));
+            }
+            SpooledEvent::Journal(entry) => journal.push(event_journal_row(&entry)),
+            SpooledEvent::DeadLetter(letter) => dead_letters.push(dead_letter_row(&letter)),
+        }
+    }
+
+    let mut cnx = SQLConnection2::from_pool().await.map_err(tr_fwd!())?;
+    let mut trans = cnx.begin().await.map_err(tr_fwd!())?;
+    for writer in [&mut temperatures, &mut device_states, &mut journal, &mut dead_letters] {
+        writer.flush(&mut trans).await.map_err(err_fwd!("💣 Batch write failed, [{}]", &writer.table))?;
+    }
+    trans.commit().await.map_err(tr_fwd!())?;
+    Ok(())
+}
+
+/// One temperature reading for the device behind the topic
+fn temperature_row(device_name: &str, temperature: f64, ts_create: DateTime<Utc>) -> HashMap<String, CellValue> {
+    let mut row = HashMap::new();
+    row.insert("device_name".to_owned(), CellValue::from_raw_str(device_name));
+    row.insert("temperature".to_owned(), CellValue::from_raw_double(temperature));
+    row.insert("ts_create".to_owned(), CellValue::from_raw_systemtime(ts_create.into()));
+    row
+}
+
+/// The json state of the device behind the topic
+fn device_state_row(device_name: &str, json_state: &str, ts_create: DateTime<Utc>, correlation_id: Option<&str>) -> HashMap<String, CellValue> {
+    let mut row = HashMap::new();
+    row.insert("device_name".to_owned(), CellValue::from_raw_str(device_name));
+    row.insert("state".to_owned(), CellValue::from_raw_str(json_state));
+    row.insert("ts_create".to_owned(), CellValue::from_raw_systemtime(ts_create.into()));
+    row.insert("correlation_id".to_owned(), CellValue::from_opt_str(correlation_id));
+    row
+}
+
+/// The raw message for the generic journal
+fn event_journal_row(entry: &JournalEntry) -> HashMap<String, CellValue> {
+    let mut row = HashMap::new();
+    row.insert("topic".to_owned(), CellValue::from_raw_str(&entry.topic));
+    row.insert("family".to_owned(), CellValue::from_raw_str(&entry.family));
+    row.insert("device_name".to_owned(), CellValue::from_raw_str(&entry.device_name));
+    row.insert("payload".to_owned(), CellValue::from_raw_json(entry.payload.clone()));
+    row.insert("properties".to_owned(), CellValue::from_raw_json(entry.properties.clone()));
+    row.insert("ts_received".to_owned(), CellValue::from_raw_systemtime(entry.received_at.into()));
+    row
+}
+
+/// A message another service could not read
+fn dead_letter_row(letter: &DeadLetter) -> HashMap<String, CellValue> {
+    let mut row = HashMap::new();
+    row.insert("service".to_owned(), CellValue::from_raw_str(&letter.service));
+    row.insert("topic".to_owned(), CellValue::from_raw_str(&letter.topic));
+    row.insert("payload".to_owned(), CellValue::from_raw_str(&letter.payload));
+    row.insert("error".to_owned(), CellValue::from_raw_str(&letter.error));
+    row.insert("correlation_id".to_owned(), CellValue::from_opt_str(letter.correlation_id.as_deref()));
+    row.insert("ts_received".to_owned(), CellValue::from_raw_systemtime(letter.ts_received.into()));
+    row
+}
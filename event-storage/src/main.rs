@@ -1,28 +1,118 @@
-use ava_toolkit::domotic_factory::DomoticFactory;
 use log::*;
-use rumqttc::v5::mqttbytes::QoS;
-use rumqttc::v5::{AsyncClient, MqttOptions};
-use std::env;
+use std::path::Path;
+use std::process::exit;
 use std::time::Duration;
 
 use crate::message_enum::MessageEnum;
-use ava_toolkit::hard_loop::HardLoop;
-use ava_toolkit::init_loop::process_initialization_message;
-use ava_toolkit::processing::process_incoming_message;
-use common_config::conf_reader::{read_config, read_env};
-use common_config::properties::{get_prop_value, set_prop_values};
-
+use ava_toolkit::service::AvaService;
+use common_config::properties::{get_prop_pg_connect_string, get_prop_value};
+use commons_error::*;
+use commons_pg::sql_transaction2::{init_db_pool2, FlushPolicy};
+use tokio::time::interval;
+
+use crate::batch::{flush_batch, init_batch};
+use crate::dao::store_events;
+use crate::retention::{run_retention, RetentionPolicy};
+use crate::spool::init_spool;
+
+mod batch;
+mod dao;
 mod message_enum;
+mod retention;
+mod spool;
+
+fn read_props_or_default(property_name: &str, default_value: u64) -> u64 {
+    match get_prop_value(property_name) {
+        Ok(value) => value.parse::<u64>().unwrap_or_else(|e| {
+            error!("Wrong value for [{}], e=[{}]", property_name, e);
+            default_value
+        }),
+        Err(_) => default_value,
+    }
+}
+
+/// Group the events before writing them, flush the batch in the background when its delay is over
+fn start_batch() -> FlushPolicy {
+    let policy = FlushPolicy {
+        max_rows: read_props_or_default("batch.max_rows", 500) as usize,
+        max_delay: Duration::from_millis(read_props_or_default("batch.max_delay_ms", 1000)),
+    };
 
-fn read_props_or_die(property_name: &str) -> String {
-    let value = match get_prop_value(property_name) {
-        Ok(file) => file,
+    let batch = match init_batch(policy) {
+        Ok(b) => b,
         Err(e) => {
-            error!("{}", e);
-            panic!("Cannot find the property")
+            log_error!("Cannot init the event batch, e=[{:?}]", e);
+            exit(-66);
         }
     };
-    value
+
+    tokio::spawn(async move {
+        let mut interval = interval((policy.max_delay / 4).max(Duration::from_millis(10)));
+        loop {
+            interval.tick().await;
+            if let Err(e) = batch.flush_if_due().await {
+                error!("💣 Cannot write the event batch, e=[{}]", e);
+            }
+        }
+    });
+    policy
+}
+
+/// Open the disk spool if the `spool.dir` property is set and drain it in the background
+fn start_spool(batch_rows: usize) {
+    let spool_dir = match get_prop_value("spool.dir") {
+        Ok(dir) => dir,
+        Err(_) => {
+            warn!("No spool.dir property, events will be lost while the database is unreachable");
+            return;
+        }
+    };
+    let max_bytes = read_props_or_default("spool.max_bytes", 100 * 1024 * 1024);
+    let segment_bytes = read_props_or_default("spool.segment_bytes", 1024 * 1024);
+    let drain_interval = read_props_or_default("spool.drain_interval", 10);
+
+    let spool = match init_spool(Path::new(&spool_dir), max_bytes, segment_bytes) {
+        Ok(s) => s,
+        Err(e) => {
+            log_error!("Cannot open the spool in [{}], e=[{:?}]", &spool_dir, e);
+            exit(-65);
+        }
+    };
+
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(drain_interval));
+        loop {
+            interval.tick().await;
+            if spool.depth() > 0 {
+                info!("📦 Spool depth: [{}] event(s), [{}] bytes, [{}] dropped, [{}] rejected, [{}] corrupted",
+                    spool.depth(), spool.bytes(), spool.dropped(), spool.rejected(), spool.corrupted());
+                let drained = spool.drain(batch_rows, store_events).await;
+                if drained > 0 {
+                    info!("📦 Drained [{}] event(s) from the spool, [{}] left", drained, spool.depth());
+                }
+            }
+        }
+    });
+}
+
+/// Build the temperature rollups and delete the old rows every `retention.interval` seconds
+fn start_retention() {
+    const DAY: u64 = 24 * 3600;
+    let policy = RetentionPolicy {
+        raw_max_age: Duration::from_secs(read_props_or_default("retention.raw_days", 30) * DAY),
+        rollup_5m_max_age: Duration::from_secs(read_props_or_default("retention.rollup_5m_days", 90) * DAY),
+    };
+    let retention_interval = read_props_or_default("retention.interval", 300);
+
+    tokio::spawn(async move {
+        let mut interval = interval(Duration::from_secs(retention_interval));
+        loop {
+            interval.tick().await;
+            if let Err(e) = run_retention(&policy).await {
+                error!("💣 Retention job failed, e=[{}]", e);
+            }
+        }
+    });
 }
 
 /// Accept parameters from the command line
@@ -32,75 +122,39 @@ fn read_props_or_die(property_name: &str) -> String {
 /// By default, the program will look for the .doka-config.json file in the user's base folder
 #[tokio::main]
 async fn main() {
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
-
-    info!("Starting AVA event-storage 0.5.0");
-
-    const PROJECT_CODE: &str = "event-storage";
-    const VAR_NAME: &str = "AVA_ENV";
-
-    let o_config_file = read_env(&VAR_NAME);
-
-    // Read the application config's file
-    println!(
-        "😎 Config file using PROJECT_CODE={} VAR_NAME={}",
-        PROJECT_CODE, VAR_NAME
-    );
-
-    let props = read_config(
-        PROJECT_CODE,
-        &o_config_file,
-        &Some("AVA_CLUSTER_PROFILE".to_string()),
-    );
-    set_prop_values(props);
-
-    let factory_message_dir = read_props_or_die("factory.dir");
-    let module_file = read_props_or_die("module");
-    let mqtt_port = read_props_or_die("mqtt.port").parse::<u16>().unwrap(); // TODO
-    let mqtt_user = read_props_or_die("mqtt.user");
-    let mqtt_password = read_props_or_die("mqtt.password");
-    let mqtt_host = read_props_or_die("mqtt.host");
-
-    let mut domo_factory: DomoticFactory<MessageEnum> =
-        DomoticFactory::new(module_file, factory_message_dir);
-    domo_factory.build_devices();
-
-    let all_loops = domo_factory.build_loops();
-    let init_list = domo_factory.devices_to_init();
-    let device_to_listen = domo_factory.devices_to_listen();
-
-    let args: Vec<String> = vec![];
-    let channels = DomoticFactory::extract_channel_from_devices(&device_to_listen, &mqtt_host);
-
-    let mut mqttoptions = MqttOptions::new(&channels.client_id, &channels.server_addr, mqtt_port);
-    mqttoptions.set_keep_alive(Duration::from_secs(channels.keep_alive as u64));
-    mqttoptions.set_clean_start(true);
-    mqttoptions.set_credentials(mqtt_user, mqtt_password);
-
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
-
-    for p in &channels.channel_filters {
-        info!("Subscribe to [{}]", p.0);
-        client
-            .subscribe(p.0.clone(), QoS::AtLeastOnce)
-            .await
-            .unwrap();
+    let service = AvaService::<MessageEnum>::builder("event-storage")
+        .version("0.5.0")
+        .build();
+
+    // Init DB pool
+    let (connect_string, db_pool_size) = match get_prop_pg_connect_string()
+        .map_err(err_fwd!("Cannot read the database connection information"))
+    {
+        Ok(x) => x,
+        Err(e) => {
+            log_error!("{:?}", e);
+            exit(-64);
+        }
+    };
+
+    if let Err(e) = init_db_pool2(&connect_string, db_pool_size).await {
+        log_error!("Cannot init the database pool, e=[{:?}]", e);
+        exit(-64);
     }
 
-    let loop_finder = |topic: &str| HardLoop::find_loops(topic, &all_loops);
+    let policy = start_batch();
+    start_spool(policy.max_rows);
+    start_retention();
 
-    match process_initialization_message(&mut client, &mut eventloop, &init_list).await {
-        Ok(_) => {
-            info!("Process incoming messages");
-            let _ = process_incoming_message(&mut client, &mut eventloop, &args, loop_finder).await;
-        }
-        Err(e) => {
-            panic!("{}", e);
-        }
+    let outcome = service.run().await;
+
+    // Write the events still in the batch before leaving
+    if let Err(e) = flush_batch().await {
+        log_error!("💣 Cannot write the last event batch, e=[{:?}]", e);
+    }
+
+    if let Err(e) = outcome {
+        log_error!("💀 {}", e);
+        exit(-67);
     }
-    println!("Done!");
 }
@@ -1,16 +1,30 @@
 use std::collections::HashMap;
-use log::info;
+use log::{error, info};
 use serde_derive::{Deserialize, Serialize};
 
+use ava_toolkit::correlation::current_correlation_id;
+use ava_toolkit::dead_letter::DeadLetter as DeadLetterMsg;
 use ava_toolkit::device_message::{RegulatorRadiatorMsg, TempSensorMsg};
+use ava_toolkit::dynamic_message::DynamicMessage;
 use ava_toolkit::generic_device::Locality;
-use crate::message_enum::MessageEnum::{Radiator, TempSensor};
+use ava_toolkit::journal::JournalEntry;
+use chrono::Utc;
+use crate::batch::store;
+use crate::spool::SpooledEvent;
+use crate::message_enum::MessageEnum::{DeadLetter, Radiator, Raw, TempSensor};
 
 /// Object by enums
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Locality)]
+#[locality(journal)]
 pub (crate) enum MessageEnum {
     TempSensor(TempSensorMsg),
-    Radiator(RegulatorRadiatorMsg)
+    Radiator(RegulatorRadiatorMsg),
+    /// Any json message, for the devices only recorded in the journal (motion sensors, switches, ...).
+    /// The payloads are checked by `<message_type>.schema.json` when the factory folder has one.
+    #[locality(dynamic)]
+    Raw(DynamicMessage),
+    /// The messages the other services could not read, from their dead-letter topic (ex: `ava/dlq/+`)
+    DeadLetter(DeadLetterMsg),
 }
 
 impl MessageEnum {
@@ -24,60 +38,28 @@ impl MessageEnum {
         self.clone()
     }
 
-}
-
-
-impl Locality for MessageEnum {
-   fn query_for_state(&self) -> String {
-        match self {
-            TempSensor(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-            Radiator(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-        }
+    fn to_raw(&self, _last_message: &MessageEnum) -> Self {
+        self.clone()
     }
 
-    fn find_set_topic(&self, topic: &str) -> String {
-        topic.to_string()
-    }
-    
-    fn raw_message(&self) -> String {
-        match self {
-            TempSensor(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO handle error
-            }
-            Radiator(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO handle error
-            }
-        }
+    fn to_dead_letter(&self, _last_message: &MessageEnum) -> Self {
+        self.clone()
     }
+
     /// Convert the original message to the type of the current Self
     fn to_local(&self, original_message: &MessageEnum, last_message: &MessageEnum) -> Self {
         match self {
             TempSensor(_) => {
-                original_message.to_temp_sensor(&last_message)
+                original_message.to_temp_sensor(last_message)
             }
             Radiator(_) => {
-                original_message.to_radiator(&last_message)
+                original_message.to_radiator(last_message)
             }
-        }
-    }
-
-    fn to_local_with_data(&self,original_message: &Self, last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>) -> Self {
-        self.to_local(original_message, last_message)
-    }
-    
-    fn json_to_local(&self, json_msg: &str) -> Result<MessageEnum, String> {
-        match self {
-            TempSensor(_) => {
-                Ok(TempSensor(TempSensorMsg::from_json(json_msg)?))
+            Raw(_) => {
+                original_message.to_raw(last_message)
             }
-            Radiator(_) => {
-                Ok(Radiator(RegulatorRadiatorMsg::from_json(json_msg)?))
+            DeadLetter(_) => {
+                original_message.to_dead_letter(last_message)
             }
         }
     }
@@ -88,19 +70,18 @@ impl Locality for MessageEnum {
         match self {
             TempSensor(msg) => {
                 info!("Default process for TempSensor, message=[{:?}]", msg);
-                insert_temp(&topic, &msg).await;
+                insert_temp(topic, msg).await;
             }
-            // MoveSensor(msg) => {
-            //     info!("Default process for MoveSensor, message=[{:?}]", msg);
-            //     db_put_device_state(&topic, &json_msg).await;
-            // }
-            // BasicSwitch(msg) => {
-            //     info!("Default process for BasicSwitch, message=[{:?}]", msg);
-            //     db_put_device_state(&topic, &json_msg).await;
-            // }
             Radiator(msg) => {
                 info!("Default process for Radiator, message=[{:?}]", msg);
-                db_put_device_state(&topic, &json_msg).await;
+                db_put_device_state(topic, &json_msg).await;
+            }
+            Raw(msg) => {
+                info!("No typed table for [{}], message=[{}]", topic, &msg.value);
+            }
+            DeadLetter(letter) => {
+                info!("Default process for DeadLetter, message=[{:?}]", letter);
+                db_put_dead_letter(letter).await;
             }
         }
     }
@@ -108,15 +89,47 @@ impl Locality for MessageEnum {
     async fn compute(&self) -> Option<HashMap<String, f64>> {
         None
     }
-    
+
+    async fn journal(&self, entry: &JournalEntry) {
+        match store(SpooledEvent::Journal(entry.clone())).await {
+            Ok(_) => info!("📝 Queued journal event for [{}]", &entry.topic),
+            Err(e) => error!("💣 Cannot record the journal event for [{}], e=[{}]", &entry.topic, e),
+        }
+    }
+
 }
 
 /// Insère les données de l'état du périphérique dans la base de données
-pub (crate) async fn db_put_device_state(_topic: &str, _json_msg: &str) {
-    panic!("Please, grab the db information from the config file !!");
+pub (crate) async fn db_put_device_state(topic: &str, json_msg: &str) {
+    let event = SpooledEvent::DeviceState {
+        device_name: topic.to_string(),
+        state: json_msg.to_string(),
+        ts_create: Utc::now(),
+        correlation_id: current_correlation_id(),
+    };
+    match store(event).await {
+        Ok(_) => info!("📝 Queued device state for [{}]", topic),
+        Err(e) => error!("💣 Cannot store the device state for [{}], e=[{}]", topic, e),
+    }
+}
+
+/// Enregistre un message illisible d'un autre service
+pub (crate) async fn db_put_dead_letter(letter: &DeadLetterMsg) {
+    match store(SpooledEvent::DeadLetter(letter.clone())).await {
+        Ok(_) => info!("📮 Queued dead letter of [{}] for [{}]", &letter.service, &letter.topic),
+        Err(e) => error!("💣 Cannot store the dead letter of [{}], e=[{}]", &letter.topic, e),
+    }
 }
 
 /// Insère les données de température dans la base de données
-pub (crate) async fn insert_temp(_topic: &str, _temp: &TempSensorMsg) {
-    panic!("Please, grab the db information from the config file !!");
+pub (crate) async fn insert_temp(topic: &str, temp: &TempSensorMsg) {
+    let event = SpooledEvent::Temperature {
+        device_name: topic.to_string(),
+        temperature: temp.temperature as f64,
+        ts_create: Utc::now(),
+    };
+    match store(event).await {
+        Ok(_) => info!("📝 Queued temperature [{}] for [{}]", temp.temperature, topic),
+        Err(e) => error!("💣 Cannot store the temperature for [{}], e=[{}]", topic, e),
+    }
 }
@@ -0,0 +1,121 @@
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime};
+
+use commons_error::*;
+use commons_pg::sql_transaction::CellValue;
+use commons_pg::sql_transaction2::{SQLChange2, SQLConnection2};
+use log::info;
+
+/// Rollups are recomputed on this window at each run, to catch the late events (spool drain).
+/// The window goes back to the last hourly bucket if it's older (first run, service down for days)
+/// and starts on a full hour, so no bucket is rebuilt from a part of its rows.
+const ROLLUP_LOOKBACK: Duration = Duration::from_secs(24 * 3600);
+
+/// See commons-pg/migrations/V005__temperature_rollups.sql
+const ROLLUP_5M_SQL: &str = r"INSERT INTO public.temperature_rollup_5m (device_name, bucket_start, min_temperature, max_temperature, avg_temperature, sample_count)
+SELECT device_name,
+    date_trunc('hour', ts_create) + floor(date_part('minute', ts_create) / 5) * interval '5 minutes' AS bucket_start,
+    min(temperature), max(temperature), avg(temperature), count(*)
+FROM public.temperature_sensor_history
+WHERE ts_create >= date_trunc('hour', LEAST(:p_since, (SELECT COALESCE(max(bucket_start), '-infinity') FROM public.temperature_rollup_1h)))
+    AND ts_create < :p_until
+GROUP BY 1, 2
+ON CONFLICT (device_name, bucket_start) DO UPDATE SET
+    min_temperature = EXCLUDED.min_temperature,
+    max_temperature = EXCLUDED.max_temperature,
+    avg_temperature = EXCLUDED.avg_temperature,
+    sample_count = EXCLUDED.sample_count";
+
+const ROLLUP_1H_SQL: &str = r"INSERT INTO public.temperature_rollup_1h (device_name, bucket_start, min_temperature, max_temperature, avg_temperature, sample_count)
+SELECT device_name, date_trunc('hour', ts_create) AS bucket_start,
+    min(temperature), max(temperature), avg(temperature), count(*)
+FROM public.temperature_sensor_history
+WHERE ts_create >= date_trunc('hour', LEAST(:p_since, (SELECT COALESCE(max(bucket_start), '-infinity') FROM public.temperature_rollup_1h)))
+    AND ts_create < :p_until
+GROUP BY 1, 2
+ON CONFLICT (device_name, bucket_start) DO UPDATE SET
+    min_temperature = EXCLUDED.min_temperature,
+    max_temperature = EXCLUDED.max_temperature,
+    avg_temperature = EXCLUDED.avg_temperature,
+    sample_count = EXCLUDED.sample_count";
+
+const DELETE_RAW_SQL: &str = r"DELETE FROM public.temperature_sensor_history WHERE ts_create < :p_before";
+
+const DELETE_ROLLUP_5M_SQL: &str = r"DELETE FROM public.temperature_rollup_5m WHERE bucket_start < :p_before";
+
+/// Ages after which the rows are deleted, the hourly rollups are kept forever
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RetentionPolicy {
+    pub raw_max_age: Duration,
+    pub rollup_5m_max_age: Duration,
+}
+
+impl RetentionPolicy {
+    /// The raw rows are kept at least as long as the lookback, so they are always rolled up before being deleted
+    fn raw_max_age(&self) -> Duration {
+        self.raw_max_age.max(ROLLUP_LOOKBACK)
+    }
+
+    /// The rows before these times are deleted, the raw ones then the 5-minute rollups
+    fn cut_offs(&self, now: SystemTime) -> (SystemTime, SystemTime) {
+        (now - self.raw_max_age(), now - self.rollup_5m_max_age)
+    }
+}
+
+/// Build the rollups of the last day, then delete the rows past their age.
+pub(crate) async fn run_retention(policy: &RetentionPolicy) -> anyhow::Result<()> {
+    let now = SystemTime::now();
+    let since = now - ROLLUP_LOOKBACK;
+    let (raw_before, rollup_5m_before) = policy.cut_offs(now);
+
+    let mut cnx = SQLConnection2::from_pool().await.map_err(tr_fwd!())?;
+    let mut trans = cnx.begin().await.map_err(tr_fwd!())?;
+
+    for sql in [ROLLUP_5M_SQL, ROLLUP_1H_SQL] {
+        let mut params = HashMap::new();
+        params.insert("p_since".to_owned(), CellValue::from_raw_systemtime(since));
+        params.insert("p_until".to_owned(), CellValue::from_raw_systemtime(now));
+        let query = SQLChange2 {
+            sql_query: sql.to_string(),
+            params,
+            sequence_name: "".to_string(),
+        };
+        query.update(&mut trans).await.map_err(err_fwd!("💣 Rollup failed, [{}]", &query.sql_query))?;
+    }
+
+    for (sql, before) in [(DELETE_RAW_SQL, raw_before), (DELETE_ROLLUP_5M_SQL, rollup_5m_before)] {
+        let mut params = HashMap::new();
+        params.insert("p_before".to_owned(), CellValue::from_raw_systemtime(before));
+        let query = SQLChange2 {
+            sql_query: sql.to_string(),
+            params,
+            sequence_name: "".to_string(),
+        };
+        query.delete(&mut trans).await.map_err(err_fwd!("💣 Purge failed, [{}]", &query.sql_query))?;
+    }
+
+    trans.commit().await.map_err(tr_fwd!())?;
+    info!("🧹 Temperature rollups updated, raw rows older than [{}] day(s) deleted", policy.raw_max_age().as_secs() / 86400);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DAY: Duration = Duration::from_secs(24 * 3600);
+
+    #[test]
+    fn cut_offs_follow_the_policy() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let policy = RetentionPolicy { raw_max_age: 30 * DAY, rollup_5m_max_age: 90 * DAY };
+        assert_eq!((now - 30 * DAY, now - 90 * DAY), policy.cut_offs(now));
+    }
+
+    #[test]
+    fn raw_rows_are_kept_for_the_rollup_lookback() {
+        let now = SystemTime::UNIX_EPOCH + 1000 * DAY;
+        let policy = RetentionPolicy { raw_max_age: Duration::ZERO, rollup_5m_max_age: 90 * DAY };
+        assert_eq!(now - ROLLUP_LOOKBACK, policy.cut_offs(now).0);
+    }
+}
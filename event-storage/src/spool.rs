@@ -0,0 +1,737 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{LazyLock, OnceLock};
+
+use anyhow::anyhow;
+use ava_toolkit::dead_letter::DeadLetter;
+use ava_toolkit::journal::JournalEntry;
+use chrono::{DateTime, Utc};
+use commons_pg::sql_transaction2::is_connection_error;
+use log::{error, info, warn};
+use prometheus::{register_int_counter, register_int_gauge, IntCounter, IntGauge};
+use serde_derive::{Deserialize, Serialize};
+use tokio::sync::Mutex;
+
+use crate::dao::store_events;
+
+const SEGMENT_PREFIX: &str = "spool-";
+const SEGMENT_EXTENSION: &str = "jsonl";
+/// The events the database refuses, and the corrupted lines of the segments, kept aside to be looked at
+const REJECT_FILE: &str = "rejected.jsonl";
+
+static SPOOL: OnceLock<Spool> = OnceLock::new();
+
+static DEPTH_GAUGE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("ava_spool_depth_events", "Events waiting in the spool").expect("Cannot register ava_spool_depth_events")
+});
+static BYTES_GAUGE: LazyLock<IntGauge> = LazyLock::new(|| {
+    register_int_gauge!("ava_spool_bytes", "Size of the spool segments").expect("Cannot register ava_spool_bytes")
+});
+static DROPPED_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("ava_spool_dropped_total", "Events dropped because the spool is full").expect("Cannot register ava_spool_dropped_total")
+});
+static REJECTED_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("ava_spool_rejected_total", "Events refused by the database, moved to the reject file").expect("Cannot register ava_spool_rejected_total")
+});
+static CORRUPTED_COUNTER: LazyLock<IntCounter> = LazyLock::new(|| {
+    register_int_counter!("ava_spool_corrupted_total", "Unreadable spool lines, moved to the reject file").expect("Cannot register ava_spool_corrupted_total")
+});
+
+/// Everything event-storage writes in the database.
+/// The timestamp is taken at reception, so a spooled event keeps its original time.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub(crate) enum SpooledEvent {
+    Temperature { device_name: String, temperature: f64, ts_create: DateTime<Utc> },
+    DeviceState {
+        device_name: String,
+        state: String,
+        ts_create: DateTime<Utc>,
+        #[serde(default)]
+        correlation_id: Option<String>,
+    },
+    Journal(JournalEntry),
+    DeadLetter(DeadLetter),
+}
+
+/// One line of the reject file, `line` is the spooled line as it was
+#[derive(Debug, Serialize, Deserialize)]
+struct RejectedLine {
+    ts_rejected: DateTime<Utc>,
+    error: String,
+    line: String,
+}
+
+/// What became of the events given to `Spool::insert_or_isolate`, in their order
+#[derive(Debug)]
+struct Insertion {
+    stored: u64,
+    rejected: usize,
+    /// The first events stored or rejected, the others are not written yet
+    handled: usize,
+    /// The connection error that stopped the insertion
+    error: Option<anyhow::Error>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    events: u64,
+    bytes: u64,
+}
+
+#[derive(Debug)]
+struct SpoolState {
+    segments: VecDeque<Segment>,
+    next_index: u64,
+}
+
+/// Append-only local buffer for the events that cannot reach PostgreSQL.
+/// Events are written as json lines in segment files `spool-<index>.jsonl`, the oldest segment is drained first.
+/// Only the connection errors are spooled : the events refused by the database would block the drain forever,
+/// they go to the reject file instead.
+#[derive(Debug)]
+pub(crate) struct Spool {
+    dir: PathBuf,
+    max_bytes: u64,
+    segment_bytes: u64,
+    state: Mutex<SpoolState>,
+    depth: AtomicU64,
+    bytes: AtomicU64,
+    dropped: AtomicU64,
+    rejected: AtomicU64,
+    corrupted: AtomicU64,
+}
+
+/// Register the global spool, `store` falls back to direct inserts if it's never called
+pub(crate) fn init_spool(dir: &Path, max_bytes: u64, segment_bytes: u64) -> anyhow::Result<&'static Spool> {
+    let spool = Spool::open(dir, max_bytes, segment_bytes)?;
+    SPOOL.set(spool).map_err(|_| anyhow!("Impossible to set the spool"))?;
+    SPOOL.get().ok_or_else(|| anyhow!("Cannot read the spool"))
+}
+
+/// Store the events in the database, or in the spool if the database is not reachable
+pub(crate) async fn store(events: Vec<SpooledEvent>) -> anyhow::Result<()> {
+    match SPOOL.get() {
+        Some(spool) => spool.store(events, store_events).await,
+        None => store_events(events).await,
+    }
+}
+
+impl Spool {
+    pub(crate) fn open(dir: &Path, max_bytes: u64, segment_bytes: u64) -> anyhow::Result<Self> {
+        fs::create_dir_all(dir)?;
+
+        let mut indexed_paths: Vec<(u64, PathBuf)> = vec![];
+        for dir_entry in fs::read_dir(dir)? {
+            let path = dir_entry?.path();
+            if let Some(index) = segment_index(&path) {
+                indexed_paths.push((index, path));
+            }
+        }
+        indexed_paths.sort_by_key(|(index, _)| *index);
+
+        let mut segments = VecDeque::new();
+        for (_, path) in &indexed_paths {
+            let events = BufReader::new(File::open(path)?).lines().count() as u64;
+            let bytes = fs::metadata(path)?.len();
+            segments.push_back(Segment { path: path.clone(), events, bytes });
+        }
+        let next_index = indexed_paths.last().map(|(index, _)| index + 1).unwrap_or(0);
+
+        let spool = Self {
+            dir: dir.to_path_buf(),
+            max_bytes,
+            segment_bytes,
+            depth: AtomicU64::new(segments.iter().map(|s| s.events).sum()),
+            bytes: AtomicU64::new(segments.iter().map(|s| s.bytes).sum()),
+            dropped: AtomicU64::new(0),
+            rejected: AtomicU64::new(0),
+            corrupted: AtomicU64::new(0),
+            state: Mutex::new(SpoolState { segments, next_index }),
+        };
+        DEPTH_GAUGE.set(spool.depth() as i64);
+        BYTES_GAUGE.set(spool.bytes() as i64);
+        info!("📦 Spool opened in [{:?}], depth=[{}] event(s)", &spool.dir, spool.depth());
+        Ok(spool)
+    }
+
+    /// Number of events waiting in the spool
+    pub(crate) fn depth(&self) -> u64 {
+        self.depth.load(Ordering::Relaxed)
+    }
+
+    /// Size of the spool on disk
+    pub(crate) fn bytes(&self) -> u64 {
+        self.bytes.load(Ordering::Relaxed)
+    }
+
+    /// Number of events lost because the spool was full
+    pub(crate) fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    /// Number of events refused by the database, moved to the reject file
+    pub(crate) fn rejected(&self) -> u64 {
+        self.rejected.load(Ordering::Relaxed)
+    }
+
+    /// Number of unreadable spool lines, moved to the reject file
+    pub(crate) fn corrupted(&self) -> u64 {
+        self.corrupted.load(Ordering::Relaxed)
+    }
+
+    /// Insert the events right away when nothing is waiting, otherwise queue them behind the others to keep the order.
+    /// The events refused by the database are rejected, not spooled.
+    pub(crate) async fn store<F, Fut>(&self, events: Vec<SpooledEvent>, insert: F) -> anyhow::Result<()>
+    where
+        F: Fn(Vec<SpooledEvent>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut state = self.state.lock().await;
+        let mut to_spool = &events[..];
+        if state.segments.is_empty() {
+            let insertion = self.insert_or_isolate(&events, &insert).await;
+            match insertion.error {
+                None if insertion.rejected > 0 => {
+                    return Err(anyhow!("[{}] event(s) refused by the database", insertion.rejected));
+                }
+                None => return Ok(()),
+                Some(e) => {
+                    to_spool = &events[insertion.handled..];
+                    warn!("📦 Database insert failed, spool [{}] event(s), e=[{}]", to_spool.len(), e);
+                }
+            }
+        }
+        let mut dropped = 0;
+        for event in to_spool {
+            if self.append(&mut state, event).is_err() {
+                dropped += 1;
+            }
+        }
+        if dropped > 0 {
+            return Err(anyhow!("Spool is full ({} bytes), [{}] event(s) dropped", self.bytes(), dropped));
+        }
+        Ok(())
+    }
+
+    /// Replay the spooled events in order, by batches of `batch_rows`, stop at the first connection error.
+    /// In a batch refused by the database, the refused events go to the reject file, and the drain goes on.
+    /// Return the number of events stored in the database.
+    pub(crate) async fn drain<F, Fut>(&self, batch_rows: usize, insert: F) -> u64
+    where
+        F: Fn(Vec<SpooledEvent>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut state = self.state.lock().await;
+        let mut drained = 0;
+
+        while let Some(segment) = state.segments.front_mut() {
+            let lines = match read_lines(&segment.path) {
+                Ok(lines) => lines,
+                Err(e) => {
+                    error!("💣 Cannot read the spool segment [{:?}], e=[{}]", &segment.path, e);
+                    return drained;
+                }
+            };
+
+            // Keep the line of each event to rewrite the segment after a failure, without the corrupted ones
+            let mut events: Vec<(usize, SpooledEvent)> = vec![];
+            for (position, line) in lines.iter().enumerate() {
+                match serde_json::from_str::<SpooledEvent>(line) {
+                    Ok(event) => events.push((position, event)),
+                    Err(e) => {
+                        error!("💣 Corrupted spool line skipped, [{:?}], line=<{}>, e=[{}]", &segment.path, line, e);
+                        self.reject(std::slice::from_ref(line), &format!("Corrupted line, e=[{}]", e));
+                        self.corrupted.fetch_add(1, Ordering::Relaxed);
+                        CORRUPTED_COUNTER.inc();
+                    }
+                }
+            }
+
+            let batch_rows = batch_rows.max(1);
+            for (chunk_index, chunk) in events.chunks(batch_rows).enumerate() {
+                let batch: Vec<SpooledEvent> = chunk.iter().map(|(_, event)| event.clone()).collect();
+                let insertion = self.insert_or_isolate(&batch, &insert).await;
+                drained += insertion.stored;
+                match insertion.error {
+                    None => {}
+                    Some(e) => {
+                        warn!("📦 Spool drain interrupted after {} event(s), e=[{}]", drained, e);
+                        let remaining: Vec<String> = events[chunk_index * batch_rows + insertion.handled..].iter()
+                            .map(|(position, _)| lines[*position].clone())
+                            .collect();
+                        if let Err(e) = rewrite_segment(segment, &remaining) {
+                            error!("💣 Cannot rewrite the spool segment [{:?}], e=[{}]", &segment.path, e);
+                        }
+                        self.refresh_counters(&state);
+                        return drained;
+                    }
+                }
+            }
+
+            if let Err(e) = fs::remove_file(&segment.path) {
+                error!("💣 Cannot remove the spool segment [{:?}], e=[{}]", &segment.path, e);
+                return drained;
+            }
+            state.segments.pop_front();
+            self.refresh_counters(&state);
+        }
+        drained
+    }
+
+    fn append(&self, state: &mut SpoolState, event: &SpooledEvent) -> anyhow::Result<()> {
+        let mut line = serde_json::to_string(event)?;
+        line.push('\n');
+        let line_bytes = line.len() as u64;
+
+        if self.bytes() + line_bytes > self.max_bytes {
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            DROPPED_COUNTER.inc();
+            return Err(anyhow!("Spool is full ({} bytes), event dropped", self.bytes()));
+        }
+
+        let need_new_segment = match state.segments.back() {
+            None => true,
+            Some(last) => last.events > 0 && last.bytes + line_bytes > self.segment_bytes,
+        };
+        if need_new_segment {
+            let path = self.dir.join(format!("{}{:020}.{}", SEGMENT_PREFIX, state.next_index, SEGMENT_EXTENSION));
+            state.next_index += 1;
+            state.segments.push_back(Segment { path, events: 0, bytes: 0 });
+        }
+
+        let segment = state.segments.back_mut().ok_or_else(|| anyhow!("No spool segment"))?;
+        let mut file = OpenOptions::new().create(true).append(true).open(&segment.path)?;
+        file.write_all(line.as_bytes())?;
+        segment.events += 1;
+        segment.bytes += line_bytes;
+        self.refresh_counters(state);
+        Ok(())
+    }
+
+    /// Insert the events in one batch. When the database refuses the batch, the events are inserted one by one
+    /// and only the refused ones are rejected. A connection error stops the insertion.
+    async fn insert_or_isolate<F, Fut>(&self, events: &[SpooledEvent], insert: &F) -> Insertion
+    where
+        F: Fn(Vec<SpooledEvent>) -> Fut,
+        Fut: Future<Output = anyhow::Result<()>>,
+    {
+        let mut insertion = Insertion { stored: 0, rejected: 0, handled: 0, error: None };
+        match insert(events.to_vec()).await {
+            Ok(_) => {
+                insertion.stored = events.len() as u64;
+                insertion.handled = events.len();
+                return insertion;
+            }
+            Err(e) if is_connection_error(&e) => {
+                insertion.error = Some(e);
+                return insertion;
+            }
+            Err(e) if events.len() == 1 => {
+                self.reject_events(events, &e);
+                insertion.rejected = 1;
+                insertion.handled = 1;
+                return insertion;
+            }
+            Err(e) => warn!("📦 Batch of [{}] event(s) refused, retried one by one, e=[{}]", events.len(), e),
+        }
+        for event in events {
+            match insert(vec![event.clone()]).await {
+                Ok(_) => insertion.stored += 1,
+                Err(e) if is_connection_error(&e) => {
+                    insertion.error = Some(e);
+                    return insertion;
+                }
+                Err(e) => {
+                    self.reject_events(std::slice::from_ref(event), &e);
+                    insertion.rejected += 1;
+                }
+            }
+            insertion.handled += 1;
+        }
+        insertion
+    }
+
+    fn reject_events(&self, events: &[SpooledEvent], e: &anyhow::Error) {
+        let lines: Vec<String> = events.iter().filter_map(|event| serde_json::to_string(event).ok()).collect();
+        self.reject(&lines, &e.to_string());
+        self.count_rejected(events.len());
+    }
+
+    /// Append the lines to the reject file, they are lost if it cannot be written
+    fn reject(&self, lines: &[String], error: &str) {
+        let path = self.dir.join(REJECT_FILE);
+        warn!("📦 [{}] spool line(s) rejected to [{:?}], e=[{}]", lines.len(), &path, error);
+        let mut content = String::new();
+        for line in lines {
+            let rejected = RejectedLine { ts_rejected: Utc::now(), error: error.to_string(), line: line.clone() };
+            match serde_json::to_string(&rejected) {
+                Ok(json) => {
+                    content.push_str(&json);
+                    content.push('\n');
+                }
+                Err(e) => error!("💣 Cannot serialize the rejected line <{}>, e=[{}]", line, e),
+            }
+        }
+        let written = OpenOptions::new().create(true).append(true).open(&path)
+            .and_then(|mut file| file.write_all(content.as_bytes()));
+        if let Err(e) = written {
+            error!("💣 Cannot write the reject file [{:?}], [{}] line(s) lost, e=[{}]", &path, lines.len(), e);
+        }
+    }
+
+    fn count_rejected(&self, count: usize) {
+        self.rejected.fetch_add(count as u64, Ordering::Relaxed);
+        REJECTED_COUNTER.inc_by(count as u64);
+    }
+
+    fn refresh_counters(&self, state: &SpoolState) {
+        self.depth.store(state.segments.iter().map(|s| s.events).sum(), Ordering::Relaxed);
+        self.bytes.store(state.segments.iter().map(|s| s.bytes).sum(), Ordering::Relaxed);
+        DEPTH_GAUGE.set(self.depth() as i64);
+        BYTES_GAUGE.set(self.bytes() as i64);
+    }
+}
+
+fn segment_index(path: &Path) -> Option<u64> {
+    if path.extension()?.to_str()? != SEGMENT_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.strip_prefix(SEGMENT_PREFIX)?.parse().ok()
+}
+
+fn read_lines(path: &Path) -> anyhow::Result<Vec<String>> {
+    let mut lines = vec![];
+    for line in BufReader::new(File::open(path)?).lines() {
+        let line = line?;
+        if !line.trim().is_empty() {
+            lines.push(line);
+        }
+    }
+    Ok(lines)
+}
+
+/// Keep only the lines not yet stored, through a temporary file to survive a crash
+fn rewrite_segment(segment: &mut Segment, remaining: &[String]) -> anyhow::Result<()> {
+    let tmp_path = segment.path.with_extension("tmp");
+    let mut content = remaining.join("\n");
+    content.push('\n');
+    fs::write(&tmp_path, &content)?;
+    fs::rename(&tmp_path, &segment.path)?;
+    segment.events = remaining.len() as u64;
+    segment.bytes = content.len() as u64;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    use anyhow::anyhow;
+    use chrono::Utc;
+
+    use super::*;
+
+    fn spool_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("ava-spool-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn temperature(device_name: &str, temperature: f64) -> SpooledEvent {
+        SpooledEvent::Temperature {
+            device_name: device_name.to_string(),
+            temperature,
+            ts_create: Utc::now(),
+        }
+    }
+
+    /// An error of the data, not of the connection
+    fn refused() -> anyhow::Error {
+        anyhow::Error::from(sqlx::Error::ColumnNotFound("temperature".to_string()))
+    }
+
+    fn reject_lines(dir: &Path) -> Vec<RejectedLine> {
+        read_lines(&dir.join(REJECT_FILE)).unwrap_or_default().iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn temperature_of(event: &SpooledEvent) -> f64 {
+        match event {
+            SpooledEvent::Temperature { temperature, .. } => *temperature,
+            _ => panic!("Not a temperature"),
+        }
+    }
+
+    #[tokio::test]
+    async fn store_goes_to_the_database_when_healthy() {
+        let spool = Spool::open(&spool_dir("healthy"), 10_000, 1_000).unwrap();
+        let inserted = AtomicU64::new(0);
+
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 19.0)], |_| async {
+            inserted.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }).await.unwrap();
+
+        assert_eq!(1, inserted.load(Ordering::Relaxed));
+        assert_eq!(0, spool.depth());
+    }
+
+    #[tokio::test]
+    async fn drain_keeps_the_order_and_resumes_after_a_failure() {
+        let dir = spool_dir("order");
+        let spool = Spool::open(&dir, 100_000, 300).unwrap();
+
+        for i in 0..10 {
+            spool.store(vec![temperature("zigbee2mqtt/ts_bureau", i as f64)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+        }
+        assert_eq!(10, spool.depth());
+        assert!(fs::read_dir(&dir).unwrap().count() > 1);
+
+        // The database is back
+        let stored = Arc::new(Mutex::new(vec![]));
+        let drained = spool.drain(2, |events| {
+            let stored = stored.clone();
+            async move {
+                stored.lock().unwrap().extend(events.iter().map(temperature_of));
+                Ok(())
+            }
+        }).await;
+        assert_eq!(10, drained);
+        assert_eq!(0, spool.depth());
+
+        // The database goes down in the middle of the drain
+        for i in 10..15 {
+            spool.store(vec![temperature("zigbee2mqtt/ts_bureau", i as f64)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+        }
+        let count = AtomicU64::new(0);
+        let drained = spool.drain(2, |events| {
+            let n = count.fetch_add(1, Ordering::Relaxed);
+            let stored = stored.clone();
+            async move {
+                if n >= 1 {
+                    return Err(anyhow!("Database down again"));
+                }
+                stored.lock().unwrap().extend(events.iter().map(temperature_of));
+                Ok(())
+            }
+        }).await;
+        assert_eq!(2, drained);
+        assert_eq!(3, spool.depth());
+
+        // A new spool instance reads the segments left on disk
+        let reopened = Spool::open(&dir, 100_000, 300).unwrap();
+        assert_eq!(3, reopened.depth());
+        let drained = reopened.drain(2, |events| {
+            let stored = stored.clone();
+            async move {
+                stored.lock().unwrap().extend(events.iter().map(temperature_of));
+                Ok(())
+            }
+        }).await;
+        assert_eq!(3, drained);
+        assert_eq!(0, reopened.depth());
+        assert_eq!(0, reopened.bytes());
+
+        let expected: Vec<f64> = (0..15).map(|i| i as f64).collect();
+        assert_eq!(expected, *stored.lock().unwrap());
+    }
+
+    #[tokio::test]
+    async fn new_events_wait_behind_the_spooled_ones() {
+        let spool = Spool::open(&spool_dir("behind"), 10_000, 1_000).unwrap();
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 1.0)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+
+        // The database is back but the spool is not empty yet
+        let inserted = AtomicU64::new(0);
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 2.0)], |_| async {
+            inserted.fetch_add(1, Ordering::Relaxed);
+            Ok(())
+        }).await.unwrap();
+
+        assert_eq!(0, inserted.load(Ordering::Relaxed));
+        assert_eq!(2, spool.depth());
+    }
+
+    #[tokio::test]
+    async fn dead_letter_is_replayed_as_received() {
+        let spool = Spool::open(&spool_dir("dead_letter"), 10_000, 1_000).unwrap();
+        let letter = DeadLetter {
+            service: "luminator".to_string(),
+            topic: "zigbee2mqtt/sw_a".to_string(),
+            payload: "{\"action\":\n".to_string(),
+            error: "EOF while parsing a value at line 2 column 0".to_string(),
+            correlation_id: Some("c1bc0075".to_string()),
+            ts_received: Utc::now(),
+        };
+        spool.store(vec![SpooledEvent::DeadLetter(letter.clone())], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+
+        let stored = Arc::new(Mutex::new(vec![]));
+        let drained = spool.drain(10, |events| {
+            let stored = stored.clone();
+            async move {
+                stored.lock().unwrap().extend(events.iter().cloned());
+                Ok(())
+            }
+        }).await;
+        assert_eq!(1, drained);
+        let stored = stored.lock().unwrap();
+        match stored.as_slice() {
+            [SpooledEvent::DeadLetter(replayed)] => assert_eq!(&letter, replayed),
+            other => panic!("Not the dead letter: {:?}", other),
+        }
+    }
+
+    #[tokio::test]
+    async fn full_spool_drops_the_event() {
+        let spool = Spool::open(&spool_dir("full"), 200, 100).unwrap();
+        let mut errors = 0;
+        for i in 0..10 {
+            if spool.store(vec![temperature("zigbee2mqtt/ts_bureau", i as f64)], |_| async { Err(anyhow!("Database down")) }).await.is_err() {
+                errors += 1;
+            }
+        }
+        assert!(errors > 0);
+        assert_eq!(errors, spool.dropped());
+        assert!(spool.bytes() <= 200);
+        assert_eq!(10 - errors, spool.depth());
+    }
+
+    #[tokio::test]
+    async fn store_rejects_the_events_refused_by_the_database() {
+        let dir = spool_dir("refused");
+        let spool = Spool::open(&dir, 10_000, 1_000).unwrap();
+
+        let result = spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 19.0)], |_| async { Err(refused()) }).await;
+
+        assert!(result.is_err());
+        assert_eq!(0, spool.depth());
+        assert_eq!(1, spool.rejected());
+        let rejected = reject_lines(&dir);
+        assert_eq!(1, rejected.len());
+        assert_eq!(19.0, temperature_of(&serde_json::from_str(&rejected[0].line).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn drain_rejects_the_refused_row_and_goes_on() {
+        let dir = spool_dir("poison");
+        let spool = Spool::open(&dir, 100_000, 10_000).unwrap();
+        for i in 0..6 {
+            spool.store(vec![temperature("zigbee2mqtt/ts_bureau", i as f64)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+        }
+
+        // 2.0 is refused, so is its batch
+        let stored = Arc::new(Mutex::new(vec![]));
+        let drained = spool.drain(2, |events| {
+            let stored = stored.clone();
+            async move {
+                if events.iter().any(|e| temperature_of(e) == 2.0) {
+                    return Err(refused());
+                }
+                stored.lock().unwrap().extend(events.iter().map(temperature_of));
+                Ok(())
+            }
+        }).await;
+
+        assert_eq!(5, drained);
+        assert_eq!(1, spool.rejected());
+        assert_eq!(0, spool.depth());
+        assert_eq!(vec![0.0, 1.0, 3.0, 4.0, 5.0], *stored.lock().unwrap());
+        let rejected = reject_lines(&dir);
+        assert_eq!(1, rejected.len());
+        assert_eq!(2.0, temperature_of(&serde_json::from_str(&rejected[0].line).unwrap()));
+    }
+
+    #[tokio::test]
+    async fn drain_counts_the_corrupted_lines_apart() {
+        let dir = spool_dir("corrupted");
+        let spool = Spool::open(&dir, 100_000, 10_000).unwrap();
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 1.0)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+        let segment = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(b"{\"Temperature\": {\"device_na\n").unwrap();
+        drop(file);
+
+        // Reopened to count the corrupted line in the depth
+        let spool = Spool::open(&dir, 100_000, 10_000).unwrap();
+        let drained = spool.drain(10, |_| async { Ok(()) }).await;
+
+        assert_eq!(1, drained);
+        assert_eq!(1, spool.corrupted());
+        assert_eq!(0, spool.rejected());
+        assert_eq!(0, spool.depth());
+        let rejected = reject_lines(&dir);
+        assert_eq!(1, rejected.len());
+        assert!(rejected[0].error.starts_with("Corrupted line"));
+    }
+
+    #[tokio::test]
+    async fn interrupted_drain_does_not_keep_the_corrupted_lines() {
+        let dir = spool_dir("corrupted_interrupted");
+        let spool = Spool::open(&dir, 100_000, 10_000).unwrap();
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 1.0)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+        let segment = fs::read_dir(&dir).unwrap().next().unwrap().unwrap().path();
+        let mut file = OpenOptions::new().append(true).open(&segment).unwrap();
+        file.write_all(b"not json\n").unwrap();
+        drop(file);
+        spool.store(vec![temperature("zigbee2mqtt/ts_bureau", 2.0)], |_| async { Err(anyhow!("Database down")) }).await.unwrap();
+
+        let spool = Spool::open(&dir, 100_000, 10_000).unwrap();
+        assert_eq!(0, spool.drain(10, |_| async { Err(anyhow!("Database down")) }).await);
+        assert_eq!(2, spool.depth());
+        assert_eq!(2, spool.drain(10, |_| async { Ok(()) }).await);
+        assert_eq!(1, spool.corrupted());
+    }
+
+    #[tokio::test]
+    async fn store_rejects_only_the_refused_rows_of_a_batch() {
+        let dir = spool_dir("refused_row");
+        let spool = Spool::open(&dir, 10_000, 1_000).unwrap();
+        let stored = Arc::new(Mutex::new(vec![]));
+
+        let events = (0..4).map(|i| temperature("zigbee2mqtt/ts_bureau", i as f64)).collect();
+        let result = spool.store(events, |events| {
+            let stored = stored.clone();
+            async move {
+                if events.iter().any(|e| temperature_of(e) == 1.0) {
+                    return Err(refused());
+                }
+                stored.lock().unwrap().extend(events.iter().map(temperature_of));
+                Ok(())
+            }
+        }).await;
+
+        assert!(result.is_err());
+        assert_eq!(vec![0.0, 2.0, 3.0], *stored.lock().unwrap());
+        assert_eq!(1, spool.rejected());
+        assert_eq!(0, spool.depth());
+    }
+
+    #[tokio::test]
+    async fn store_spools_the_rows_left_when_the_connection_drops_while_isolating() {
+        let spool = Spool::open(&spool_dir("refused_then_down"), 10_000, 1_000).unwrap();
+        let calls = AtomicU64::new(0);
+
+        // The batch is refused, the first row is stored, then the database is down
+        let events = (0..3).map(|i| temperature("zigbee2mqtt/ts_bureau", i as f64)).collect();
+        spool.store(events, |_| {
+            let n = calls.fetch_add(1, Ordering::Relaxed);
+            async move {
+                match n {
+                    0 => Err(refused()),
+                    1 => Ok(()),
+                    _ => Err(anyhow!("Database down")),
+                }
+            }
+        }).await.unwrap();
+
+        assert_eq!(0, spool.rejected());
+        assert_eq!(2, spool.depth());
+    }
+}
@@ -1,104 +1,79 @@
-use std::env;
-
-use std::time::Duration;
+use std::process::exit;
 
 use crate::message_enum::MessageEnum;
-use ava_toolkit::domotic_factory::DomoticFactory;
-
-use ava_toolkit::hard_loop::HardLoop;
-use ava_toolkit::init_loop::process_initialization_message;
-use ava_toolkit::processing::process_incoming_message;
-use common_config::conf_reader::{read_config, read_env};
-use common_config::properties::{get_prop_value, set_prop_values};
-use log::{error, info};
-use rumqttc::v5::mqttbytes::QoS;
-use rumqttc::v5::{AsyncClient, MqttOptions};
+use ava_toolkit::service::AvaService;
+use log::error;
 
 mod message_enum;
 
 #[tokio::main]
 async fn main() {
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
-
-    info!("Starting AVA luminator 0.5.0");
-
-    const PROJECT_CODE: &str = "luminator";
-    const VAR_NAME: &str = "AVA_ENV";
-
-    let o_config_file = read_env(&VAR_NAME);
-
-    // Read the application config's file
-    println!(
-        "😎 Config file using PROJECT_CODE={} VAR_NAME={}",
-        PROJECT_CODE, VAR_NAME
-    );
+    let service = AvaService::<MessageEnum>::builder("luminator")
+        .version("0.5.0")
+        .build();
 
-    let props = read_config(
-        PROJECT_CODE,
-        &o_config_file,
-        &Some("AVA_CLUSTER_PROFILE".to_string()),
-    );
-    set_prop_values(props);
-
-    let factory_message_dir = read_props_or_die("factory.dir");
-    let module_file = read_props_or_die("module");
-    let mqtt_port = read_props_or_die("mqtt.port").parse::<u16>().unwrap(); // TODO
-    let mqtt_user = read_props_or_die("mqtt.user");
-    let mqtt_password = read_props_or_die("mqtt.password");
-    let mqtt_host = read_props_or_die("mqtt.host");
+    if let Err(e) = service.run().await {
+        error!("💀 {}", e);
+        exit(-67);
+    }
+}
 
-    let mut domo_factory: DomoticFactory<MessageEnum> =
-        DomoticFactory::new(module_file, factory_message_dir);
-    domo_factory.build_devices();
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
 
-    let all_loops = domo_factory.build_loops();
-    let init_list = domo_factory.devices_to_init();
-    let device_to_listen = domo_factory.devices_to_listen();
+    use ava_toolkit::domotic_factory::DomoticFactory;
+    use ava_toolkit::hard_loop::HardLoop;
+    use ava_toolkit::loopback::Loopback;
+    use ava_toolkit::processing::process_incoming_message;
+    use ava_toolkit::reconnect::Reconnect;
+    use ava_toolkit::replay::{compare_transcripts, read_transcript, Replay};
+    use ava_toolkit::transport::{TransportEvent, TransportEvents};
+    use rumqttc::v5::mqttbytes::v5::PublishProperties;
 
-    let args: Vec<String> = vec![];
-    let channels =
-        DomoticFactory::extract_channel_from_devices(&device_to_listen, mqtt_host.as_str());
+    use crate::message_enum::MessageEnum;
 
-    let mut mqttoptions = MqttOptions::new(&channels.client_id, &channels.server_addr, mqtt_port);
-    mqttoptions.set_keep_alive(Duration::from_secs(channels.keep_alive as u64));
-    mqttoptions.set_clean_start(true);
-    mqttoptions.set_credentials(mqtt_user, mqtt_password);
+    /// Golden test of the hall loop, replay/hall.jsonl is in the format of `mqtt.record_file`
+    #[tokio::test]
+    async fn hall_loop_replays_its_record() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/replay");
+        let record = read_transcript(format!("{}/hall.jsonl", dir)).unwrap();
+        let replay = Replay::<MessageEnum>::from_module(format!("{}/module.json", dir), format!("{}/factory", dir));
 
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
+        let published = replay.run(&record).await;
 
-    for p in &channels.channel_filters {
-        info!("Subscribe to [{}]", p.0);
-        client
-            .subscribe(p.0.clone(), QoS::AtLeastOnce)
-            .await
-            .unwrap();
+        compare_transcripts(&record, &published).unwrap();
     }
 
-    let loop_finder = |topic: &str| HardLoop::find_loops(topic, &all_loops);
-
-    match process_initialization_message(&mut client, &mut eventloop, &init_list).await {
-        Ok(_) => {
-            info!("Process incoming messages");
-            let _ = process_incoming_message(&mut client, &mut eventloop, &args, loop_finder).await;
-        }
-        Err(e) => {
-            panic!("{}", e);
-        }
+    /// The hall loop on the in-process broker, the switch turns the lamp on
+    #[tokio::test]
+    async fn hall_loop_runs_on_the_loopback() {
+        let dir = concat!(env!("CARGO_MANIFEST_DIR"), "/replay");
+        let mut factory = DomoticFactory::<MessageEnum>::new(format!("{}/module.json", dir), format!("{}/factory", dir));
+        factory.build_devices();
+        let loops = factory.build_loops();
+
+        let broker = Loopback::new();
+        let (client, mut events) = broker.connect();
+        client.subscribe("zigbee2mqtt/sw_hall").await.unwrap();
+        let (house, mut house_events) = broker.connect();
+        house.subscribe("zigbee2mqtt/hall_lamp/set").await.unwrap();
+
+        tokio::spawn(async move {
+            let reconnect = Reconnect::new(&[]);
+            let find_loops = |topic: &str| HardLoop::find_loops(topic, &loops);
+            process_incoming_message(&client, &mut events, &[], find_loops, &reconnect).await;
+        });
+        house.publish("zigbee2mqtt/sw_hall", br#"{"action":"single"}"#.to_vec(), PublishProperties::default()).await.unwrap();
+
+        let command = tokio::time::timeout(Duration::from_secs(5), async {
+            loop {
+                if let Ok(TransportEvent::Publish { payload, .. }) = house_events.poll().await {
+                    return payload;
+                }
+            }
+        }).await.unwrap();
+        let lamp: serde_json::Value = serde_json::from_str(&command).unwrap();
+        assert_eq!("ON", lamp["state"]);
     }
-    println!("Done!");
-}
-
-fn read_props_or_die(property_name: &str) -> String {
-    let value = match get_prop_value(property_name) {
-        Ok(file) => file,
-        Err(e) => {
-            error!("{}", e);
-            panic!("Cannot find the property")
-        }
-    };
-    value
 }
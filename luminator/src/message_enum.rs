@@ -20,71 +20,23 @@ macro_rules! ensure_specific_enum {
 }
 
 /// All kind of messages we can encounter in the pattern
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Locality)]
 pub (crate) enum MessageEnum {
+    #[locality(set_suffix = "set", state_query = r#"{"color":{"x":"","y":""}}"#)]
     LampRgb(LampRgbMsg),
     SimpleSwitch(SimpleSwitchMsg),
 }
 
-impl Locality for MessageEnum {
-
-    fn query_for_state(&self) -> String {
-        match self {
-            LampRgb(_) => {
-                let msg =  r#"{"color":{"x":"","y":""}}"#;
-                msg.to_string()
-            }
-            SimpleSwitch(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-        }
-    }
+impl MessageEnum {
 
-    fn find_set_topic(&self, topic: &str) -> String {
-        match self {
-            LampRgb(_) => {
-                format!("{}/set", topic)
-            }
-            SimpleSwitch(_) => {
-                topic.to_string()
-            }
-        }
-    }
-    
-    fn raw_message(&self) -> String {
-        match self {
-            LampRgb(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO
-            }
-            SimpleSwitch(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO
-            }
-        }
-    }
     /// Convert the original message to the type of the current Self
-   fn to_local(&self, original_message: &MessageEnum, last_message: &MessageEnum) -> Self {
+    fn to_local(&self, original_message: &MessageEnum, last_message: &MessageEnum) -> Self {
         match self {
             LampRgb(_) => {
-                original_message.to_lamp_rgb(&last_message)
+                original_message.to_lamp_rgb(last_message)
             }
             SimpleSwitch(_) => {
-                original_message.to_simple_switch(&last_message)
-            }
-        }
-    }
-
-    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>) -> Self {
-        self.to_local(original_message, last_message)
-    }
-    
-    fn json_to_local(&self, json_msg: &str) -> Result<MessageEnum, String> {
-        match self {
-            LampRgb(_) => {
-                Ok(LampRgb(LampRgbMsg::from_json(json_msg)?))
-            }
-            SimpleSwitch(_) => {
-                Ok(SimpleSwitch(SimpleSwitchMsg::from_json(json_msg)?))
+                original_message.to_simple_switch(last_message)
             }
         }
     }
@@ -103,11 +55,7 @@ impl Locality for MessageEnum {
     async fn compute(&self) -> Option<HashMap<String, f64>> {
         None
     }
-}
 
-
-impl MessageEnum {
-    
     /// Convert the current type of message to LampRGB
     fn to_lamp_rgb(&self, last_message: &MessageEnum) -> Self {
         // We know the "last_message" is of type LAMP_RGB
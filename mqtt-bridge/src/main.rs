@@ -1,6 +1,9 @@
-use std::{env, net::SocketAddr, time::Duration};
+use std::{env, net::SocketAddr, process::exit, time::Duration};
 
 use futures_util::stream::{SplitSink, SplitStream};
+use ava_toolkit::logging::init_logging;
+use ava_toolkit::metrics::start_metrics;
+use common_config::properties::set_prop_value;
 use futures_util::{SinkExt, StreamExt};
 use log::*;
 use rumqttc::v5::mqttbytes::QoS;
@@ -57,7 +60,13 @@ async fn handle_reading_ws(
                         let msg = msg?;
                         if msg.is_text() ||msg.is_binary() {
                             info!("WS message received : {}", msg);
-                            let bridge_message : BridgeMessage = serde_json::from_str(&msg.to_string()).unwrap(); // TODO
+                            let bridge_message : BridgeMessage = match serde_json::from_str(&msg.to_string()) {
+                                Ok(m) => m,
+                                Err(e) => {
+                                    error!("💣 Client [{}] sent a wrong bridge message, e=[{}]", &peer, e);
+                                    continue;
+                                }
+                            };
                             info!("📨 Client [{}] sent message to mqtt, topic=[{}], message=[{}]", &peer, &bridge_message.topic,  &bridge_message.raw_message);
                             let data = bridge_message.raw_message.as_bytes().to_vec();
                             client.publish(&bridge_message.topic, QoS::AtLeastOnce, false, data).await.unwrap(); // TODO
@@ -173,6 +182,14 @@ async fn handle_writing_ws(
     Ok(())
 }
 
+/// The value of the --metrics-port argument
+fn read_metrics_port() -> Option<String> {
+    let args: Vec<String> = env::args().collect();
+    args.iter()
+        .position(|a| a == "--metrics-port")
+        .and_then(|index| args.get(index + 1).cloned())
+}
+
 ///
 /// host ,  mqtt-user , mqtt-pass
 ///
@@ -234,16 +251,21 @@ fn read_host() -> (String, String, String) {
 async fn main() {
     // --host 192.168.0.99
 
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
+    init_logging();
 
     info!("Starting Mqtt Bridge 0.7.0");
 
     let (ip, mqtt_user, mqtt_pass) = read_host();
 
+    // --metrics-port [optional] serves GET /metrics
+    if let Some(port) = read_metrics_port() {
+        set_prop_value("metrics.port", &port);
+    }
+    if let Err(e) = start_metrics().await {
+        error!("💀 {}", e);
+        exit(-67);
+    }
+
     // ** Web Socket **
     let addr = format!("{}:9002", ip); // "192.168.0.99:9002";
     let listener = TcpListener::bind(&addr).await.expect("Can't listen");
@@ -1,20 +1,20 @@
-use std::cell::RefCell;
 use std::collections::HashMap;
 use std::env;
-use std::sync::Arc;
 use std::time::Duration;
 
 use log::info;
-use rumqttc::v5::mqttbytes::QoS;
-use rumqttc::v5::{AsyncClient, MqttOptions};
+use rumqttc::v5::MqttOptions;
 
 use crate::device_repo::{build_device_repo, device_to_listen};
 use crate::loops::{build_init_list, build_loops};
 use crate::message_enum::MessageEnum;
-use ava_toolkit::generic_device::GenericDevice;
+use ava_toolkit::generic_device::SharedDevice;
 use ava_toolkit::hard_loop::HardLoop;
 use ava_toolkit::init_loop::process_initialization_message;
+use ava_toolkit::logging::init_logging;
 use ava_toolkit::processing::process_incoming_message;
+use ava_toolkit::reconnect::Reconnect;
+use ava_toolkit::transport::rumqttc_transport;
 
 mod device_repo;
 mod loops;
@@ -26,20 +26,15 @@ const CLIENT_ID: &str = "ava-0.5.0";
 pub struct Params {
     pub server_addr: String,
     pub client_id: String,
-    pub channel_filters: Vec<(String, QoS)>,
+    pub channel_filters: Vec<String>,
     pub keep_alive: u16,
 }
 
 /// Build the list of channel to listen
-fn parse_params(device_repo: &HashMap<String, Arc<RefCell<GenericDevice<MessageEnum>>>>) -> Params {
+fn parse_params(device_repo: &HashMap<String, SharedDevice<MessageEnum>>) -> Params {
     let client_id = CLIENT_ID.to_string();
 
-    let mut channel_filters: Vec<(String, QoS)> = vec![];
-    for dev in device_to_listen(&device_repo) {
-        let dd = dev.as_ref().borrow();
-        let topic = dd.get_topic();
-        channel_filters.push((topic, QoS::AtMostOnce));
-    }
+    let channel_filters: Vec<String> = device_to_listen(&device_repo).iter().map(|dev| dev.get_topic()).collect();
 
     Params {
         server_addr: "raspberrypi.local".to_string(),
@@ -51,11 +46,7 @@ fn parse_params(device_repo: &HashMap<String, Arc<RefCell<GenericDevice<MessageE
 
 #[tokio::main]
 async fn main() {
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
+    init_logging();
 
     info!("Starting AVA 0.5.0");
 
@@ -71,12 +62,12 @@ async fn main() {
     let mqtt_password = env::var("AVA_MQTT_PASSWORD").expect("Missing AVA_MQTT_PASSWORD");
     mqttoptions.set_credentials(mqtt_user, mqtt_password);
 
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
+    let (client, mut events) = rumqttc_transport(mqttoptions, 15);
 
-    for p in &params.channel_filters {
-        info!("Subscribe to [{}]", p.0);
+    for topic in &params.channel_filters {
+        info!("Subscribe to [{}]", topic);
         client
-            .subscribe(p.0.clone(), QoS::AtLeastOnce)
+            .subscribe(topic)
             .await
             .unwrap();
     }
@@ -85,11 +76,12 @@ async fn main() {
     let all_loops = build_loops(&device_repo);
 
     let loop_finder = |topic: &str| HardLoop::find_loops(topic, &all_loops);
+    let reconnect = Reconnect::new(&params.channel_filters);
 
-    match process_initialization_message(&mut client, &mut eventloop, &mut init_list).await {
+    match process_initialization_message(client.as_ref(), &mut events, &mut init_list, &reconnect).await {
         Ok(_) => {
             info!("Process incoming messages");
-            let _ = process_incoming_message(&mut client, &mut eventloop, &args, loop_finder).await;
+            let _ = process_incoming_message(&client, &mut events, &args, loop_finder, &reconnect).await;
         }
         Err(e) => {
             panic!("{}", e);
@@ -71,7 +71,7 @@ impl Locality for MessageEnum {
         }
     }
 
-    fn to_local_with_data(&self, _original_message: &Self, _last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>) -> Self {
+    fn to_local_with_data(&self, _original_message: &Self, _last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>, _source_topic: &str) -> Self {
         todo!()
     }
 
@@ -1,13 +1,17 @@
 use std::collections::HashMap;
 use std::sync::{Arc, RwLock};
+use std::time::SystemTime;
 
 use anyhow::anyhow;
+use ava_toolkit::correlation::{correlation_from_header, current_correlation_id, with_correlation_id, CORRELATION_HEADER};
 use ava_toolkit::device_message::RadiatorMode;
 use axum::extract::{Path, State};
-use axum::http::StatusCode;
+use axum::http::{HeaderMap, StatusCode};
 use axum::Json;
 use chrono::{Local, NaiveTime};
 use common_config::properties::set_prop_value;
+use commons_pg::sql_transaction::CellValue;
+use commons_pg::sql_transaction2::{BatchMode, SQLBatchWriter2, SQLConnection2};
 use log::{error, info};
 use radiator_toolkit::HeatzyClient;
 use serde_derive::{Deserialize, Serialize};
@@ -148,9 +152,18 @@ enum RadiatorAction {
 
 /// Direct command endpoint: set one radiator mode without using temperature-based computation.
 pub async fn set_radiator_mode(
+    headers: HeaderMap,
     Path(room): Path<String>,
     State(state): State<AppState>,
     Json(payload): Json<SetRadiatorModeRequest>,
+) -> Result<Json<SetRadiatorModeResponse>, (StatusCode, String)> {
+    with_correlation_id(request_correlation_id(&headers), set_direct_mode(room, state, payload)).await
+}
+
+async fn set_direct_mode(
+    room: String,
+    state: AppState,
+    payload: SetRadiatorModeRequest,
 ) -> Result<Json<SetRadiatorModeResponse>, (StatusCode, String)> {
     info!(
         "🚀 Process direct radiator mode request for room [{}] and mode [{}]",
@@ -189,15 +202,23 @@ pub async fn set_radiator_mode(
 
 /// Main endpoint: compute required changes, call Heatzy, then persist state changes.
 pub async fn update_radiator(
+    headers: HeaderMap,
     State(state): State<AppState>,
     Json(payload): Json<UpdateRadiatorRequest>,
+) -> Result<Json<UpdateRadiatorResponse>, (StatusCode, String)> {
+    with_correlation_id(request_correlation_id(&headers), regulate_radiators(state, payload)).await
+}
+
+async fn regulate_radiators(
+    state: AppState,
+    payload: UpdateRadiatorRequest,
 ) -> Result<Json<UpdateRadiatorResponse>, (StatusCode, String)> {
     info!("🚀 Process radiator update request: {:?}", payload);
 
     // 1) Open a dedicated DB connection for this request.
     let (client, _connection) = open_db_connection(&state).await.map_err(internal_error)?;
 
-    save_input_temperatures(&client, &payload)
+    save_input_temperatures(&payload)
         .await
         .map_err(internal_error)?;
     info!("📝 Stored incoming temperatures into temperature_sensor_history");
@@ -365,10 +386,8 @@ async fn apply_radiator_mode_change(
     Ok(())
 }
 
-async fn save_input_temperatures(
-    client: &tokio_postgres::Client,
-    payload: &UpdateRadiatorRequest,
-) -> anyhow::Result<()> {
+/// The 4 readings are written in one multi-row insert.
+async fn save_input_temperatures(payload: &UpdateRadiatorRequest) -> anyhow::Result<()> {
     let readings = [
         (TS_BUREAU, payload.bureau),
         (TS_CHAMBRE, payload.chambre),
@@ -376,17 +395,28 @@ async fn save_input_temperatures(
         (TS_SALON, payload.salon),
     ];
 
-    let query = r#"INSERT INTO temperature_sensor_history (device_name, temperature, ts_create)
-VALUES ($1, $2, timezone('UTC', current_timestamp))"#;
-
+    let mut writer = SQLBatchWriter2::new(
+        "public.temperature_sensor_history",
+        &["device_name", "temperature", "ts_create"],
+        BatchMode::Insert,
+    );
+    let ts_create = SystemTime::now();
     for (device_name, temperature) in readings {
-        client.execute(query, &[&device_name, &temperature]).await?;
+        let mut row = HashMap::new();
+        row.insert("device_name".to_owned(), CellValue::from_raw_str(device_name));
+        row.insert("temperature".to_owned(), CellValue::from_raw_double(temperature));
+        row.insert("ts_create".to_owned(), CellValue::from_raw_systemtime(ts_create));
+        writer.push(row);
         info!(
-            "\t📝 Stored input temperature for sensor {} = [{}]",
+            "\t📝 Input temperature for sensor {} = [{}]",
             device_name, temperature
         );
     }
 
+    let mut cnx = SQLConnection2::from_pool().await?;
+    let mut trans = cnx.begin().await?;
+    writer.flush(&mut trans).await?;
+    trans.commit().await?;
     Ok(())
 }
 
@@ -434,10 +464,11 @@ async fn save_radiator_state(
         mode: mode_as_str(mode).to_string(),
     })?;
 
-    let query = r#"INSERT INTO public.device_state_history (device_name, state, ts_create)
-VALUES($1, $2, timezone('UTC', current_timestamp))"#;
+    let correlation_id = current_correlation_id();
+    let query = r#"INSERT INTO public.device_state_history (device_name, state, ts_create, correlation_id)
+VALUES($1, $2, timezone('UTC', current_timestamp), $3)"#;
 
-    client.execute(query, &[&radiator, &state]).await?;
+    client.execute(query, &[&radiator, &state, &correlation_id]).await?;
     Ok(())
 }
 
@@ -537,6 +568,11 @@ fn mode_as_str(mode: RadiatorMode) -> &'static str {
     }
 }
 
+/// The ID of the request, the caller may give it in the X-Correlation-Id header
+fn request_correlation_id(headers: &HeaderMap) -> String {
+    correlation_from_header(headers.get(CORRELATION_HEADER).and_then(|v| v.to_str().ok()))
+}
+
 fn internal_error<E: std::fmt::Display>(err: E) -> (StatusCode, String) {
     (StatusCode::INTERNAL_SERVER_ERROR, err.to_string())
 }
@@ -546,7 +582,7 @@ mod tests {
     use super::*;
     use axum::body::{to_bytes, Body};
     use axum::http::{Request, StatusCode};
-    use axum::routing::post;
+    use axum::routing::{get, post};
     use axum::Router;
     use tower::util::ServiceExt;
 
@@ -656,4 +692,32 @@ mod tests {
             .unwrap()
             .contains("Supported modes: CFT, STOP, ECO"));
     }
+
+    #[test]
+    fn request_correlation_id_comes_from_the_header() {
+        let mut headers = HeaderMap::new();
+        headers.insert(CORRELATION_HEADER, "plan-42".parse().unwrap());
+        assert_eq!(request_correlation_id(&headers), "plan-42");
+
+        let generated = request_correlation_id(&HeaderMap::new());
+        assert_eq!(generated.len(), 36);
+        assert_ne!(generated, request_correlation_id(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn metrics_endpoint_exposes_heatzy_calls() {
+        ava_toolkit::metrics::heatzy_request("control", std::time::Duration::from_millis(120), false);
+        let app: Router = Router::new().route("/metrics", get(ava_toolkit::metrics::metrics));
+
+        let response = app
+            .oneshot(Request::builder().uri("/metrics").body(Body::empty()).unwrap())
+            .await
+            .unwrap();
+
+        assert_eq!(response.status(), StatusCode::OK);
+        let body = to_bytes(response.into_body(), usize::MAX).await.unwrap();
+        let text = String::from_utf8(body.to_vec()).unwrap();
+        assert!(text.contains(r#"ava_heatzy_request_seconds_count{operation="control"}"#));
+        assert!(text.contains(r#"ava_heatzy_errors_total{operation="control"}"#));
+    }
 }
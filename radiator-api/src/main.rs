@@ -1,12 +1,14 @@
-use std::env;
 use std::net::SocketAddr;
 use std::process::exit;
 
 use axum::http::Method;
-use axum::routing::post;
+use axum::routing::{get, post};
+use ava_toolkit::logging::init_logging;
+use ava_toolkit::metrics::metrics;
 use axum::Router;
 use common_config::conf_reader::{read_config, read_env};
 use common_config::properties::{get_prop_pg_connect_string, get_prop_value, set_prop_values};
+use commons_pg::sql_transaction2::init_db_pool2;
 use log::{error, info};
 use std::sync::{Arc, RwLock};
 use tower_http::cors::{Any, CorsLayer};
@@ -16,11 +18,7 @@ mod api;
 #[tokio::main]
 async fn main() {
     // 1) Logging initialization.
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
+    init_logging();
 
     info!("Starting AVA radiator-api 0.1.0");
 
@@ -41,7 +39,7 @@ async fn main() {
         .unwrap_or(30055);
 
     // 3) Build shared app state consumed by request handlers.
-    let (db_url, _) = match get_prop_pg_connect_string() {
+    let (db_url, db_pool_size) = match get_prop_pg_connect_string() {
         Ok(v) => v,
         Err(e) => {
             error!("Cannot read database properties: {}", e);
@@ -49,6 +47,12 @@ async fn main() {
         }
     };
 
+    // The pool is used for the batched inserts
+    if let Err(e) = init_db_pool2(&db_url, db_pool_size).await {
+        error!("Cannot init the database pool: {}", e);
+        exit(-64);
+    }
+
     let app_state = api::AppState {
         db_url,
         heatzy_application_id: read_props_or_die("heatzy.application.id"),
@@ -66,6 +70,7 @@ async fn main() {
     let app = Router::new()
         .route("/radiator/:room", post(api::set_radiator_mode))
         .route("/update-radiator", post(api::update_radiator))
+        .route("/metrics", get(metrics))
         .with_state(app_state)
         .layer(cors);
 
@@ -2,6 +2,7 @@ use std::collections::HashMap;
 
 use lazy_static::lazy_static;
 use log::{error, info};
+use ava_toolkit::correlation::current_correlation_id;
 use ava_toolkit::device_message::{RegulatorRadiatorMsg, RadiatorMode};
 use ava_toolkit::generic_device::{GenericDevice, Locality, EXTERNAL_FAMILY};
 use common_config::properties::{get_prop_value, set_prop_value};
@@ -11,7 +12,7 @@ use std::sync::{Arc, RwLock};
 use crate::message_enum::MessageEnum::RegulatorRadiator;
 
 /// Object by enums
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Locality)]
 pub (crate) enum MessageEnum {
     RegulatorRadiator(RegulatorRadiatorMsg)
 }
@@ -21,58 +22,22 @@ impl MessageEnum {
     fn to_radiator(&self, _last_message: &MessageEnum) -> Self {
         self.clone()
     }
-}
-
-impl Locality for MessageEnum {
-    fn query_for_state(&self) -> String {
-        match self {
-            RegulatorRadiator(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-        }
-    }
-
-    fn find_set_topic(&self, topic: &str) -> String {
-        topic.to_string()
-    }
-    
-    fn raw_message(&self) -> String {
-        match self {
-            RegulatorRadiator(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO
-            }
-        }
-    }
 
     /// Convert the original message to the type of the current Self
     fn to_local(&self, original_message: &MessageEnum, last_message: &MessageEnum) -> Self {
         match self {
             RegulatorRadiator(_) => {
-                original_message.to_radiator(&last_message)
-            }
-        }
-    }
-
-    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, _ext_data: Option<&HashMap<String, f64>>, _topic: Option<&str>) -> Self {
-        self.to_local(original_message, last_message)
-    }
-    
-    fn json_to_local(&self, json_msg: &str) -> Result<MessageEnum, String> {
-        match self {
-            RegulatorRadiator(_) => {
-                Ok(RegulatorRadiator(RegulatorRadiatorMsg::from_json(json_msg)?))
+                original_message.to_radiator(last_message)
             }
         }
     }
 
-    
     /// Default process for the message
     async fn process(&self, topic: &str, args: &[String]) {
         match self {
             RegulatorRadiator(t) => {
                 info!("Default process for Radiator, message=[{:?}]", t);
-                command_radiator(&topic, &t, &args).await;
+                command_radiator(topic, t, args).await;
             }
         }
     }
@@ -114,7 +79,7 @@ lazy_static! {
 
 // TODO : we could remove the args param all along the cascade of routines
 pub (crate) async fn command_radiator(topic: &str, msg: &RegulatorRadiatorMsg, _args: &[String]) {
-    info!("Command [{}]", &topic);
+    info!("Command [{}], correlation_id=[{}]", &topic, current_correlation_id().unwrap_or_default());
 
     let heatzy_username = get_prop_value("heatzy.username").unwrap();
     let heatzy_password = get_prop_value("heatzy.password").unwrap();
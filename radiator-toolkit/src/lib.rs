@@ -1,7 +1,9 @@
 use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
 use anyhow::anyhow;
 use ava_toolkit::device_message::RadiatorMode;
+use ava_toolkit::metrics;
 use log::info;
 use reqwest::header;
 use serde::Deserialize;
@@ -76,6 +78,18 @@ impl HeatzyClient {
         heatzy_token: &str,
         did: &str,
         mode: RadiatorMode,
+    ) -> Result<(), HeatzyCallError> {
+        let started = Instant::now();
+        let result = self.send_mode(heatzy_token, did, mode).await;
+        metrics::heatzy_request("control", started.elapsed(), result.is_ok());
+        result
+    }
+
+    async fn send_mode(
+        &self,
+        heatzy_token: &str,
+        did: &str,
+        mode: RadiatorMode,
     ) -> Result<(), HeatzyCallError> {
         let h_mode = match mode {
             RadiatorMode::CFT => 0,
@@ -132,6 +146,13 @@ impl HeatzyClient {
     }
 
     async fn login(&self) -> anyhow::Result<String> {
+        let started = Instant::now();
+        let result = self.send_login().await;
+        metrics::heatzy_request("login", started.elapsed(), result.is_ok());
+        result
+    }
+
+    async fn send_login(&self) -> anyhow::Result<String> {
         let url = "https://euapi.gizwits.com/app/login";
         let body = serde_json::json!({
             "username": self.username,
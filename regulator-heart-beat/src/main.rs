@@ -1,17 +1,19 @@
-use std::env;
 use std::process::exit;
 use std::time::Duration;
 
 use crate::dao::get_current_regulation_map;
 use crate::message_enum::MessageEnum;
 use ava_toolkit::domotic_factory::DomoticFactory;
+use ava_toolkit::logging::init_logging;
+use ava_toolkit::metrics::start_metrics;
+use ava_toolkit::transport::{rumqttc_transport, TransportEvent, TransportEvents};
 use common_config::conf_reader::{read_config, read_env};
 use common_config::properties::{get_prop_pg_connect_string, get_prop_value, set_prop_values};
 use commons_error::*;
 use commons_pg::sql_transaction2::init_db_pool2;
 use log::info;
 use log::*;
-use rumqttc::v5::{AsyncClient, Event, Incoming, MqttOptions};
+use rumqttc::v5::MqttOptions;
 use tokio::time::interval;
 
 mod dao;
@@ -21,11 +23,7 @@ pub(crate) const REGULATE_RADIATOR: &str = "regulate_radiator";
 
 #[tokio::main]
 async fn main() {
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
+    init_logging();
 
     info!("Starting AVA regulator-heart-beat 0.5.0");
 
@@ -77,7 +75,7 @@ async fn main() {
     mqttoptions.set_clean_start(true);
     mqttoptions.set_credentials(mqtt_user, mqtt_password);
 
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
+    let (client, mut events) = rumqttc_transport(mqttoptions, 15);
 
     // Init DB pool
     let (connect_string, db_pool_size) = match get_prop_pg_connect_string()
@@ -92,13 +90,18 @@ async fn main() {
 
     log_info!("Connnect String : [{}]", &connect_string);
 
-    let _r = init_db_pool2(&connect_string, db_pool_size).await;
+    // The pool checks the schema, an outdated database stops the service
+    if let Err(e) = init_db_pool2(&connect_string, db_pool_size).await {
+        log_error!("Cannot init the database pool, e=[{:?}]", e);
+        exit(-64);
+    }
 
-    let device = device_repo
-        .get(REGULATE_RADIATOR)
-        .unwrap()
-        .as_ref()
-        .borrow();
+    if let Err(e) = start_metrics().await {
+        log_error!("💀 {}", e);
+        exit(-67);
+    }
+
+    let device = device_repo.get(REGULATE_RADIATOR).unwrap();
 
     //  5 minutes
     let mut interval = interval(Duration::from_secs(5 * 60));
@@ -113,19 +116,12 @@ async fn main() {
             let msg = MessageEnum::RegulationMap(reg_plan.2);
 
             info!("prepare to send :  [{:?}]", &msg);
-            let _ = device.publish_message(&mut client, &msg).await;
+            let _ = device.publish_message(client.as_ref(), &msg).await;
             info!("Sent regulation map notification");
 
-            while let Ok(notification) = eventloop.poll().await {
-                let mut end_loop = false;
-                match notification {
-                    Event::Incoming(Incoming::PubAck(pub_ack)) => {
-                        info!("📩  PubAck ({:?})", &pub_ack);
-                        end_loop = true;
-                    }
-                    _ => {}
-                }
-                if end_loop {
+            while let Ok(event) = events.poll().await {
+                if let TransportEvent::Acknowledged = event {
+                    info!("📩  PubAck");
                     break;
                 }
             }
@@ -48,7 +48,7 @@ impl Locality for MessageEnum {
         }
     }
 
-    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, _o_ext_data: Option<&HashMap<String, f64>>, _o_topic: Option<&str>) -> Self {
+    fn to_local_with_data(&self, original_message: &Self, last_message: &Self, _o_ext_data: Option<&HashMap<String, f64>>, _o_topic: Option<&str>, _source_topic: &str) -> Self {
         self.to_local(original_message, last_message)
     }
     
@@ -1,17 +1,10 @@
-use std::env;
-use std::time::Duration;
+use std::process::exit;
 
 use crate::message_enum::MessageEnum;
-use ava_toolkit::domotic_factory::DomoticFactory;
-
-use ava_toolkit::hard_loop::HardLoop;
-use ava_toolkit::init_loop::process_initialization_message;
-use ava_toolkit::processing::process_incoming_message;
-use common_config::conf_reader::{read_config, read_env};
-use common_config::properties::{get_prop_value, set_prop_values};
-use log::{error, info};
-use rumqttc::v5::mqttbytes::QoS;
-use rumqttc::v5::{AsyncClient, MqttOptions};
+use ava_toolkit::service::AvaService;
+use common_config::properties::get_prop_pg_connect_string;
+use commons_pg::sql_transaction2::check_db_schema;
+use log::error;
 
 mod external_computing;
 mod message_enum;
@@ -20,86 +13,25 @@ mod message_enum;
 async fn main() {
     // run --package regulator --bin regulator -- --cluster-profile ava_home_01
 
-    env::set_var(
-        "RUST_LOG",
-        env::var_os("RUST_LOG").unwrap_or_else(|| "info".into()),
-    );
-    env_logger::init();
-
-    info!("Starting AVA regulator 0.5.0");
-
-    const PROJECT_CODE: &str = "regulator";
-    const VAR_NAME: &str = "AVA_ENV";
-
-    let o_config_file = read_env(&VAR_NAME);
-
-    // Read the application config's file
-    println!(
-        "😎 Config file using PROJECT_CODE={} VAR_NAME={}",
-        PROJECT_CODE, VAR_NAME
-    );
-
-    let props = read_config(
-        PROJECT_CODE,
-        &o_config_file,
-        &Some("AVA_CLUSTER_PROFILE".to_string()),
-    );
-    set_prop_values(props);
-
-    let factory_message_dir = read_props_or_die("factory.dir");
-    let module_file = read_props_or_die("module");
-    let mqtt_port = read_props_or_die("mqtt.port").parse::<u16>().unwrap(); // TODO
-    let mqtt_user = read_props_or_die("mqtt.user");
-    let mqtt_password = read_props_or_die("mqtt.password");
-    let mqtt_host = read_props_or_die("mqtt.host");
-
-    let mut domo_factory: DomoticFactory<MessageEnum> =
-        DomoticFactory::new(module_file, factory_message_dir);
-    domo_factory.build_devices();
-
-    let all_loops = domo_factory.build_loops();
-    let init_list = domo_factory.devices_to_init();
-    let device_to_listen = domo_factory.devices_to_listen();
-
-    let args: Vec<String> = vec![];
-    let channels = DomoticFactory::extract_channel_from_devices(&device_to_listen, &mqtt_host);
-
-    let mut mqttoptions = MqttOptions::new(&channels.client_id, &channels.server_addr, mqtt_port);
-    mqttoptions.set_keep_alive(Duration::from_secs(channels.keep_alive as u64));
-    mqttoptions.set_clean_start(true);
-    mqttoptions.set_credentials(mqtt_user, mqtt_password);
+    let service = AvaService::<MessageEnum>::builder("regulator")
+        .version("0.5.0")
+        .build();
 
-    let (mut client, mut eventloop) = AsyncClient::new(mqttoptions, 15);
-
-    for p in &channels.channel_filters {
-        info!("Subscribe to [{}]", p.0);
-        client
-            .subscribe(p.0.clone(), QoS::AtLeastOnce)
-            .await
-            .unwrap();
-    }
-
-    let loop_finder = |topic: &str| HardLoop::find_loops(topic, &all_loops);
-
-    match process_initialization_message(&mut client, &mut eventloop, &init_list).await {
-        Ok(_) => {
-            info!("Process incoming messages");
-            let _ = process_incoming_message(&mut client, &mut eventloop, &args, loop_finder).await;
-        }
+    // The temperatures are read with tokio_postgres by external_computing, there is no pool to check the schema
+    let (connect_string, _) = match get_prop_pg_connect_string() {
+        Ok(x) => x,
         Err(e) => {
-            panic!("{}", e);
+            error!("Cannot read the database connection information, e=[{}]", e);
+            exit(-64);
         }
+    };
+    if let Err(e) = check_db_schema(&connect_string).await {
+        error!("Cannot check the database schema, e=[{:?}]", e);
+        exit(-64);
     }
-    println!("Done!");
-}
 
-fn read_props_or_die(property_name: &str) -> String {
-    let value = match get_prop_value(property_name) {
-        Ok(file) => file,
-        Err(e) => {
-            error!("{}", e);
-            panic!("Cannot find the property")
-        }
-    };
-    value
+    if let Err(e) = service.run().await {
+        error!("💀 {}", e);
+        exit(-67);
+    }
 }
@@ -8,7 +8,8 @@ use log::info;
 use serde_derive::{Deserialize, Serialize};
 
 /// Object by enums
-#[derive(Debug, Clone, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, Locality)]
+#[locality(with_data)]
 pub (crate) enum MessageEnum {
     RegulationMap(RegulationMapMsg),
     RegulatorRadiator(RegulatorRadiatorMsg)
@@ -94,63 +95,20 @@ impl MessageEnum {
             }
         }
     }
-    
-}
-
-
-impl Locality for MessageEnum {
-    fn query_for_state(&self) -> String {
-        match self {
-            RegulatorRadiator(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-            RegulationMap(_) => {
-                let msg = r#"{"state":""}"#;
-                msg.to_string()
-            }
-        }
-    }
-
-    fn find_set_topic(&self, topic: &str) -> String {
-        topic.to_string()
-    }
-    
-    fn raw_message(&self) -> String {
-        match self {
-            RegulationMap(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO
-            }
-            RegulatorRadiator(msg) => {
-                serde_json::to_string(msg).unwrap() // TODO
-            }
-        }
-    }
 
     /// Convert the original message to the type of the current Self
     fn to_local(&self, original_message: &MessageEnum, last_message: &MessageEnum) -> Self {
-        self.to_local_with_data(original_message, last_message, None, None)
+        self.to_local_with_data(original_message, last_message, None, None, "")
     }
 
     /// Convert the original message to the type of the current Self
-    fn to_local_with_data(&self, original_message: &MessageEnum, last_message: &MessageEnum, ext_data: Option<&HashMap<String, f64>>, topic: Option<&str>) -> Self {
+    fn to_local_with_data(&self, original_message: &MessageEnum, last_message: &MessageEnum, ext_data: Option<&HashMap<String, f64>>, topic: Option<&str>, _source_topic: &str) -> Self {
         match self {
             RegulationMap(_) => {
-                original_message.to_regulation_map(&last_message)
+                original_message.to_regulation_map(last_message)
             }
             RegulatorRadiator(_) => {
-                original_message.to_radiator(&last_message, ext_data, topic)
-            }
-        }
-    }
-
-    fn json_to_local(&self, json_msg: &str) -> Result<MessageEnum, String> {
-        match self {
-            RegulatorRadiator(_) => {
-                Ok(RegulatorRadiator(RegulatorRadiatorMsg::from_json(json_msg)?))
-            }
-            RegulationMap(_) => {
-                Ok(RegulationMap(RegulationMapMsg::from_json(json_msg)?))
+                original_message.to_radiator(last_message, ext_data, topic)
             }
         }
     }
@@ -179,5 +137,4 @@ impl Locality for MessageEnum {
             }
         }
     }
-    
-}
\ No newline at end of file
+}